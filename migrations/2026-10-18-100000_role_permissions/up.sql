@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS permissions (
+    name TEXT PRIMARY KEY,
+    description TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role_id uuid NOT NULL,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role_id, permission),
+    CONSTRAINT fk_role
+        FOREIGN KEY(role_id)
+            REFERENCES roles(uuid)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_permission
+        FOREIGN KEY(permission)
+            REFERENCES permissions(name)
+            ON DELETE CASCADE
+);
+
+INSERT INTO permissions (name, description) VALUES
+    ('deals.write', 'Create, edit and delete deals in the catalog'),
+    ('users.read', 'View the account details of any user'),
+    ('roles.write', 'Grant and revoke the permissions of roles')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission)
+    SELECT roles.uuid, permissions.name
+    FROM roles CROSS JOIN permissions
+    WHERE roles.name = 'admin'
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,14 @@
+-- drops jwt_issuers if up.sql created it, otherwise only undoes what up.sql added to it
+DO $$
+BEGIN
+    IF obj_description('jwt_issuers'::regclass, 'pg_class') = 'created by 2026-10-18-110000_jwt_issuer_keys' THEN
+        DROP TABLE jwt_issuers;
+    ELSE
+        DELETE FROM jwt_issuers WHERE uuid = 'd582df1f-3642-4191-b822-0c9a73719259';
+
+        ALTER TABLE jwt_issuers
+            DROP COLUMN algorithm,
+            DROP COLUMN verification_key,
+            DROP COLUMN enabled;
+    END IF;
+END $$;
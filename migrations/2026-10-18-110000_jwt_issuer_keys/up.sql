@@ -0,0 +1,28 @@
+-- no earlier migration creates jwt_issuers, so databases set up from them lack it while others
+-- had it created by hand. It is only created here when missing, and marked so that down.sql
+-- drops it only if it was created here
+DO $$
+BEGIN
+    IF to_regclass('jwt_issuers') IS NULL THEN
+        CREATE TABLE jwt_issuers (
+            uuid uuid DEFAULT uuid_generate_v4() PRIMARY KEY,
+            name TEXT NOT NULL
+        );
+
+        COMMENT ON TABLE jwt_issuers IS 'created by 2026-10-18-110000_jwt_issuer_keys';
+    END IF;
+END $$;
+
+-- issuers start out disabled and without a key, existing rows included, so none is trusted until
+-- an admin stores its real verification key and enables it. An empty key would verify tokens
+-- HMAC signed with an empty secret, so it is refused outright
+ALTER TABLE jwt_issuers
+    ADD COLUMN IF NOT EXISTS algorithm TEXT NOT NULL DEFAULT 'HS256',
+    ADD COLUMN IF NOT EXISTS verification_key TEXT CHECK (verification_key <> ''),
+    ADD COLUMN IF NOT EXISTS enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- the issuer for tokens minted by this api. It has no verification key, its tokens are only ever
+-- verified with the key ring, and are refused if JWT_ISSUER_ID names another issuer
+INSERT INTO jwt_issuers (uuid, name, enabled)
+    VALUES ('d582df1f-3642-4191-b822-0c9a73719259', 'commerce-api', TRUE)
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,3 @@
+DELETE FROM permissions WHERE name = 'keys.write';
+
+DROP TABLE IF EXISTS jwt_signing_keys;
@@ -0,0 +1,25 @@
+-- the keys this api signs its own tokens with. The key without a retired_at is the active one,
+-- retired keys still verify tokens until their expires_at has passed. The key material is stored
+-- encrypted with JWT_KEY_ENCRYPTION_KEY, never in plaintext
+CREATE TABLE IF NOT EXISTS jwt_signing_keys (
+    kid TEXT PRIMARY KEY,
+    algorithm TEXT NOT NULL,
+    encrypted_key TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    retired_at TIMESTAMP,
+    expires_at TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS jwt_signing_keys_single_active
+    ON jwt_signing_keys ((retired_at IS NULL))
+    WHERE retired_at IS NULL;
+
+INSERT INTO permissions (name, description) VALUES
+    ('keys.write', 'View and rotate the keys tokens are signed with')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission)
+    SELECT roles.uuid, 'keys.write'
+    FROM roles
+    WHERE roles.name = 'admin'
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,18 @@
+-- refresh tokens are only stored as a sha-256 hash. Every signin starts a new family, and each
+-- refresh replaces the presented token with the next token of the same family
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    uuid uuid DEFAULT uuid_generate_v4() PRIMARY KEY,
+    family_id uuid NOT NULL,
+    user_id uuid NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    revoked_at TIMESTAMP,
+    CONSTRAINT fk_user
+        FOREIGN KEY(user_id)
+            REFERENCES users(uuid)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_id ON refresh_tokens (family_id);
@@ -0,0 +1,20 @@
+-- access tokens revoked before their exp, by jti. Rows are only needed until the token would
+-- have expired anyway
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti uuid PRIMARY KEY,
+    user_id uuid NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS revoked_tokens_expires_at ON revoked_tokens (expires_at);
+
+-- "log out everywhere", every token of the user issued at or before revoked_before is refused
+CREATE TABLE IF NOT EXISTS user_token_cutoffs (
+    user_id uuid PRIMARY KEY,
+    revoked_before TIMESTAMP NOT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY(user_id)
+            REFERENCES users(uuid)
+            ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE nonces ADD COLUMN IF NOT EXISTS key TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+-- nonce tags are keyed with NONCE_SECRET rather than a key stored next to the nonce
+ALTER TABLE nonces DROP COLUMN IF EXISTS key;
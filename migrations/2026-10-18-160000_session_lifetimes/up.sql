@@ -0,0 +1,2 @@
+-- when the session was created, bounding its absolute lifetime however active it stays
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
@@ -0,0 +1,33 @@
+-- a cart belongs to a signed in user, or to an anonymous session until it is merged into the
+-- user's cart on signin. Sessions may live outside of postgres, so session_id is not a foreign key
+CREATE TABLE IF NOT EXISTS carts (
+    uuid uuid DEFAULT uuid_generate_v4() PRIMARY KEY,
+    user_id uuid,
+    session_id TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    updated_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_user
+        FOREIGN KEY(user_id)
+            REFERENCES users(uuid)
+            ON DELETE CASCADE,
+    CONSTRAINT carts_owner CHECK ((user_id IS NULL) <> (session_id IS NULL))
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS carts_user_id ON carts (user_id) WHERE user_id IS NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS carts_session_id ON carts (session_id) WHERE session_id IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS cart_items (
+    cart_id uuid NOT NULL,
+    deal_id uuid NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    added_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    PRIMARY KEY (cart_id, deal_id),
+    CONSTRAINT fk_cart
+        FOREIGN KEY(cart_id)
+            REFERENCES carts(uuid)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_deal
+        FOREIGN KEY(deal_id)
+            REFERENCES deals(uuid)
+            ON DELETE CASCADE
+);
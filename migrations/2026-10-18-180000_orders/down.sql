@@ -0,0 +1,4 @@
+DELETE FROM permissions WHERE name = 'orders.read';
+
+DROP TABLE IF EXISTS order_lines;
+DROP TABLE IF EXISTS orders;
@@ -0,0 +1,43 @@
+-- an order is placed from a user's cart. Its lines keep the name and price of each deal as it was
+-- bought, so later edits to a deal, or deleting it, leave the order history unchanged
+CREATE TABLE IF NOT EXISTS orders (
+    uuid uuid DEFAULT uuid_generate_v4() PRIMARY KEY,
+    user_id uuid NOT NULL,
+    total BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_user
+        FOREIGN KEY(user_id)
+            REFERENCES users(uuid)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS orders_user_id ON orders (user_id, created_at);
+
+CREATE TABLE IF NOT EXISTS order_lines (
+    order_id uuid NOT NULL,
+    line INTEGER NOT NULL,
+    deal_id uuid,
+    deal_version INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    price INTEGER NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (order_id, line),
+    CONSTRAINT fk_order
+        FOREIGN KEY(order_id)
+            REFERENCES orders(uuid)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_deal
+        FOREIGN KEY(deal_id)
+            REFERENCES deals(uuid)
+            ON DELETE SET NULL
+);
+
+INSERT INTO permissions (name, description) VALUES
+    ('orders.read', 'View the orders of any user')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission)
+    SELECT roles.uuid, 'orders.read'
+    FROM roles
+    WHERE roles.name = 'admin'
+ON CONFLICT DO NOTHING;
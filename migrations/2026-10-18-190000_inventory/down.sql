@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS stock_reservations_track_reserved ON stock_reservations;
+DROP FUNCTION IF EXISTS track_reserved_stock();
+
+DROP TABLE IF EXISTS stock_reservations;
+DROP TABLE IF EXISTS inventory;
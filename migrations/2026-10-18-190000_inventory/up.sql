@@ -0,0 +1,55 @@
+-- the stock of every deal. reserved counts the units held by the rows of stock_reservations and is
+-- kept in step with them by the trigger below, so it stays right however a reservation goes away,
+-- including when its cart or deal is deleted
+CREATE TABLE IF NOT EXISTS inventory (
+    deal_id uuid PRIMARY KEY,
+    on_hand INTEGER NOT NULL DEFAULT 0 CHECK (on_hand >= 0),
+    reserved INTEGER NOT NULL DEFAULT 0 CHECK (reserved >= 0),
+    CONSTRAINT inventory_reserved_on_hand CHECK (reserved <= on_hand),
+    CONSTRAINT fk_deal
+        FOREIGN KEY(deal_id)
+            REFERENCES deals(uuid)
+            ON DELETE CASCADE
+);
+
+INSERT INTO inventory (deal_id)
+    SELECT uuid FROM deals
+ON CONFLICT DO NOTHING;
+
+-- the units of a deal held for a cart until the cart is checked out, the item is removed, or the
+-- reservation expires
+CREATE TABLE IF NOT EXISTS stock_reservations (
+    cart_id uuid NOT NULL,
+    deal_id uuid NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    expires_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (cart_id, deal_id),
+    CONSTRAINT fk_cart
+        FOREIGN KEY(cart_id)
+            REFERENCES carts(uuid)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_deal
+        FOREIGN KEY(deal_id)
+            REFERENCES deals(uuid)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS stock_reservations_expires_at ON stock_reservations (expires_at);
+
+CREATE OR REPLACE FUNCTION track_reserved_stock() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE inventory SET reserved = reserved - OLD.quantity WHERE deal_id = OLD.deal_id;
+    END IF;
+
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        UPDATE inventory SET reserved = reserved + NEW.quantity WHERE deal_id = NEW.deal_id;
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER stock_reservations_track_reserved
+    AFTER INSERT OR UPDATE OR DELETE ON stock_reservations
+    FOR EACH ROW EXECUTE FUNCTION track_reserved_stock();
@@ -0,0 +1,11 @@
+-- amounts in other currencies than USD can't be told apart once the currency is dropped
+ALTER TABLE orders DROP COLUMN IF EXISTS total_currency;
+ALTER TABLE orders RENAME COLUMN total_amount TO total;
+
+ALTER TABLE order_lines DROP COLUMN IF EXISTS price_currency;
+ALTER TABLE order_lines ALTER COLUMN price_amount TYPE INTEGER;
+ALTER TABLE order_lines RENAME COLUMN price_amount TO price;
+
+ALTER TABLE deals DROP COLUMN IF EXISTS price_currency;
+ALTER TABLE deals ALTER COLUMN price_amount TYPE INTEGER;
+ALTER TABLE deals RENAME COLUMN price_amount TO price;
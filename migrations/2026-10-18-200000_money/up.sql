@@ -0,0 +1,18 @@
+-- every amount is stored in the minor units of its currency, next to the ISO-4217 code of that
+-- currency. Existing amounts were cents without a currency and are taken to be USD
+ALTER TABLE deals RENAME COLUMN price TO price_amount;
+ALTER TABLE deals ALTER COLUMN price_amount TYPE BIGINT;
+ALTER TABLE deals ADD COLUMN IF NOT EXISTS price_currency TEXT NOT NULL DEFAULT 'USD'
+    CONSTRAINT deals_price_currency CHECK (price_currency ~ '^[A-Z]{3}$');
+ALTER TABLE deals ALTER COLUMN price_currency DROP DEFAULT;
+
+ALTER TABLE order_lines RENAME COLUMN price TO price_amount;
+ALTER TABLE order_lines ALTER COLUMN price_amount TYPE BIGINT;
+ALTER TABLE order_lines ADD COLUMN IF NOT EXISTS price_currency TEXT NOT NULL DEFAULT 'USD'
+    CONSTRAINT order_lines_price_currency CHECK (price_currency ~ '^[A-Z]{3}$');
+ALTER TABLE order_lines ALTER COLUMN price_currency DROP DEFAULT;
+
+ALTER TABLE orders RENAME COLUMN total TO total_amount;
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS total_currency TEXT NOT NULL DEFAULT 'USD'
+    CONSTRAINT orders_total_currency CHECK (total_currency ~ '^[A-Z]{3}$');
+ALTER TABLE orders ALTER COLUMN total_currency DROP DEFAULT;
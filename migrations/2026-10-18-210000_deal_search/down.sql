@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS deals_created_at;
+DROP INDEX IF EXISTS deals_price;
+DROP INDEX IF EXISTS deals_search;
+
+ALTER TABLE deals DROP COLUMN IF EXISTS search;
+ALTER TABLE deals DROP COLUMN IF EXISTS created_at;
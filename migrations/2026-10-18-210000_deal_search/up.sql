@@ -0,0 +1,11 @@
+-- when a deal was listed, for sorting the catalog by newest. Existing deals count as listed now
+ALTER TABLE deals ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
+
+-- the words of a deal's name and description for full text search, names weighing more
+ALTER TABLE deals ADD COLUMN IF NOT EXISTS search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', name), 'A') || setweight(to_tsvector('english', description), 'B')
+) STORED;
+
+CREATE INDEX IF NOT EXISTS deals_search ON deals USING GIN (search);
+CREATE INDEX IF NOT EXISTS deals_price ON deals (price_currency, price_amount);
+CREATE INDEX IF NOT EXISTS deals_created_at ON deals (created_at);
@@ -0,0 +1,4 @@
+DELETE FROM permissions WHERE name = 'categories.write';
+
+DROP TABLE IF EXISTS deal_categories;
+DROP TABLE IF EXISTS categories;
@@ -0,0 +1,43 @@
+-- categories form a tree, a category without a parent is at the top level. A category can only be
+-- deleted once it has no subcategories, and names are unique among the children of a parent
+CREATE TABLE IF NOT EXISTS categories (
+    uuid uuid DEFAULT uuid_generate_v4() PRIMARY KEY,
+    parent_id uuid,
+    name TEXT NOT NULL CHECK (length(name) BETWEEN 1 AND 128),
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    CONSTRAINT categories_not_own_parent CHECK (parent_id <> uuid),
+    CONSTRAINT fk_parent
+        FOREIGN KEY(parent_id)
+            REFERENCES categories(uuid)
+            ON DELETE RESTRICT
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS categories_sibling_name
+    ON categories (COALESCE(parent_id, '00000000-0000-0000-0000-000000000000'), name);
+
+-- the categories a deal is filed under, a deal can be in any number of them
+CREATE TABLE IF NOT EXISTS deal_categories (
+    deal_id uuid NOT NULL,
+    category_id uuid NOT NULL,
+    PRIMARY KEY (deal_id, category_id),
+    CONSTRAINT fk_deal
+        FOREIGN KEY(deal_id)
+            REFERENCES deals(uuid)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_category
+        FOREIGN KEY(category_id)
+            REFERENCES categories(uuid)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS deal_categories_category_id ON deal_categories (category_id);
+
+INSERT INTO permissions (name, description) VALUES
+    ('categories.write', 'Create, move, rename and delete categories')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission)
+    SELECT roles.uuid, 'categories.write'
+    FROM roles
+    WHERE roles.name = 'admin'
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,3 @@
+DELETE FROM nonces WHERE session_id NOT IN (SELECT id FROM sessions);
+ALTER TABLE nonces
+    ADD CONSTRAINT fk_session FOREIGN KEY(session_id) REFERENCES sessions(id);
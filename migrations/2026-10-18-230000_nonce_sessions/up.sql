@@ -0,0 +1,4 @@
+-- sessions may live outside of postgres, so a nonce's session_id is not a foreign key. The stub
+-- sessions rows that used to be inserted to satisfy the key are dropped along with it
+ALTER TABLE nonces DROP CONSTRAINT IF EXISTS fk_session;
+DELETE FROM sessions WHERE session_data IS NULL AND user_id IS NULL;
@@ -0,0 +1,87 @@
+use deadpool_diesel::{ postgres::PoolError, InteractError };
+use diesel::result::{ DatabaseErrorKind, Error as DieselError };
+use std::fmt;
+use uuid::Uuid;
+
+/// The error type returned by every database operation
+/// 
+/// Diesel and pool errors are folded into a small set of cases the API cares about, so callers can
+/// tell "the row isn't there" apart from "the row conflicts with another" or "the database is
+/// down" without matching on driver specific error kinds. The variants are:
+///     NotFound: the query expected a row and found none
+///     UniqueViolation: the write conflicts with an existing row on a unique constraint
+///     ForeignKeyViolation: the write references a row that does not exist
+///     InvalidData: the write broke a not null or check constraint
+///     SerializationFailure: the transaction lost a race with a concurrent one and can be retried
+///     VersionMismatch: an optimistic concurrency check failed, the row was changed by someone else
+///     InsufficientStock: fewer units of the deal are available than were asked for
+///     CurrencyMismatch: amounts in different currencies would have to be combined
+///     Connection: no connection could be checked out, or it was lost mid query
+///     Internal: anything else, these are bugs rather than client errors
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    UniqueViolation(String),
+    ForeignKeyViolation(String),
+    InvalidData(String),
+    SerializationFailure,
+    VersionMismatch,
+    InsufficientStock { deal: Uuid, remaining: i32 },
+    CurrencyMismatch,
+    Connection(String),
+    Internal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "Record not found"),
+            Error::UniqueViolation(message) => write!(f, "Unique constraint violated: {}", message),
+            Error::ForeignKeyViolation(message) => write!(f, "Foreign key constraint violated: {}", message),
+            Error::InvalidData(message) => write!(f, "Constraint violated: {}", message),
+            Error::SerializationFailure => write!(f, "Transaction could not be serialized"),
+            Error::VersionMismatch => write!(f, "Record version does not match the expected version"),
+            Error::InsufficientStock { deal, remaining } => write!(f, "Insufficient stock of deal {}, {} remaining", deal, remaining),
+            Error::CurrencyMismatch => write!(f, "Amounts in different currencies can't be combined"),
+            Error::Connection(message) => write!(f, "Database connection failed: {}", message),
+            Error::Internal(message) => write!(f, "Database error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<DieselError> for Error {
+    fn from(error: DieselError) -> Self {
+        match error {
+            DieselError::NotFound => Error::NotFound,
+            DieselError::DatabaseError(kind, info) => {
+                let message = info.message().to_string();
+
+                match kind {
+                    DatabaseErrorKind::UniqueViolation => Error::UniqueViolation(message),
+                    DatabaseErrorKind::ForeignKeyViolation => Error::ForeignKeyViolation(message),
+                    DatabaseErrorKind::NotNullViolation
+                    | DatabaseErrorKind::CheckViolation => Error::InvalidData(message),
+                    DatabaseErrorKind::SerializationFailure => Error::SerializationFailure,
+                    DatabaseErrorKind::ClosedConnection => Error::Connection(message),
+                    _ => Error::Internal(message),
+                }
+            },
+            DieselError::BrokenTransactionManager => Error::Connection(error.to_string()),
+            _ => Error::Internal(error.to_string()),
+        }
+    }
+}
+
+impl From<PoolError> for Error {
+    fn from(error: PoolError) -> Self {
+        Error::Connection(error.to_string())
+    }
+}
+
+impl From<InteractError> for Error {
+    fn from(error: InteractError) -> Self {
+        Error::Internal(error.to_string())
+    }
+}
@@ -1,26 +1,98 @@
-use diesel::{ pg::PgConnection, prelude::*, sql_types::*, sql_function };
+use deadpool_diesel::{ postgres::{ Manager, Pool }, ManagerConfig, RecyclingMethod, Runtime };
+use diesel::{ infix_operator, pg::{ Pg, PgConnection }, sql_types::*, define_sql_function };
 use dotenvy::dotenv;
-use log::trace;
-use std::env;
+use log::{ error, trace };
+use std::{ env, time::Duration };
 
-/// establish a connection to the database
+use crate::db::{ models::schema::sql_types::Tsvector, Error };
+
+/// The shared pool of PostgreSQL connections used by every model and the session store
+pub type DbPool = Pool;
+
+/// create the shared connection pool for the database
+/// 
+/// This function builds the pool of pgconnections used to access data from the commerce database.
+/// The url is grabbed from the .env file under the DATABASE_URL name. The pool can be tuned with
+/// the following optional variables:
+///     DATABASE_POOL_SIZE: the max number of open connections (default 16)
+///     DATABASE_POOL_TIMEOUT: seconds to wait on a connection checkout before failing (default 5)
 /// 
-/// This function creates a pgconnection to access data from the commerce database. The url is
-/// grabbed from the .env file under the DATABASE_URL name.
+/// Recycled connections are verified with a test query before they are handed out, so a
+/// connection dropped by the server is replaced instead of failing the request that receives it.
 /// 
 /// # Panics
-/// This function will panic if the .env file or DATABASE_URL field is missing. Additionally, this
-/// will panic if it is unable to connect to the specified database
-pub fn establish_connection() -> PgConnection {
-    trace!("Starting connection to PostgreSQL...");
+/// This function will panic if the .env file or DATABASE_URL field is missing, or if the pool
+/// settings are invalid. Connections are opened lazily, so an unreachable database is reported
+/// on checkout rather than here.
+pub fn create_pool() -> DbPool {
+    trace!("Building PostgreSQL connection pool...");
     dotenv().ok();
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+
+    let pool_size = str::parse::<usize>(
+        &env::var("DATABASE_POOL_SIZE").unwrap_or_default()
+    ).unwrap_or(16);
+
+    let pool_timeout = str::parse::<u64>(
+        &env::var("DATABASE_POOL_TIMEOUT").unwrap_or_default()
+    ).unwrap_or(5);
+
+    let manager = Manager::from_config(
+        database_url,
+        Runtime::Tokio1,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Verified,
+        },
+    );
+
+    Pool::builder(manager)
+        .max_size(pool_size)
+        .wait_timeout(Some(Duration::from_secs(pool_timeout)))
+        .create_timeout(Some(Duration::from_secs(pool_timeout)))
+        .recycle_timeout(Some(Duration::from_secs(pool_timeout)))
+        .runtime(Runtime::Tokio1)
+        .build()
+        .expect("Failed to build the database connection pool")
 }
 
-sql_function! {
+/// run a diesel query on a pooled connection
+/// 
+/// Checks a connection out of the pool and runs the supplied closure on tokio's blocking thread
+/// pool, so the synchronous diesel calls inside never stall the async runtime. Failures to check
+/// out a connection and failures of the query itself are both reported as a `db::Error`. The
+/// closure may return either a diesel error or a `db::Error`, the latter allows transactions to
+/// bail out with domain specific failures such as a version mismatch.
+/// 
+/// # Examples
+/// 
+/// ```
+///     ...
+///     let user = with_connection(&pool, move |conn| {
+///         users.filter(uuid.eq(user_id)).first::<User>(conn)
+///     }).await?;
+///     ...
+/// ```
+pub async fn with_connection<F, R, E>(pool: &DbPool, query: F) -> Result<R, Error>
+where
+    F: FnOnce(&mut PgConnection) -> Result<R, E> + Send + 'static,
+    R: Send + 'static,
+    E: Into<Error> + Send + 'static,
+{
+    let connection = pool.get().await.map_err(|e| {
+        error!("Failed to check out a database connection: {}", e);
+        Error::from(e)
+    })?;
+
+    let response = connection.interact(query).await.map_err(|e| {
+        error!("Database interaction failed: {}", e);
+        Error::from(e)
+    })?;
+
+    response.map_err(Into::into)
+}
+
+define_sql_function! {
     /// The decryption plugin function used for password verification
     /// 
     /// This SQL function is from the pgcrypto extension for postgreSQL. It uses a basic salt
@@ -38,7 +110,7 @@ sql_function! {
     fn crypt(a: Text, b: Text) -> Text;
 }
 
-sql_function! {
+define_sql_function! {
     /// The encryption plugin function used for password encryption
     /// 
     /// This SQL function is from the pgcrypto extension for postgreSQL. It uses a basic salt
@@ -57,4 +129,79 @@ sql_function! {
     ///     ...
     /// ```
     fn gen_salt(a: Text) -> Text;
-}
\ No newline at end of file
+}
+
+define_sql_function! {
+    /// Takes a postgres advisory lock for the rest of the transaction without waiting for it
+    /// 
+    /// Returns false right away if another session holds the lock. The lock is released when the
+    /// transaction commits or rolls back, there is no explicit unlock. Used by the scheduled jobs
+    /// so that only one instance runs each of them at a time.
+    fn pg_try_advisory_xact_lock(key: BigInt) -> Bool;
+}
+
+/// The postgres full text search types the deal search needs besides `Tsvector`, which is
+/// generated into the schema as the type of deals.search
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "tsquery", schema = "pg_catalog"))]
+    pub struct Tsquery;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "regconfig", schema = "pg_catalog"))]
+    pub struct Regconfig;
+}
+
+use self::sql_types::{ Regconfig, Tsquery };
+
+define_sql_function! {
+    /// Parses search terms the way web search engines do into a full text query
+    /// 
+    /// Terms are and-ed together, "quoted terms" must appear as a phrase, `or` separates
+    /// alternatives and a leading `-` excludes a term. Never fails on malformed input, so it is
+    /// safe to pass user input to as is. Pass the config with `english()`.
+    fn websearch_to_tsquery(config: Regconfig, query: Text) -> Tsquery;
+}
+
+define_sql_function! {
+    /// Ranks how well a document matches the full text query, higher is better
+    fn ts_rank(document: Tsvector, query: Tsquery) -> Float;
+}
+
+infix_operator!(Matches, " @@ ", backend: Pg);
+
+/// The text search config deals.search is built with, to parse queries against it the same way
+pub fn english() -> diesel::expression::SqlLiteral<Regconfig> {
+    diesel::dsl::sql::<Regconfig>("'english'")
+}
+
+/// Pages a boxed query by keyset, see `Pagination::page`
+/// 
+/// Orders the query by the sort expression and then the id column, both ascending or both
+/// descending, and keeps only the rows after `after`, a (sort key, id) pair, in that order. Pass
+/// the reverse of the list's order to load the page before a backward cursor.
+/// 
+/// # Examples
+/// 
+/// ```
+/// let after = cursor.map(|cursor| (cursor.key, cursor.id));
+/// let page = keyset!(deals.into_boxed(), created_at, uuid, false, after).limit(11);
+/// ```
+macro_rules! keyset {
+    ($query:expr, $sort:expr, $id:expr, $ascending:expr, $after:expr) => {{
+        let mut query = $query;
+        if let Some((key, id)) = $after {
+            query = match $ascending {
+                true => query.filter($sort.gt(key.clone()).or($sort.eq(key).and($id.gt(id)))),
+                false => query.filter($sort.lt(key.clone()).or($sort.eq(key).and($id.lt(id)))),
+            };
+        }
+
+        match $ascending {
+            true => query.order(($sort.asc(), $id.asc())),
+            false => query.order(($sort.desc(), $id.desc())),
+        }
+    }};
+}
+
+pub(crate) use keyset;
@@ -1,7 +1,9 @@
+pub mod error;
 pub mod models;
 pub mod lib;
 
 pub use self::{
+    error::*,
     models::*,
     lib::*,
 };
@@ -0,0 +1,421 @@
+use chrono::{ Duration, NaiveDateTime, Utc };
+use diesel::{ prelude::*, upsert::excluded };
+use serde::{ Serialize, Deserialize };
+use uuid::Uuid;
+
+use super::schema;
+use crate::db::{ with_connection, Currency, Deal, DbPool, Error, Inventory };
+
+/// How long an anonymous cart is kept after it was last changed, in days
+const ABANDONED_CART_DAYS: i64 = 30;
+
+/// The struct to represent a shopping cart returned from the postgresql database
+///
+/// This struct is a representation of the schema from the carts table in the commerce database.
+/// Currently this includes fields for the cart's uuid, its owner, and when it was created and last
+/// changed. A cart is owned either by a signed in user, or by the anonymous session it was filled
+/// in, see `CartOwner`. Every owner has at most one cart, which is created on the first item added
+/// to it. Signing in merges the session's cart into the user's cart.
+///
+/// The units of every item in a cart are reserved for it while it is changed, see `Inventory`, so
+/// adding more of an item than is available fails with `Error::InsufficientStock`. All items of a
+/// cart are priced in the same currency, adding a deal priced in another one fails with
+/// `Error::CurrencyMismatch`.
+///
+/// cart.uuid is the primary key of the table.
+///
+/// # Examples
+///
+/// ```
+/// Cart::add_item(&pool, &CartOwner::Session(sid), deal_id, 2).await?;
+///
+/// // on signin
+/// Cart::merge(&pool, &sid, user_id).await?;
+/// let lines = Cart::get_lines(&pool, &CartOwner::User(user_id)).await?;
+/// ```
+#[derive(Queryable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(uuid), table_name = schema::carts)]
+pub struct Cart {
+    pub uuid: Uuid,
+    pub user_id: Option<Uuid>,
+    pub session_id: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// The struct to represent an item in a cart returned from the postgresql database
+///
+/// This struct is a representation of the schema from the cart_items table in the commerce
+/// database, holding how many of a deal the cart holds. A cart holds each deal at most once.
+///
+/// (cart_item.cart_id, cart_item.deal_id) is the primary key of the table.
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(cart_id, deal_id), table_name = schema::cart_items)]
+pub struct CartItem {
+    pub cart_id: Uuid,
+    pub deal_id: Uuid,
+    pub quantity: i32,
+    pub added_at: NaiveDateTime,
+}
+
+/// Who a cart belongs to, the signed in user or else the anonymous session
+#[derive(Clone, Debug)]
+pub enum CartOwner {
+    User(Uuid),
+    Session(String),
+}
+
+impl Cart {
+    /// Loads the items of the owner's cart along with their deals, in the order they were added.
+    /// An owner without a cart has an empty one.
+    pub async fn get_lines(pool: &DbPool, owner: &CartOwner) -> Result<Vec<(CartItem, Deal)>, Error> {
+        let owner = owner.clone();
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                match find(conn, &owner)? {
+                    Some(cart) => get_lines(conn, cart.uuid),
+                    None => Ok(Vec::new()),
+                }
+            })
+        }).await
+    }
+
+    /// Adds the quantity of the deal to the owner's cart, on top of any already in it, and
+    /// reserves the units of the whole line
+    pub async fn add_item(pool: &DbPool, owner: &CartOwner, deal: Uuid, quantity: i32) -> Result<(), Error> {
+        use schema::cart_items;
+
+        let owner = owner.clone();
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let cart = find_or_create(conn, &owner)?;
+                touch(conn, cart.uuid)?;
+                check_currency(conn, cart.uuid, deal)?;
+
+                let line_quantity = diesel::insert_into(cart_items::table)
+                    .values(&CartItem {
+                        cart_id: cart.uuid,
+                        deal_id: deal,
+                        quantity,
+                        added_at: Utc::now().naive_utc(),
+                    })
+                    .on_conflict((cart_items::cart_id, cart_items::deal_id))
+                    .do_update()
+                    .set(cart_items::quantity.eq(cart_items::quantity + excluded(cart_items::quantity)))
+                    .returning(cart_items::quantity)
+                    .get_result::<i32>(conn)?;
+
+                Inventory::reserve(conn, cart.uuid, deal, line_quantity)
+            })
+        }).await
+    }
+
+    /// Sets how many of the deal the owner's cart holds and reserves them, `Error::NotFound` if it
+    /// holds none
+    pub async fn set_quantity(pool: &DbPool, owner: &CartOwner, deal: Uuid, quantity: i32) -> Result<(), Error> {
+        use schema::cart_items;
+
+        let owner = owner.clone();
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let cart = find(conn, &owner)?.ok_or(Error::NotFound)?;
+                touch(conn, cart.uuid)?;
+
+                let updated = diesel::update(cart_items::table.find((cart.uuid, deal)))
+                    .set(cart_items::quantity.eq(quantity))
+                    .execute(conn)?;
+
+                if updated == 0 {
+                    return Err(Error::NotFound);
+                }
+
+                Inventory::reserve(conn, cart.uuid, deal, quantity)
+            })
+        }).await
+    }
+
+    /// Removes the deal from the owner's cart, `Error::NotFound` if it isn't in it
+    pub async fn remove_item(pool: &DbPool, owner: &CartOwner, deal: Uuid) -> Result<(), Error> {
+        use schema::cart_items;
+
+        let owner = owner.clone();
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let cart = find(conn, &owner)?.ok_or(Error::NotFound)?;
+
+                let deleted = diesel::delete(cart_items::table.find((cart.uuid, deal)))
+                    .execute(conn)?;
+
+                if deleted == 0 {
+                    return Err(Error::NotFound);
+                }
+
+                Inventory::release(conn, cart.uuid, Some(deal))?;
+                touch(conn, cart.uuid).map_err(Error::from)
+            })
+        }).await
+    }
+
+    /// Removes every item from the owner's cart
+    pub async fn clear(pool: &DbPool, owner: &CartOwner) -> Result<(), Error> {
+        use schema::cart_items;
+
+        let owner = owner.clone();
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let Some(cart) = find(conn, &owner)? else {
+                    return Ok(());
+                };
+
+                diesel::delete(cart_items::table.filter(cart_items::cart_id.eq(cart.uuid)))
+                    .execute(conn)?;
+                Inventory::release(conn, cart.uuid, None)?;
+
+                touch(conn, cart.uuid)
+            })
+        }).await
+    }
+
+    /// Moves the items of the session's anonymous cart into the user's cart, adding up the
+    /// quantities of deals in both, and deletes the anonymous cart. Called as the session signs in,
+    /// before its id is regenerated. The reservations of the anonymous cart move along, as far as
+    /// the stock allows, the merge never fails for lack of stock.
+    pub async fn merge(pool: &DbPool, sid: &str, user: Uuid) -> Result<(), Error> {
+        use schema::{ cart_items, carts };
+
+        let anonymous = CartOwner::Session(sid.to_string());
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let Some(from) = find(conn, &anonymous)? else {
+                    return Ok(());
+                };
+                let into = find_or_create(conn, &CartOwner::User(user))?;
+
+                let items = cart_items::table
+                    .filter(cart_items::cart_id.eq(from.uuid))
+                    .load::<CartItem>(conn)?
+                    .into_iter()
+                    .map(|item| CartItem { cart_id: into.uuid, ..item })
+                    .collect::<Vec<CartItem>>();
+
+                touch(conn, into.uuid)?;
+                let mut merged = if items.is_empty() {
+                    Vec::new()
+                } else {
+                    diesel::insert_into(cart_items::table)
+                        .values(&items)
+                        .on_conflict((cart_items::cart_id, cart_items::deal_id))
+                        .do_update()
+                        .set(cart_items::quantity.eq(cart_items::quantity + excluded(cart_items::quantity)))
+                        .returning((cart_items::deal_id, cart_items::quantity))
+                        .get_results::<(Uuid, i32)>(conn)?
+                };
+
+                diesel::delete(carts::table.find(from.uuid)).execute(conn)?;
+
+                merged.sort_by_key(|(deal, _)| *deal);
+                for (deal, quantity) in merged {
+                    Inventory::reserve_up_to(conn, into.uuid, deal, quantity)?;
+                }
+
+                Ok::<_, diesel::result::Error>(())
+            })
+        }).await
+    }
+
+    /// Empties the owner's cart inside the caller's transaction, returning the lines it held. The
+    /// cart is locked first, so of two concurrent checkouts of the same cart only one gets its
+    /// lines. Used to place orders.
+    pub fn take(conn: &mut PgConnection, owner: &CartOwner) -> QueryResult<Vec<(CartItem, Deal)>> {
+        use schema::{ cart_items, carts };
+
+        let Some(cart) = find(conn, owner)? else {
+            return Ok(Vec::new());
+        };
+
+        carts::table
+            .find(cart.uuid)
+            .select(carts::uuid)
+            .for_update()
+            .first::<Uuid>(conn)?;
+
+        let lines = get_lines(conn, cart.uuid)?;
+
+        diesel::delete(cart_items::table.filter(cart_items::cart_id.eq(cart.uuid)))
+            .execute(conn)?;
+        touch(conn, cart.uuid)?;
+
+        Ok(lines)
+    }
+
+    /// Deletes the anonymous carts left untouched for 30 days, returning how many were deleted.
+    /// Run by the cleanup jobs.
+    pub fn purge_abandoned(conn: &mut PgConnection) -> QueryResult<usize> {
+        use schema::carts::dsl::*;
+
+        let cutoff = Utc::now().naive_utc() - Duration::days(ABANDONED_CART_DAYS);
+
+        diesel::delete(
+            carts
+                .filter(user_id.is_null())
+                .filter(updated_at.lt(cutoff))
+        )
+        .execute(conn)
+    }
+}
+
+/// Finds the owner's cart, if they have one
+fn find(conn: &mut PgConnection, owner: &CartOwner) -> QueryResult<Option<Cart>> {
+    use schema::carts::dsl::*;
+
+    let query = match owner {
+        CartOwner::User(user) => carts.filter(user_id.eq(*user)).into_boxed(),
+        CartOwner::Session(sid) => carts.filter(session_id.eq(sid.clone())).into_boxed(),
+    };
+
+    query.first::<Cart>(conn).optional()
+}
+
+/// Finds the owner's cart, creating an empty one if they have none
+fn find_or_create(conn: &mut PgConnection, owner: &CartOwner) -> QueryResult<Cart> {
+    use schema::carts::dsl::*;
+
+    let (owner_user, owner_session) = match owner {
+        CartOwner::User(user) => (Some(*user), None),
+        CartOwner::Session(sid) => (None, Some(sid.clone())),
+    };
+
+    diesel::insert_into(carts)
+        .values((
+            user_id.eq(owner_user),
+            session_id.eq(owner_session),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+
+    find(conn, owner)?.ok_or(diesel::result::Error::NotFound)
+}
+
+fn get_lines(conn: &mut PgConnection, cart: Uuid) -> QueryResult<Vec<(CartItem, Deal)>> {
+    use schema::{ cart_items, deals };
+
+    cart_items::table
+        .inner_join(deals::table)
+        .filter(cart_items::cart_id.eq(cart))
+        .order((cart_items::added_at.asc(), cart_items::deal_id.asc()))
+        .select((CartItem::as_select(), Deal::as_select()))
+        .load::<(CartItem, Deal)>(conn)
+}
+
+/// Fails with `Error::CurrencyMismatch` if the cart holds items priced in another currency than
+/// the deal
+fn check_currency(conn: &mut PgConnection, cart: Uuid, deal: Uuid) -> Result<(), Error> {
+    use schema::{ cart_items, deals };
+
+    let Some(currency) = deals::table
+        .find(deal)
+        .select(deals::price_currency)
+        .first::<Currency>(conn)
+        .optional()? else {
+        return Ok(());
+    };
+
+    let mixed = cart_items::table
+        .inner_join(deals::table)
+        .filter(cart_items::cart_id.eq(cart))
+        .filter(deals::price_currency.ne(currency))
+        .select(cart_items::deal_id)
+        .first::<Uuid>(conn)
+        .optional()?;
+
+    match mixed {
+        Some(_) => Err(Error::CurrencyMismatch),
+        None => Ok(()),
+    }
+}
+
+/// Marks the cart as changed now
+fn touch(conn: &mut PgConnection, cart: Uuid) -> QueryResult<()> {
+    use schema::carts::dsl::*;
+
+    diesel::update(carts.find(cart))
+        .set(updated_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::db::{ create_pool, insert_test_user, CreateDeal, Money };
+
+    async fn deal(pool: &DbPool, price: i64, currency: &str) -> Uuid {
+        let deal = Deal::insert(pool, CreateDeal {
+            name: "Cart test deal".to_string(),
+            image: "https://example.com/deal.png".to_string(),
+            price: Money::new(price, currency.parse().unwrap()),
+            description: "A deal put in carts by the cart tests".to_string(),
+        }).await.unwrap().uuid.unwrap();
+
+        Inventory::set_on_hand(pool, deal, 100).await.unwrap();
+        deal
+    }
+
+    fn quantities(lines: Vec<(CartItem, Deal)>) -> HashMap<Uuid, i32> {
+        lines.into_iter().map(|(item, _)| (item.deal_id, item.quantity)).collect()
+    }
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn anonymous_cart_is_merged_into_the_users_cart() {
+        let pool = create_pool();
+        let (shirt, socks) = (deal(&pool, 1500, "USD").await, deal(&pool, 300, "USD").await);
+        let scarf = deal(&pool, 2000, "EUR").await;
+        let user = insert_test_user(&pool, "cart").await;
+
+        let sid = Uuid::new_v4().to_string();
+        let anonymous = CartOwner::Session(sid.clone());
+        let signed_in = CartOwner::User(user);
+
+        Cart::add_item(&pool, &anonymous, shirt, 1).await.unwrap();
+        Cart::add_item(&pool, &anonymous, shirt, 2).await.unwrap();
+        Cart::add_item(&pool, &anonymous, socks, 4).await.unwrap();
+        Cart::set_quantity(&pool, &anonymous, socks, 5).await.unwrap();
+        assert_eq!(quantities(Cart::get_lines(&pool, &anonymous).await.unwrap()), HashMap::from([(shirt, 3), (socks, 5)]));
+        assert!(matches!(Cart::add_item(&pool, &anonymous, scarf, 1).await, Err(Error::CurrencyMismatch)));
+
+        Cart::add_item(&pool, &signed_in, shirt, 1).await.unwrap();
+        Cart::merge(&pool, &sid, user).await.unwrap();
+
+        assert!(Cart::get_lines(&pool, &anonymous).await.unwrap().is_empty());
+        assert_eq!(quantities(Cart::get_lines(&pool, &signed_in).await.unwrap()), HashMap::from([(shirt, 4), (socks, 5)]));
+
+        Cart::remove_item(&pool, &signed_in, socks).await.unwrap();
+        assert!(matches!(Cart::remove_item(&pool, &signed_in, socks).await, Err(Error::NotFound)));
+        assert!(matches!(Cart::set_quantity(&pool, &anonymous, shirt, 1).await, Err(Error::NotFound)));
+
+        Cart::clear(&pool, &signed_in).await.unwrap();
+        assert!(Cart::get_lines(&pool, &signed_in).await.unwrap().is_empty());
+    }
+}
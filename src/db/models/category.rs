@@ -0,0 +1,341 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{ Serialize, Deserialize, Deserializer };
+use std::collections::HashMap;
+use uuid::Uuid;
+use validator::Validate;
+
+use super::schema;
+use crate::db::{ with_connection, DbPool, Error };
+
+/// The struct to represent a category of deals returned from the postgresql database
+///
+/// This struct is a representation of the schema from the categories table in the commerce
+/// database. Categories form a tree through parent_id, top level categories have none. Deals are
+/// filed under any number of categories through the deal_categories join table, and a deal in a
+/// category is also in every category above it when listing by category, see `Category::subtree`.
+///
+/// category.uuid is the primary key of the table. Names are unique among the children of a parent.
+///
+/// # Examples
+///
+/// ```
+/// let shoes = Category::insert(&pool, CreateCategory { name: "Shoes".into(), parent_id: None }).await?;
+/// Category::set_for_deal(&pool, deal_id, vec![shoes.uuid]).await?;
+/// ```
+#[derive(Queryable, Selectable, Identifiable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(uuid), table_name = schema::categories)]
+pub struct Category {
+    pub uuid: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// The client supplied fields of a new category, a top level one if parent_id is left out. Call
+/// `validate` before use.
+#[derive(Deserialize, Validate, Clone, Debug)]
+pub struct CreateCategory {
+    #[validate(length(min = 1, max = 128, message = "must be between 1 and 128 characters"))]
+    pub name: String,
+    pub parent_id: Option<Uuid>,
+}
+
+/// The fields of a category a client may change, only the ones present are written
+///
+/// parent_id moves the category under another one, or to the top level when it is null. Leaving
+/// it out keeps the category where it is.
+#[derive(Deserialize, Validate, Clone, Debug, Default)]
+pub struct CategoryChanges {
+    #[validate(length(min = 1, max = 128, message = "must be between 1 and 128 characters"))]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub parent_id: Option<Option<Uuid>>,
+}
+
+/// Serde deserialization decorator to tell a field set to null apart from a missing one, which
+/// is `None` through `#[serde(default)]`
+fn present<'de, D, T>(de: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(de).map(Some)
+}
+
+impl CategoryChanges {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.parent_id.is_none()
+    }
+}
+
+impl Category {
+    /// Loads every category, in the order of their names. The tree is small enough to be sent
+    /// whole, see `CategoryTree`.
+    pub async fn get_all(pool: &DbPool) -> Result<Vec<Category>, Error> {
+        use schema::categories::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                categories
+                    .order((name.asc(), uuid.asc()))
+                    .load::<Category>(conn)
+            })
+        }).await
+    }
+
+    /// Creates the category. Fails with `Error::ForeignKeyViolation` if the parent doesn't exist,
+    /// and with `Error::UniqueViolation` if the parent already has a child of the same name.
+    pub async fn insert(pool: &DbPool, new_category: CreateCategory) -> Result<Category, Error> {
+        use schema::categories::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::insert_into(categories)
+                    .values((
+                        name.eq(&new_category.name),
+                        parent_id.eq(new_category.parent_id),
+                    ))
+                    .get_result::<Category>(conn)
+            })
+        }).await
+    }
+
+    /// Renames or moves the category. Moving it under itself or one of its subcategories would
+    /// cut its branch off the tree and fails with `Error::InvalidData`. Moves lock the table, so
+    /// two concurrent moves can't form a loop between them.
+    pub async fn update(pool: &DbPool, id: Uuid, changes: CategoryChanges) -> Result<Category, Error> {
+        use schema::categories::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                if let Some(Some(new_parent)) = changes.parent_id {
+                    diesel::sql_query("LOCK TABLE categories IN SHARE ROW EXCLUSIVE MODE")
+                        .execute(conn)?;
+
+                    if Category::subtree(conn, id)?.contains(&new_parent) {
+                        return Err(Error::InvalidData("A category can't be moved under itself".to_string()));
+                    }
+                }
+
+                diesel::update(categories.find(id))
+                    .set((
+                        changes.name.as_ref().map(|value| name.eq(value)),
+                        changes.parent_id.map(|value| parent_id.eq(value)),
+                    ))
+                    .get_result::<Category>(conn)
+                    .map_err(Error::from)
+            })
+        }).await
+    }
+
+    /// Deletes the category, taking it off every deal filed under it. Fails with
+    /// `Error::ForeignKeyViolation` if it still has subcategories.
+    pub async fn delete(pool: &DbPool, id: Uuid) -> Result<(), Error> {
+        use schema::categories::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                match diesel::delete(categories.find(id)).execute(conn)? {
+                    0 => Err(diesel::result::Error::NotFound),
+                    _ => Ok(()),
+                }
+            })
+        }).await
+    }
+
+    /// Loads the categories the deal is filed under, in the order of their names
+    pub async fn get_for_deal(pool: &DbPool, deal: Uuid) -> Result<Vec<Category>, Error> {
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                schema::deals::table
+                    .find(deal)
+                    .select(schema::deals::uuid)
+                    .first::<Uuid>(conn)?;
+
+                for_deal(conn, deal)
+            })
+        }).await
+    }
+
+    /// Files the deal under exactly the given categories, in place of the ones it was under.
+    /// Fails with `Error::NotFound` if the deal doesn't exist, and with
+    /// `Error::ForeignKeyViolation` if any of the categories doesn't.
+    pub async fn set_for_deal(pool: &DbPool, deal: Uuid, category_ids: Vec<Uuid>) -> Result<Vec<Category>, Error> {
+        use schema::{ deal_categories, deals };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                deals::table
+                    .find(deal)
+                    .select(deals::uuid)
+                    .for_update()
+                    .first::<Uuid>(conn)?;
+
+                diesel::delete(deal_categories::table.filter(deal_categories::deal_id.eq(deal)))
+                    .execute(conn)?;
+
+                diesel::insert_into(deal_categories::table)
+                    .values(category_ids.iter()
+                        .map(|category| (
+                            deal_categories::deal_id.eq(deal),
+                            deal_categories::category_id.eq(category),
+                        ))
+                        .collect::<Vec<_>>()
+                    )
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+
+                for_deal(conn, deal)
+            })
+        }).await
+    }
+
+    /// The uuids of the category and of every category below it, inside the caller's
+    /// transaction. Fails with `NotFound` if the category doesn't exist.
+    pub fn subtree(conn: &mut PgConnection, root: Uuid) -> QueryResult<Vec<Uuid>> {
+        use schema::categories::dsl::*;
+
+        let edges = categories
+            .select((uuid, parent_id))
+            .load::<(Uuid, Option<Uuid>)>(conn)?;
+
+        if !edges.iter().any(|(category, _)| *category == root) {
+            return Err(diesel::result::Error::NotFound);
+        }
+
+        Ok(descendants(&edges, root))
+    }
+}
+
+/// Loads the categories the deal is filed under, in the order of their names
+fn for_deal(conn: &mut PgConnection, deal: Uuid) -> QueryResult<Vec<Category>> {
+    use schema::{ categories, deal_categories };
+
+    deal_categories::table
+        .inner_join(categories::table)
+        .filter(deal_categories::deal_id.eq(deal))
+        .order((categories::name.asc(), categories::uuid.asc()))
+        .select(Category::as_select())
+        .load::<Category>(conn)
+}
+
+/// The root and every category below it, given the (category, parent) edges of the whole tree
+fn descendants(edges: &[(Uuid, Option<Uuid>)], root: Uuid) -> Vec<Uuid> {
+    let mut children = HashMap::<Uuid, Vec<Uuid>>::new();
+    for (category, parent) in edges {
+        if let Some(parent) = parent {
+            children.entry(*parent).or_default().push(*category);
+        }
+    }
+
+    let mut found = vec![root];
+    let mut next = 0;
+    while let Some(category) = found.get(next).copied() {
+        found.extend(children.get(&category).into_iter().flatten().filter(|child| **child != root));
+        next += 1;
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ create_pool, CreateDeal, Deal, Money };
+    use crate::net::{ ItemSearch, Pagination };
+
+    #[test]
+    fn finds_every_category_below_the_root() {
+        let [a, b, c, d, e] = [1, 2, 3, 4, 5].map(Uuid::from_u128);
+        let edges = vec![(a, None), (b, Some(a)), (c, Some(b)), (d, Some(a)), (e, None)];
+
+        let mut below_a = descendants(&edges, a);
+        below_a.sort();
+        assert_eq!(below_a, vec![a, b, c, d]);
+        assert_eq!(descendants(&edges, c), vec![c]);
+        assert_eq!(descendants(&edges, e), vec![e]);
+    }
+
+    #[test]
+    fn tells_null_parents_from_missing_ones() {
+        let moved = async_session::serde_json::from_str::<CategoryChanges>(r#"{"parent_id": null}"#).unwrap();
+        assert_eq!(moved.parent_id, Some(None));
+
+        let renamed = async_session::serde_json::from_str::<CategoryChanges>(r#"{"name": "Boots"}"#).unwrap();
+        assert_eq!(renamed.parent_id, None);
+        assert!(!renamed.is_empty());
+    }
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn deals_are_listed_under_their_categories_and_those_above() {
+        let pool = create_pool();
+        let marker = Uuid::new_v4().simple().to_string();
+
+        let clothing = Category::insert(&pool, CreateCategory { name: format!("Clothing {}", marker), parent_id: None }).await.unwrap();
+        let shoes = Category::insert(&pool, CreateCategory { name: "Shoes".to_string(), parent_id: Some(clothing.uuid) }).await.unwrap();
+        let boots = Category::insert(&pool, CreateCategory { name: "Boots".to_string(), parent_id: Some(shoes.uuid) }).await.unwrap();
+        assert!(matches!(
+            Category::insert(&pool, CreateCategory { name: "Shoes".to_string(), parent_id: Some(clothing.uuid) }).await,
+            Err(Error::UniqueViolation(_))
+        ));
+
+        let deal = Deal::insert(&pool, CreateDeal {
+            name: "Category test boots".to_string(),
+            image: "https://example.com/deal.png".to_string(),
+            price: Money::new(8000, "USD".parse().unwrap()),
+            description: "A deal filed by the category tests".to_string(),
+        }).await.unwrap().uuid.unwrap();
+
+        let filed = Category::set_for_deal(&pool, deal, vec![boots.uuid, boots.uuid]).await.unwrap();
+        assert_eq!(filed.len(), 1);
+        assert!(matches!(
+            Category::set_for_deal(&pool, deal, vec![Uuid::new_v4()]).await,
+            Err(Error::ForeignKeyViolation(_))
+        ));
+        assert_eq!(Category::get_for_deal(&pool, deal).await.unwrap()[0].uuid, boots.uuid);
+
+        for category in [clothing.uuid, shoes.uuid, boots.uuid] {
+            let listed = Deal::get_all(&pool, Some(category), Pagination::default()).await.unwrap().items;
+            assert_eq!(listed.iter().map(|deal| deal.uuid.unwrap()).collect::<Vec<Uuid>>(), vec![deal]);
+        }
+
+        let found = Deal::search(&pool, ItemSearch {
+            q: Some("category boots".to_string()),
+            category: Some(clothing.uuid),
+            ..ItemSearch::default()
+        }, Pagination::default()).await.unwrap().items;
+        assert_eq!(found.len(), 1);
+
+        assert!(matches!(
+            Category::update(&pool, clothing.uuid, CategoryChanges { parent_id: Some(Some(boots.uuid)), ..CategoryChanges::default() }).await,
+            Err(Error::InvalidData(_))
+        ));
+        assert!(matches!(Category::delete(&pool, shoes.uuid).await, Err(Error::ForeignKeyViolation(_))));
+
+        let moved = Category::update(&pool, boots.uuid, CategoryChanges { parent_id: Some(None), ..CategoryChanges::default() }).await.unwrap();
+        assert_eq!(moved.parent_id, None);
+        assert!(Deal::get_all(&pool, Some(clothing.uuid), Pagination::default()).await.unwrap().items.is_empty());
+
+        Category::delete(&pool, boots.uuid).await.unwrap();
+        assert!(Category::get_for_deal(&pool, deal).await.unwrap().is_empty());
+        Category::delete(&pool, shoes.uuid).await.unwrap();
+        Category::delete(&pool, clothing.uuid).await.unwrap();
+        assert!(matches!(Deal::get_all(&pool, Some(clothing.uuid), Pagination::default()).await, Err(Error::NotFound)));
+    }
+}
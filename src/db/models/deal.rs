@@ -1,17 +1,27 @@
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
 use uuid::Uuid;
-use diesel::{ prelude::*, RunQueryDsl, QueryDsl, };
+use diesel::{ deserialize, pg::Pg, prelude::*, sql_types, RunQueryDsl, QueryDsl, };
 use serde::{ Serialize, Deserialize };
+use validator::{ Validate, ValidationError };
 
 use super::schema;
-use crate::{ establish_connection, net::Pagination };
+use crate::{
+    db::{ english, keyset, ts_rank, Category, websearch_to_tsquery, with_connection, Currency, DbPool, Error, Inventory, Matches, Money },
+    net::{ ItemSearch, ItemSort, Page, Pagination },
+};
 
 /// The struct to represent a deal returned from the postgresql database
 /// 
 /// This struct is a representation of the schema from the deals table in the commerce database.
-/// Currently this includes fields for the deal's uuid, name, image url, price in minor units, and
-/// the deal's description. It is mainly used for parsing database responses.
+/// Currently this includes fields for the deal's uuid, name, image url, price, the deal's
+/// description, the row version, and when it was created. It is mainly used for parsing database
+/// responses. The price is kept in the price_amount and price_currency columns, see `Money`. The
+/// deals.search column only feeds `Deal::search` and is never selected, so deals are loaded with
+/// `Deal::as_select()` rather than the whole row.
 /// 
-/// deal.uuid is the primary key of the table
+/// deal.uuid is the primary key of the table. deal.version starts at 1 and is bumped on every
+/// write, it is exposed to clients as the item's ETag so concurrent edits can be detected.
 /// 
 /// # Examples
 /// 
@@ -19,72 +29,504 @@ use crate::{ establish_connection, net::Pagination };
 /// // this assumes you are using diesel
 /// use commerce::db::models::schema::deals::dsl::*;
 ///
-/// let connection = &mut establish_connection();
-///
-/// let response = deals
-///     .filter(uuid.eq(item_id))
-///     .first::<Deal>(connection);
+/// let response = with_connection(&pool, move |conn| {
+///     deals
+///         .filter(uuid.eq(item_id))
+///         .select(Deal::as_select())
+///         .first::<Deal>(conn)
+/// }).await;
 /// ```
 
-#[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
-#[diesel(primary_key(uuid), table_name = schema::deals)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Deal {
-    #[diesel(deserialize_as = Uuid)]
     pub uuid: Option<Uuid>,
     pub name: String,
     pub image: String,
-    pub price: i32,
+    pub price: Money,
     pub description: String,
+    pub version: i32,
+    pub created_at: NaiveDateTime,
+}
+
+type DealSqlType = (
+    sql_types::Uuid,
+    sql_types::Text,
+    sql_types::Text,
+    sql_types::BigInt,
+    sql_types::Text,
+    sql_types::Integer,
+    sql_types::Text,
+    sql_types::Timestamp,
+);
+
+impl Queryable<DealSqlType, Pg> for Deal {
+    type Row = (Uuid, String, String, i64, String, i32, Currency, NaiveDateTime);
+
+    fn build((uuid, name, image, price_amount, description, version, price_currency, created_at): Self::Row) -> deserialize::Result<Self> {
+        Ok(Deal {
+            uuid: Some(uuid),
+            name,
+            image,
+            price: Money::new(price_amount, price_currency),
+            description,
+            version,
+            created_at,
+        })
+    }
+}
+
+impl Selectable<Pg> for Deal {
+    type SelectExpression = (
+        schema::deals::uuid,
+        schema::deals::name,
+        schema::deals::image,
+        schema::deals::price_amount,
+        schema::deals::description,
+        schema::deals::version,
+        schema::deals::price_currency,
+        schema::deals::created_at,
+    );
+
+    fn construct_selection() -> Self::SelectExpression {
+        use schema::deals::dsl::*;
+
+        (uuid, name, image, price_amount, description, version, price_currency, created_at)
+    }
+}
+
+/// The client supplied fields of a new deal
+/// 
+/// Used as the body of create (POST) and replace (PUT) requests. It deliberately has no uuid or
+/// version field, the uuid is always generated server side by `Deal::insert` and the version is
+/// managed by the database, so neither can be chosen by the client. Call `validate` before use.
+#[derive(Deserialize, Validate, Clone, Debug)]
+pub struct CreateDeal {
+    #[validate(length(min = 1, max = 256, message = "must be between 1 and 256 characters"))]
+    pub name: String,
+    #[validate(url(message = "must be a valid url"))]
+    pub image: String,
+    #[validate(custom = "validate_price")]
+    pub price: Money,
+    #[validate(length(max = 4096, message = "must be at most 4096 characters"))]
+    pub description: String,
+}
+
+/// The set of deal fields a client may change
+/// 
+/// Used as the body of partial (PATCH) updates, where only the fields present in the request are
+/// written, and built from a `CreateDeal` for replacing (PUT) updates. Present fields are held to
+/// the same rules as `CreateDeal`. The uuid and version are never client controlled, the version
+/// is bumped by `Deal::update` itself.
+#[derive(Deserialize, Validate, Clone, Debug, Default)]
+pub struct DealChanges {
+    #[validate(length(min = 1, max = 256, message = "must be between 1 and 256 characters"))]
+    pub name: Option<String>,
+    #[validate(url(message = "must be a valid url"))]
+    pub image: Option<String>,
+    #[validate(custom = "validate_price")]
+    pub price: Option<Money>,
+    #[validate(length(max = 4096, message = "must be at most 4096 characters"))]
+    pub description: Option<String>,
+}
+
+/// Prices must be greater than 0, in any currency
+fn validate_price(price: &Money) -> Result<(), ValidationError> {
+    if price.amount < 1 {
+        let mut error = ValidationError::new("range");
+        error.message = Some("must be greater than 0".into());
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+impl DealChanges {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.image.is_none()
+            && self.price.is_none()
+            && self.description.is_none()
+    }
+}
+
+impl From<CreateDeal> for DealChanges {
+    fn from(deal: CreateDeal) -> Self {
+        Self {
+            name: Some(deal.name),
+            image: Some(deal.image),
+            price: Some(deal.price),
+            description: Some(deal.description),
+        }
+    }
 }
 
 impl Deal {
-    pub fn get(id: Uuid) -> Option<Deal> {
+    pub async fn get(pool: &DbPool, id: Uuid) -> Result<Deal, Error> {
+        use schema::deals::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                deals
+                    .filter(uuid.eq(id))
+                    .select(Deal::as_select())
+                    .first::<Deal>(conn)
+            })
+        }).await
+    }
+
+    /// Loads a page of every deal, or of the deals in the category and the categories below it,
+    /// the newest first. Fails with `Error::NotFound` if the category doesn't exist.
+    pub async fn get_all(pool: &DbPool, category: Option<Uuid>, pagination: Pagination) -> Result<Page<Deal>, Error> {
+        use schema::deals::dsl::*;
+
+        let cursor = pagination.cursor::<NaiveDateTime, Uuid>()?;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                let categories = category.map(|category| Category::subtree(conn, category)).transpose()?;
+                let listed = || {
+                    let mut query = deals.into_boxed();
+                    if let Some(categories) = &categories {
+                        query = query.filter(in_categories(categories));
+                    }
+                    query
+                };
+
+                let backward = cursor.as_ref().is_some_and(|cursor| cursor.backward);
+                let after = cursor.as_ref().map(|cursor| (cursor.key, cursor.id));
+
+                let rows = keyset!(listed().select(Deal::as_select()), created_at, uuid, backward, after)
+                    .limit(pagination.get_limit() + 1)
+                    .load::<Deal>(conn)?;
+
+                let total = match pagination.wants_total() {
+                    true => Some(listed().count().get_result::<i64>(conn)?),
+                    false => None,
+                };
+
+                Ok::<_, Error>(pagination.page(&cursor, rows, |deal| (deal.created_at, deal.uuid.unwrap()), total))
+            })
+        }).await
+    }
+
+    /// Loads a page of the deals matching the search, see `ItemSearch`. Without search terms every
+    /// deal in the price range matches.
+    pub async fn search(pool: &DbPool, query: ItemSearch, pagination: Pagination) -> Result<Page<Deal>, Error> {
+        use schema::deals::dsl::*;
+
+        let cursor = pagination.cursor::<SearchKey, Uuid>()?;
+        let sort = match (query.sort, &query.q) {
+            (ItemSort::Relevance, None) => ItemSort::Newest,
+            (sort, _) => sort,
+        };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                let categories = query.category.map(|category| Category::subtree(conn, category)).transpose()?;
+                let backward = cursor.as_ref().is_some_and(|cursor| cursor.backward);
+                let results = matching(&query, categories.as_deref()).select(Deal::as_select());
+                let terms = query.q.clone().unwrap_or_default();
+                let invalid = || Error::InvalidData("Cursor doesn't match the sort order".to_string());
+
+                // each sort seeks by its own key, a cursor of another sort is refused
+                let results = match sort {
+                    ItemSort::Relevance => {
+                        let after = cursor.as_ref()
+                            .map(|cursor| match cursor.key {
+                                SearchKey::Rank(rank) => Ok((rank, cursor.id)),
+                                _ => Err(invalid()),
+                            })
+                            .transpose()?;
+                        keyset!(results, ts_rank(search, websearch_to_tsquery(english(), terms.clone())), uuid, backward, after)
+                    },
+                    ItemSort::PriceAsc | ItemSort::PriceDesc => {
+                        let after = cursor.as_ref()
+                            .map(|cursor| match cursor.key {
+                                SearchKey::Price(amount) => Ok((amount, cursor.id)),
+                                _ => Err(invalid()),
+                            })
+                            .transpose()?;
+                        keyset!(results, price_amount, uuid, (sort == ItemSort::PriceAsc) != backward, after)
+                    },
+                    ItemSort::Name => {
+                        let after = cursor.as_ref()
+                            .map(|cursor| match &cursor.key {
+                                SearchKey::Name(deal_name) => Ok((deal_name.clone(), cursor.id)),
+                                _ => Err(invalid()),
+                            })
+                            .transpose()?;
+                        keyset!(results, name, uuid, !backward, after)
+                    },
+                    ItemSort::Newest => {
+                        let after = cursor.as_ref()
+                            .map(|cursor| match cursor.key {
+                                SearchKey::Newest(created) => Ok((created, cursor.id)),
+                                _ => Err(invalid()),
+                            })
+                            .transpose()?;
+                        keyset!(results, created_at, uuid, backward, after)
+                    },
+                };
+
+                let rows = results
+                    .limit(pagination.get_limit() + 1)
+                    .load::<Deal>(conn)?;
+
+                // the rank of a row isn't loaded along with it, so it is looked up for the cursors
+                let ranks = match sort {
+                    ItemSort::Relevance => deals
+                        .filter(uuid.eq_any(rows.iter().filter_map(|deal| deal.uuid).collect::<Vec<Uuid>>()))
+                        .select((uuid, ts_rank(search, websearch_to_tsquery(english(), terms.clone()))))
+                        .load::<(Uuid, f32)>(conn)?
+                        .into_iter()
+                        .collect::<HashMap<Uuid, f32>>(),
+                    _ => HashMap::new(),
+                };
+
+                let total = match pagination.wants_total() {
+                    true => Some(matching(&query, categories.as_deref()).count().get_result::<i64>(conn)?),
+                    false => None,
+                };
+
+                let page = pagination.page(&cursor, rows, |deal| {
+                    let key = match sort {
+                        ItemSort::Relevance => SearchKey::Rank(ranks[&deal.uuid.unwrap()]),
+                        ItemSort::PriceAsc | ItemSort::PriceDesc => SearchKey::Price(deal.price.amount),
+                        ItemSort::Name => SearchKey::Name(deal.name.clone()),
+                        ItemSort::Newest => SearchKey::Newest(deal.created_at),
+                    };
+                    (key, deal.uuid.unwrap())
+                }, total);
+
+                Ok::<_, Error>(page)
+            })
+        }).await
+    }
+
+    pub async fn insert(pool: &DbPool, new_deal: CreateDeal) -> Result<Deal, Error> {
         use schema::deals::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
-        .read_only()
-        .run(|conn| {
-            deals
-                .filter(uuid.eq(id))
-                .first::<Deal>(conn)
-        });
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let deal = diesel::insert_into(deals)
+                    .values((
+                        uuid.eq(Uuid::new_v4()),
+                        name.eq(&new_deal.name),
+                        image.eq(&new_deal.image),
+                        price_amount.eq(new_deal.price.amount),
+                        price_currency.eq(new_deal.price.currency),
+                        description.eq(&new_deal.description),
+                    ))
+                    .returning(Deal::as_returning())
+                    .get_result::<Deal>(conn)?;
 
-        response.ok()
+                Inventory::create(conn, deal.uuid.unwrap())?;
+                Ok::<_, Error>(deal)
+            })
+        }).await
     }
 
-    pub fn get_all(pagination: Pagination) -> Option<Vec<Deal>> {
+    /// Applies the changes to the deal, failing with `VersionMismatch` if an expected version is
+    /// given and the stored row has moved on since. The row is locked for the duration of the
+    /// check so two writers holding the same version cannot both succeed.
+    pub async fn update(
+        pool: &DbPool,
+        id: Uuid,
+        expected_version: Option<i32>,
+        changes: DealChanges,
+    ) -> Result<Deal, Error> {
         use schema::deals::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
-        .read_only()
-        .run(|conn| {
-            deals
-                .limit(pagination.get_limit())
-                .offset(pagination.get_offset())
-                .load::<Deal>(conn)
-        });
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let current_version = deals
+                    .filter(uuid.eq(id))
+                    .select(version)
+                    .for_update()
+                    .first::<i32>(conn)?;
+
+                if expected_version.is_some_and(|expected| expected != current_version) {
+                    return Err(Error::VersionMismatch);
+                }
 
-        response.ok()
+                diesel::update(deals.filter(uuid.eq(id)))
+                    .set((
+                        changes.name.as_ref().map(|value| name.eq(value)),
+                        changes.image.as_ref().map(|value| image.eq(value)),
+                        changes.price.map(|value| price_amount.eq(value.amount)),
+                        changes.price.map(|value| price_currency.eq(value.currency)),
+                        changes.description.as_ref().map(|value| description.eq(value)),
+                        version.eq(current_version + 1),
+                    ))
+                    .returning(Deal::as_returning())
+                    .get_result::<Deal>(conn)
+                    .map_err(Error::from)
+            })
+        }).await
     }
 
-    pub fn insert(&self) -> Option<Deal> {
+    /// Deletes the deal, with the same version check as `Deal::update`.
+    pub async fn delete(pool: &DbPool, id: Uuid, expected_version: Option<i32>) -> Result<(), Error> {
         use schema::deals::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
-        .read_write()
-        .run(|conn| {
-            diesel::insert_into(deals)
-                .values((
-                    name.eq(&self.name),
-                    image.eq(&self.image),
-                    price.eq(&self.price),
-                ))
-                .get_result::<Deal>(conn)
-            });
-
-        response.ok()
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let current_version = deals
+                    .filter(uuid.eq(id))
+                    .select(version)
+                    .for_update()
+                    .first::<i32>(conn)?;
+
+                if expected_version.is_some_and(|expected| expected != current_version) {
+                    return Err(Error::VersionMismatch);
+                }
+
+                diesel::delete(deals.filter(uuid.eq(id)))
+                    .execute(conn)
+                    .map(|_| ())
+                    .map_err(Error::from)
+            })
+        }).await
+    }
+
+    /// The ETag header value for this version of the deal
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.version)
+    }
+}
+
+/// What a search cursor seeks by, the key of the sort it was issued for
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum SearchKey {
+    Rank(f32),
+    Price(i64),
+    Name(String),
+    Newest(NaiveDateTime),
+}
+
+/// Whether the deal is filed under any of the categories
+fn in_categories(categories: &[Uuid]) -> Box<dyn BoxableExpression<schema::deals::table, Pg, SqlType = sql_types::Bool>> {
+    use schema::deal_categories;
+
+    Box::new(schema::deals::uuid.eq_any(
+        deal_categories::table
+            .filter(deal_categories::category_id.eq_any(categories.to_vec()))
+            .select(deal_categories::deal_id)
+    ))
+}
+
+/// The deals matching the search terms and filters, in no particular order. `categories` are
+/// the uuids of the searched category and the ones below it.
+fn matching(query: &ItemSearch, categories: Option<&[Uuid]>) -> schema::deals::BoxedQuery<'static, Pg> {
+    use schema::deals::dsl::*;
+
+    let mut results = deals.into_boxed();
+
+    if let Some(terms) = &query.q {
+        results = results.filter(Matches::new(search, websearch_to_tsquery(english(), terms.clone())));
+    }
+    if let Some(min) = query.min_price {
+        results = results.filter(price_amount.ge(min));
+    }
+    if let Some(max) = query.max_price {
+        results = results.filter(price_amount.le(max));
+    }
+    if let Some(code) = query.currency {
+        results = results.filter(price_currency.eq(code));
+    }
+    if let Some(categories) = categories {
+        results = results.filter(in_categories(categories));
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::create_pool;
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn search_ranks_filters_and_sorts_deals() {
+        let pool = create_pool();
+        // a currency of its own keeps the deals of other tests out of the results
+        let currency: Currency = "ZZS".parse().unwrap();
+        let marker = Uuid::new_v4().simple().to_string();
+
+        for (deal_name, description, amount) in [
+            ("Walnut desk", "A solid walnut writing desk", 30000),
+            ("Desk lamp", "A brass lamp for any walnut desk", 4500),
+            ("Office chair", "Pairs well with a desk", 12000),
+        ] {
+            Deal::insert(&pool, CreateDeal {
+                name: deal_name.to_string(),
+                image: "https://example.com/deal.png".to_string(),
+                price: Money::new(amount, currency),
+                description: format!("{} {}", description, marker),
+            }).await.unwrap();
+        }
+
+        let names = |found: Vec<Deal>| found.into_iter().map(|deal| deal.name).collect::<Vec<String>>();
+        let search = |q: &str, sort: ItemSort| ItemSearch {
+            q: Some(format!("{} {}", q, marker)),
+            currency: Some(currency),
+            sort,
+            ..ItemSearch::default()
+        };
+
+        let found = Deal::search(&pool, search("walnut desk", ItemSort::Relevance), Pagination::default()).await.unwrap().items;
+        assert_eq!(names(found), vec!["Walnut desk", "Desk lamp"]);
+
+        let found = Deal::search(&pool, search("desk", ItemSort::PriceAsc), Pagination::default()).await.unwrap().items;
+        assert_eq!(names(found), vec!["Desk lamp", "Office chair", "Walnut desk"]);
+
+        let found = Deal::search(&pool, search("desk -lamp", ItemSort::Name), Pagination::default()).await.unwrap().items;
+        assert_eq!(names(found), vec!["Office chair", "Walnut desk"]);
+
+        let found = Deal::search(&pool, ItemSearch {
+            min_price: Some(5000),
+            max_price: Some(30000),
+            sort: ItemSort::PriceDesc,
+            ..search("desk", ItemSort::Relevance)
+        }, Pagination::default()).await.unwrap().items;
+        assert_eq!(names(found), vec!["Walnut desk", "Office chair"]);
+
+        let found = Deal::search(&pool, search("\"walnut writing\"", ItemSort::Relevance), Pagination::default()).await.unwrap().items;
+        assert_eq!(names(found), vec!["Walnut desk"]);
+
+        let first = Deal::search(&pool, search("desk", ItemSort::Name), Pagination::new(None, Some(2), true)).await.unwrap();
+        assert_eq!((first.total, first.prev.is_none()), (Some(3), true));
+        let last = Deal::search(&pool, search("desk", ItemSort::Name), Pagination::new(first.next.clone(), Some(2), false)).await.unwrap();
+        assert_eq!((last.total, last.next.is_none()), (None, true));
+        assert_eq!(names(first.items), vec!["Desk lamp", "Office chair"]);
+        assert_eq!(names(last.items), vec!["Walnut desk"]);
+
+        let back = Deal::search(&pool, search("desk", ItemSort::Name), Pagination::new(last.prev, Some(2), false)).await.unwrap();
+        assert_eq!(names(back.items), vec!["Desk lamp", "Office chair"]);
+        assert_eq!((back.next, back.prev), (first.next.clone(), None));
+
+        let ranked = Deal::search(&pool, search("walnut desk", ItemSort::Relevance), Pagination::new(None, Some(1), false)).await.unwrap();
+        let next = Deal::search(&pool, search("walnut desk", ItemSort::Relevance), Pagination::new(ranked.next, Some(1), false)).await.unwrap();
+        assert_eq!(names(next.items), vec!["Desk lamp"]);
+
+        assert!(matches!(
+            Deal::search(&pool, search("desk", ItemSort::PriceAsc), Pagination::new(first.next, Some(2), false)).await,
+            Err(Error::InvalidData(_))
+        ));
     }
 }
@@ -0,0 +1,298 @@
+use chrono::{ Duration, NaiveDateTime, Utc };
+use diesel::prelude::*;
+use serde::{ Serialize, Deserialize };
+use uuid::Uuid;
+
+use super::schema;
+use crate::db::{ with_connection, DbPool, Error };
+
+/// How long units added to a cart stay reserved for it, in minutes. Changing the item's quantity
+/// starts the reservation over.
+const RESERVATION_MINUTES: i64 = 15;
+
+/// The struct to represent the stock of a deal returned from the postgresql database
+///
+/// This struct is a representation of the schema from the inventory table in the commerce
+/// database. on_hand is how many units are in stock, reserved how many of them are held for carts
+/// by `StockReservation`s. Units on hand that aren't reserved are available to anyone.
+///
+/// Adding an item to a cart reserves its units for the cart, and placing an order takes them off
+/// the shelf. Either fails with `Error::InsufficientStock` when too few units are available.
+/// Reservations are released when the item is removed from the cart, or when they expire.
+///
+/// inventory.deal_id is the primary key of the table.
+///
+/// # Examples
+///
+/// ```
+/// Inventory::set_on_hand(&pool, deal_id, 25).await?;
+/// let stock = Inventory::get(&pool, deal_id).await?;
+/// println!("{} left", stock.available());
+/// ```
+#[derive(Queryable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(deal_id), table_name = schema::inventory)]
+pub struct Inventory {
+    pub deal_id: Uuid,
+    pub on_hand: i32,
+    pub reserved: i32,
+}
+
+/// The struct to represent units of a deal held for a cart, returned from the postgresql database
+///
+/// This struct is a representation of the schema from the stock_reservations table in the
+/// commerce database. A database trigger keeps inventory.reserved in step with these rows, so
+/// reservations are released by deleting them, whichever way that happens.
+///
+/// (stock_reservation.cart_id, stock_reservation.deal_id) is the primary key of the table.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(cart_id, deal_id), table_name = schema::stock_reservations)]
+pub struct StockReservation {
+    pub cart_id: Uuid,
+    pub deal_id: Uuid,
+    pub quantity: i32,
+    pub expires_at: NaiveDateTime,
+}
+
+impl Inventory {
+    /// The units on hand that aren't reserved
+    pub fn available(&self) -> i32 {
+        self.on_hand - self.reserved
+    }
+
+    pub async fn get(pool: &DbPool, deal: Uuid) -> Result<Inventory, Error> {
+        use schema::inventory::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                inventory
+                    .find(deal)
+                    .first::<Inventory>(conn)
+            })
+        }).await
+    }
+
+    /// Sets how many units of the deal are on hand. Fails with `Error::InvalidData` if that is
+    /// fewer than are reserved.
+    pub async fn set_on_hand(pool: &DbPool, deal: Uuid, units: i32) -> Result<Inventory, Error> {
+        use schema::inventory::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::update(inventory.find(deal))
+                    .set(on_hand.eq(units))
+                    .get_result::<Inventory>(conn)
+            })
+        }).await
+    }
+
+    /// Creates the empty stock of a newly inserted deal
+    pub fn create(conn: &mut PgConnection, deal: Uuid) -> QueryResult<()> {
+        use schema::inventory::dsl::*;
+
+        diesel::insert_into(inventory)
+            .values(deal_id.eq(deal))
+            .execute(conn)
+            .map(|_| ())
+    }
+
+    /// Reserves `quantity` units of the deal for the cart, in place of any units it already held.
+    /// Fails with `Error::InsufficientStock` if fewer are available to the cart, counting the
+    /// units it already held, leaving its reservation as it was.
+    pub fn reserve(conn: &mut PgConnection, cart: Uuid, deal: Uuid, quantity: i32) -> Result<(), Error> {
+        let remaining = available_to(conn, cart, deal)?;
+        if quantity > remaining {
+            return Err(Error::InsufficientStock { deal, remaining });
+        }
+
+        hold(conn, cart, deal, quantity).map_err(Error::from)
+    }
+
+    /// Reserves as many of `quantity` units of the deal for the cart as are available to it, in
+    /// place of any units it already held
+    pub fn reserve_up_to(conn: &mut PgConnection, cart: Uuid, deal: Uuid, quantity: i32) -> QueryResult<()> {
+        let remaining = available_to(conn, cart, deal)?;
+        hold(conn, cart, deal, quantity.min(remaining))
+    }
+
+    /// Releases the units of the deal reserved for the cart, or of every deal if `deal` is `None`
+    pub fn release(conn: &mut PgConnection, cart: Uuid, deal: Option<Uuid>) -> QueryResult<usize> {
+        use schema::stock_reservations;
+
+        let mut query = diesel::delete(stock_reservations::table)
+            .filter(stock_reservations::cart_id.eq(cart))
+            .into_boxed();
+
+        if let Some(deal) = deal {
+            query = query.filter(stock_reservations::deal_id.eq(deal));
+        }
+
+        query.execute(conn)
+    }
+
+    /// Takes the units of the cart's lines off the shelf as their order is placed, releasing the
+    /// cart's reservations. The stock of every deal is locked, in a fixed order so concurrent
+    /// checkouts can't deadlock. Fails with `Error::InsufficientStock` for the first deal whose
+    /// units available to the cart, reserved or not, fall short of its line.
+    pub fn commit(conn: &mut PgConnection, cart: Uuid, lines: &[(Uuid, i32)]) -> Result<(), Error> {
+        use schema::{ inventory, stock_reservations };
+
+        let mut lines = lines.to_vec();
+        lines.sort_by_key(|(deal, _)| *deal);
+
+        for (deal, quantity) in lines {
+            let remaining = available_to(conn, cart, deal)?;
+            if quantity > remaining {
+                return Err(Error::InsufficientStock { deal, remaining });
+            }
+
+            diesel::delete(stock_reservations::table.find((cart, deal)))
+                .execute(conn)?;
+
+            diesel::update(inventory::table.find(deal))
+                .set(inventory::on_hand.eq(inventory::on_hand - quantity))
+                .execute(conn)?;
+        }
+
+        Ok(())
+    }
+
+    /// Releases the reservations past their expiry, returning how many were released. Run by the
+    /// cleanup jobs.
+    pub fn release_expired(conn: &mut PgConnection) -> QueryResult<usize> {
+        use schema::stock_reservations::dsl::*;
+
+        diesel::delete(stock_reservations.filter(expires_at.lt(Utc::now().naive_utc())))
+            .execute(conn)
+    }
+}
+
+/// Locks the stock of the deal and returns how many units are available to the cart, counting
+/// the units it already holds. A deal without stock has none available.
+fn available_to(conn: &mut PgConnection, cart: Uuid, deal: Uuid) -> QueryResult<i32> {
+    use schema::{ inventory, stock_reservations };
+
+    let Some(stock) = inventory::table
+        .find(deal)
+        .for_update()
+        .first::<Inventory>(conn)
+        .optional()? else {
+        return Ok(0);
+    };
+
+    let held = stock_reservations::table
+        .find((cart, deal))
+        .select(stock_reservations::quantity)
+        .first::<i32>(conn)
+        .optional()?
+        .unwrap_or(0);
+
+    Ok(stock.available() + held)
+}
+
+/// Replaces the cart's reservation of the deal with one of `quantity` units, releasing it if
+/// that is none. The stock must already be locked by `available_to`.
+fn hold(conn: &mut PgConnection, cart: Uuid, deal: Uuid, quantity: i32) -> QueryResult<()> {
+    use schema::stock_reservations;
+
+    if quantity <= 0 {
+        return Inventory::release(conn, cart, Some(deal)).map(|_| ());
+    }
+
+    diesel::insert_into(stock_reservations::table)
+        .values(&StockReservation {
+            cart_id: cart,
+            deal_id: deal,
+            quantity,
+            expires_at: Utc::now().naive_utc() + Duration::minutes(RESERVATION_MINUTES),
+        })
+        .on_conflict((stock_reservations::cart_id, stock_reservations::deal_id))
+        .do_update()
+        .set((
+            stock_reservations::quantity.eq(quantity),
+            stock_reservations::expires_at.eq(Utc::now().naive_utc() + Duration::minutes(RESERVATION_MINUTES)),
+        ))
+        .execute(conn)
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ create_pool, insert_test_user, Cart, CartOwner, CreateDeal, Deal, Money, Order };
+
+    async fn stock(pool: &DbPool, deal: Uuid) -> (i32, i32) {
+        let stock = Inventory::get(pool, deal).await.unwrap();
+        (stock.on_hand, stock.reserved)
+    }
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn carts_reserve_and_orders_take_stock() {
+        use schema::stock_reservations;
+
+        let pool = create_pool();
+        let deal = Deal::insert(&pool, CreateDeal {
+            name: "Inventory test deal".to_string(),
+            image: "https://example.com/deal.png".to_string(),
+            price: Money::new(500, "USD".parse().unwrap()),
+            description: "A deal stocked by the inventory tests".to_string(),
+        }).await.unwrap().uuid.unwrap();
+
+        let user = insert_test_user(&pool, "inventory").await;
+
+        let buyer = CartOwner::User(user);
+        let browser = CartOwner::Session(Uuid::new_v4().to_string());
+
+        assert_eq!(stock(&pool, deal).await, (0, 0));
+        assert!(matches!(
+            Cart::add_item(&pool, &buyer, deal, 1).await,
+            Err(Error::InsufficientStock { remaining: 0, .. })
+        ));
+
+        Inventory::set_on_hand(&pool, deal, 5).await.unwrap();
+        Cart::add_item(&pool, &buyer, deal, 3).await.unwrap();
+        assert!(matches!(
+            Cart::add_item(&pool, &browser, deal, 3).await,
+            Err(Error::InsufficientStock { remaining: 2, .. })
+        ));
+        Cart::add_item(&pool, &browser, deal, 2).await.unwrap();
+        assert_eq!(stock(&pool, deal).await, (5, 5));
+        assert!(matches!(Inventory::set_on_hand(&pool, deal, 4).await, Err(Error::InvalidData(_))));
+
+        Cart::set_quantity(&pool, &buyer, deal, 1).await.unwrap();
+        assert_eq!(stock(&pool, deal).await, (5, 3));
+
+        with_connection(&pool, move |conn| {
+            diesel::update(stock_reservations::table.filter(stock_reservations::deal_id.eq(deal)))
+                .set(stock_reservations::expires_at.eq(Utc::now().naive_utc() - Duration::minutes(1)))
+                .execute(conn)?;
+            Inventory::release_expired(conn)
+        }).await.unwrap();
+        assert_eq!(stock(&pool, deal).await, (5, 0));
+
+        Cart::set_quantity(&pool, &buyer, deal, 4).await.unwrap();
+        Cart::remove_item(&pool, &browser, deal).await.unwrap();
+        let (order, _) = Order::place(&pool, user).await.unwrap().unwrap();
+        assert_eq!(order.total, Money::new(2000, "USD".parse().unwrap()));
+        assert_eq!(stock(&pool, deal).await, (1, 0));
+
+        Cart::add_item(&pool, &buyer, deal, 1).await.unwrap();
+        Inventory::set_on_hand(&pool, deal, 1).await.unwrap();
+        with_connection(&pool, move |conn| {
+            diesel::delete(stock_reservations::table.filter(stock_reservations::deal_id.eq(deal)))
+                .execute(conn)
+        }).await.unwrap();
+        Cart::add_item(&pool, &browser, deal, 1).await.unwrap();
+        assert!(matches!(
+            Order::place(&pool, user).await,
+            Err(Error::InsufficientStock { remaining: 0, .. })
+        ));
+        assert_eq!(Cart::get_lines(&pool, &buyer).await.unwrap().len(), 1);
+    }
+}
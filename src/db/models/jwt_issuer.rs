@@ -2,32 +2,60 @@ use uuid::Uuid;
 use diesel::prelude::*;
 use serde::{ Serialize, Deserialize };
 
-use crate::db::models::schema;
+use crate::db::{ models::schema, with_connection, DbPool, Error };
 
 /// The struct to represent a jwt issuer returned from the postgresql database
 /// 
 /// This struct is a representation of the schema from the jwt_issuer table in the commerce 
-/// database. Currently this includes fields for the issuer's uuid and name. This data is primarily
-/// used to verify the issuer of a JWT is within the trusted issuers that are known to the database
-/// and for monitoring of tokens.
+/// database. Currently this includes fields for the issuer's uuid, name, the algorithm its tokens
+/// are signed with, the key used to verify them, and whether the issuer is currently trusted.
+/// This data is primarily used to verify the issuer of a JWT is within the trusted issuers that
+/// are known to the database and for monitoring of tokens.
 /// 
-/// jwt_issuer.uuid is the primary key of the table.
+/// The verification key is the base64 encoded shared secret for HMAC algorithms, and the PEM
+/// encoded public key for asymmetric ones. Tokens are only ever verified with the algorithm
+/// registered here, never the one named in the token's header. Issuers without a key are never
+/// verified with one, this api's own issuer is verified with the key ring instead, and issuers
+/// are disabled until enabled by an admin.
+/// 
+/// jwt_issuer.uuid is the primary key of the table, and is the value of the iss claim of tokens
+/// minted by that issuer.
 /// 
 /// # Examples
 /// 
 /// ```
 /// // this assumes you are using diesel
-/// use commerce::db::models::schema::deals::dsl::*;
-///
-/// let connection = &mut establish_connection();
+/// use commerce::db::models::schema::jwt_issuers::dsl::*;
 ///
-/// let response = deals
-///     .filter(uuid.eq(item_id))
-///     .first::<Deal>(connection);
+/// let response = with_connection(&pool, move |conn| {
+///     jwt_issuers
+///         .filter(uuid.eq(issuer_id))
+///         .first::<JwtIssuer>(conn)
+/// }).await;
 /// ```
 #[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
 #[diesel(primary_key(uuid), table_name = schema::jwt_issuers)]
 pub struct JwtIssuer {
     pub uuid: Uuid,
     pub name: String,
+    pub algorithm: String,
+    #[serde(skip_serializing)]
+    pub verification_key: Option<String>,
+    pub enabled: bool,
+}
+
+impl JwtIssuer {
+    pub async fn get(pool: &DbPool, issuer_id: Uuid) -> Result<JwtIssuer, Error> {
+        use schema::jwt_issuers::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                jwt_issuers
+                    .filter(uuid.eq(issuer_id))
+                    .first::<JwtIssuer>(conn)
+            })
+        }).await
+    }
 }
@@ -0,0 +1,159 @@
+use chrono::{ Duration, NaiveDateTime, Utc };
+use diesel::prelude::*;
+use serde::{ Serialize, Deserialize };
+
+use crate::db::{ models::schema, with_connection, DbPool, Error };
+
+/// The struct to represent a key this api signs its tokens with, returned from the postgresql
+/// database
+///
+/// This struct is a representation of the schema from the jwt_signing_keys table in the commerce
+/// database. Currently this includes fields for the key's id (the kid header of the tokens it
+/// signs), the algorithm, the encrypted key material, and when the key was created, retired and
+/// stops being accepted.
+///
+/// The key material is the base64 encoded secret for HMAC algorithms, and the PEM encoded PKCS#8
+/// private key for asymmetric ones. It is only ever stored sealed by `seal_key_material`, the
+/// table never holds it in plaintext. At most one key has no retired_at, that is the active key
+/// every new token is signed with. Retired keys are kept to verify the tokens they already signed
+/// until their expires_at has passed, after which they are deleted on the next rotation.
+///
+/// jwt_signing_key.kid is the primary key of the table.
+///
+/// # Examples
+///
+/// ```
+/// let keys = JwtSigningKey::get_live(&pool).await?;
+/// let active = keys.iter().find(|key| key.retired_at.is_none());
+/// ```
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(kid), table_name = schema::jwt_signing_keys)]
+pub struct JwtSigningKey {
+    pub kid: String,
+    pub algorithm: String,
+    #[serde(skip_serializing)]
+    pub encrypted_key: String,
+    pub created_at: NaiveDateTime,
+    pub retired_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl JwtSigningKey {
+    pub fn new(kid: String, algorithm: String, encrypted_key: String) -> Self {
+        Self {
+            kid,
+            algorithm,
+            encrypted_key,
+            created_at: Utc::now().naive_utc(),
+            retired_at: None,
+            expires_at: None,
+        }
+    }
+
+    /// Loads the active key and every retired key that is still within its grace period, newest
+    /// first
+    pub async fn get_live(pool: &DbPool) -> Result<Vec<JwtSigningKey>, Error> {
+        use schema::jwt_signing_keys::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                jwt_signing_keys
+                    .filter(expires_at.is_null().or(expires_at.gt(Utc::now().naive_utc())))
+                    .order(created_at.desc())
+                    .load::<JwtSigningKey>(conn)
+            })
+        }).await
+    }
+
+    /// Makes the key the active key unless `is_same` recognises one of the stored keys as it
+    ///
+    /// Used to seed the table with the key from the environment. Symmetric keys get a random kid,
+    /// so `is_same` has to compare the stored key material rather than the kid. Changing that key
+    /// registers it as a rotation, so tokens signed by the previous key stay valid for the grace
+    /// period. Returns whether the key was added.
+    pub async fn adopt(
+        pool: &DbPool,
+        key: JwtSigningKey,
+        grace: Duration,
+        is_same: impl Fn(&JwtSigningKey) -> bool + Send + 'static,
+    ) -> Result<bool, Error> {
+        use schema::jwt_signing_keys::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .serializable()
+            .run(|conn| {
+                let known = jwt_signing_keys
+                    .load::<JwtSigningKey>(conn)?
+                    .iter()
+                    .any(&is_same);
+
+                if known {
+                    return Ok::<_, Error>(false);
+                }
+
+                replace_active(conn, key, grace)?;
+                Ok(true)
+            })
+        }).await
+    }
+
+    /// Retires the active key and makes the given key the active one
+    ///
+    /// The retired key keeps verifying tokens for the grace period. `expected_active` must name
+    /// the key that is active right now, if another rotation got there first this fails with
+    /// `Error::VersionMismatch` instead of rotating twice. Keys whose grace period has ended are
+    /// deleted in the same transaction.
+    pub async fn rotate(
+        pool: &DbPool,
+        expected_active: String,
+        key: JwtSigningKey,
+        grace: Duration,
+    ) -> Result<(), Error> {
+        use schema::jwt_signing_keys::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .serializable()
+            .run(|conn| {
+                let active = jwt_signing_keys
+                    .filter(retired_at.is_null())
+                    .select(kid)
+                    .first::<String>(conn)
+                    .optional()?;
+
+                if active.as_deref() != Some(expected_active.as_str()) {
+                    return Err(Error::VersionMismatch);
+                }
+
+                replace_active(conn, key, grace)
+            })
+        }).await
+    }
+}
+
+/// Retires the current active key, if any, inserts the new key as active and drops keys whose
+/// grace period has ended
+fn replace_active(conn: &mut PgConnection, key: JwtSigningKey, grace: Duration) -> Result<(), Error> {
+    use schema::jwt_signing_keys::dsl::*;
+
+    let now = Utc::now().naive_utc();
+
+    diesel::delete(jwt_signing_keys.filter(expires_at.lt(now)))
+        .execute(conn)?;
+
+    diesel::update(jwt_signing_keys.filter(retired_at.is_null()))
+        .set((
+            retired_at.eq(now),
+            expires_at.eq(now + grace),
+        ))
+        .execute(conn)?;
+
+    diesel::insert_into(jwt_signing_keys)
+        .values(key)
+        .execute(conn)?;
+
+    Ok(())
+}
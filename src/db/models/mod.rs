@@ -1,17 +1,34 @@
+pub mod cart;
+pub mod category;
 pub mod user;
 pub mod deal;
+pub mod inventory;
+pub mod money;
 pub mod nonce;
+pub mod order;
+pub mod permission;
+pub mod refresh_token;
+pub mod revoked_token;
 pub mod role;
 pub mod jwt_issuer;
+pub mod jwt_signing_key;
 pub mod schema;
 pub mod usersession;
 
 pub use self::{
+    cart::*,
+    category::*,
     user::*,
     deal::*,
+    inventory::*,
+    money::*,
     nonce::*,
+    order::*,
+    permission::*,
+    refresh_token::*,
+    revoked_token::*,
     role::*,
     jwt_issuer::*,
-    schema::*,
+    jwt_signing_key::*,
     usersession::*,
 };
@@ -0,0 +1,214 @@
+use diesel::{
+    deserialize::{ self, FromSql, FromSqlRow },
+    expression::AsExpression,
+    pg::{ Pg, PgValue },
+    serialize::{ self, Output, ToSql },
+    sql_types::Text,
+};
+use serde::{ de, Deserialize, Deserializer, Serialize, Serializer };
+use std::{ fmt, str::FromStr };
+
+use crate::db::Error;
+
+/// An ISO-4217 currency code, e.g. `USD`
+///
+/// Stored as the three letter code in the `*_currency` columns next to every amount, and
+/// serialized as the same string. Only the shape of the code is checked, three uppercase ASCII
+/// letters, the api doesn't keep a list of currencies.
+#[derive(AsExpression, FromSqlRow, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[diesel(sql_type = Text)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // only ever built from three ASCII letters
+        std::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b, c] if s.bytes().all(|byte| byte.is_ascii_uppercase()) => Ok(Currency([a, b, c])),
+            _ => Err(format!("Invalid currency code: {}", s)),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for Currency {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for Currency {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// An amount of money in the minor units of its currency, e.g. cents for `USD`
+///
+/// Every price and total of the api is a `Money`, serialized as
+/// `{ "amount": 1999, "currency": "USD" }`. Amounts of different currencies never mix, the
+/// arithmetic is checked and fails with `MoneyError` instead of adding them up or overflowing.
+///
+/// # Examples
+///
+/// ```
+/// let price = Money::new(1999, "USD".parse()?);
+/// let subtotal = price.checked_mul(3)?;
+/// let total = Money::sum(vec![subtotal, shipping])?;
+/// ```
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Money {
+    pub amount: i64,
+    pub currency: Currency,
+}
+
+/// Why arithmetic on `Money` failed
+#[derive(Debug, PartialEq, Eq)]
+pub enum MoneyError {
+    CurrencyMismatch(Currency, Currency),
+    Overflow,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::CurrencyMismatch(a, b) => write!(f, "Cannot combine amounts in {} and {}", a, b),
+            MoneyError::Overflow => write!(f, "Amount out of range"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+impl From<MoneyError> for Error {
+    fn from(error: MoneyError) -> Self {
+        match error {
+            MoneyError::CurrencyMismatch(..) => Error::CurrencyMismatch,
+            MoneyError::Overflow => Error::InvalidData(error.to_string()),
+        }
+    }
+}
+
+impl Money {
+    pub fn new(amount: i64, currency: Currency) -> Self {
+        Self {
+            amount,
+            currency,
+        }
+    }
+
+    pub fn checked_add(self, other: Money) -> Result<Money, MoneyError> {
+        if self.currency != other.currency {
+            return Err(MoneyError::CurrencyMismatch(self.currency, other.currency));
+        }
+
+        self.amount.checked_add(other.amount)
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// The amount times the quantity, e.g. the subtotal of a line
+    pub fn checked_mul(self, quantity: i32) -> Result<Money, MoneyError> {
+        self.amount.checked_mul(quantity as i64)
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// Adds up the amounts, all of which must share a currency. `None` if there are none, as an
+    /// empty sum has no currency.
+    pub fn sum<I: IntoIterator<Item = Money>>(amounts: I) -> Result<Option<Money>, MoneyError> {
+        amounts.into_iter().try_fold(None, |total: Option<Money>, amount| match total {
+            Some(total) => total.checked_add(amount).map(Some),
+            None => Ok(Some(amount)),
+        })
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.amount, self.currency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(amount: i64) -> Money {
+        Money::new(amount, "USD".parse().unwrap())
+    }
+
+    fn eur(amount: i64) -> Money {
+        Money::new(amount, "EUR".parse().unwrap())
+    }
+
+    #[test]
+    fn parses_only_three_letter_uppercase_codes() {
+        assert_eq!("USD".parse::<Currency>().unwrap().as_str(), "USD");
+        assert!("usd".parse::<Currency>().is_err());
+        assert!("US".parse::<Currency>().is_err());
+        assert!("USDT".parse::<Currency>().is_err());
+        assert!("U$D".parse::<Currency>().is_err());
+        assert!("ÜSD".parse::<Currency>().is_err());
+    }
+
+    #[test]
+    fn adds_and_multiplies_within_a_currency() {
+        assert_eq!(usd(1999).checked_add(usd(1)), Ok(usd(2000)));
+        assert_eq!(usd(250).checked_mul(4), Ok(usd(1000)));
+        assert_eq!(Money::sum(vec![usd(1), usd(2), usd(3)]), Ok(Some(usd(6))));
+        assert_eq!(Money::sum(Vec::new()), Ok(None));
+    }
+
+    #[test]
+    fn refuses_to_mix_currencies() {
+        let usd_code = "USD".parse().unwrap();
+        let eur_code = "EUR".parse().unwrap();
+
+        assert_eq!(usd(1).checked_add(eur(1)), Err(MoneyError::CurrencyMismatch(usd_code, eur_code)));
+        assert!(Money::sum(vec![usd(1), usd(2), eur(3)]).is_err());
+    }
+
+    #[test]
+    fn refuses_to_overflow() {
+        assert_eq!(usd(i64::MAX).checked_add(usd(1)), Err(MoneyError::Overflow));
+        assert_eq!(usd(i64::MAX / 2 + 1).checked_mul(2), Err(MoneyError::Overflow));
+    }
+
+    #[test]
+    fn serializes_as_amount_and_code() {
+        let json = async_session::serde_json::to_string(&usd(1999)).unwrap();
+        assert_eq!(json, r#"{"amount":1999,"currency":"USD"}"#);
+
+        let parsed = async_session::serde_json::from_str::<Money>(&json).unwrap();
+        assert_eq!(parsed, usd(1999));
+        assert!(async_session::serde_json::from_str::<Money>(r#"{"amount":1,"currency":"usd"}"#).is_err());
+    }
+}
@@ -1,20 +1,50 @@
 use base64::{Engine as _, engine::general_purpose};
-use diesel::{ prelude::*, RunQueryDsl, QueryDsl, };
+use diesel::{ dsl::sql, prelude::*, sql_types::Bool, RunQueryDsl, QueryDsl, };
 use dotenvy::dotenv;
 use rand::Rng;
-use ring::{ digest, hmac, rand::SystemRandom };
+use ring::hmac;
 use serde::{ Serialize, Deserialize };
 use std::{ env, time::{ SystemTime, UNIX_EPOCH }};
 
 use super::schema;
-use crate::establish_connection;
+use crate::db::{ with_connection, DbPool, Error };
 
-#[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
+/// How long a signin challenge can be answered for, in seconds
+const NONCE_TTL_SECS: u64 = 60 * 5;
+
+/// A single use signin challenge bound to a session
+///
+/// `GET /auth/nonce` stores a fresh nonce for the caller's session and hands out its tag, which the
+/// client must send back as the `nonce` field of the `POST /auth/signin` body from the same
+/// session within 5 minutes. The tag is the lower case hex HMAC-SHA384, keyed with NONCE_SECRET, of
+/// the message
+///
+///     signin:v1:<session id>:<created_at>:<nonce>
+///
+/// where created_at is the unix time in seconds the nonce was created at and nonce is 32 random
+/// bytes, base64url encoded without padding. Binding the session id means a tag is useless from
+/// any other session, and keying with the server secret means the tag can't be forged from the
+/// stored row alone. Each session holds at most one nonce, requesting a new one replaces it, and
+/// signing in consumes it whether or not the tag matched, so every tag can be tried only once.
+///
+/// nonce.session_id is the primary key of the table.
+///
+/// # Examples
+///
+/// ```
+/// let nonce = Nonce::new(sid);
+/// nonce.insert(&pool).await?;
+/// let tag = nonce.tag();
+///
+/// // later, on signin from the same session
+/// let nonce = Nonce::take(&pool, sid).await?;
+/// if !nonce.verify(sid, &tag) { ... }
+/// ```
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
 #[diesel(primary_key(session_id), table_name = schema::nonces)]
 pub struct Nonce {
     pub nonce: String,
     pub session_id: String,
-    pub key: String,
     pub created_at: i64,
 }
 
@@ -23,121 +53,207 @@ impl Nonce {
         let nonce_rand_bytes: Vec<u8> = (0..32).map(|_| rand::thread_rng().gen()).collect();
         let nonce = general_purpose::URL_SAFE_NO_PAD.encode(nonce_rand_bytes);
 
-        let rng = SystemRandom::new();
-        let key_value: [u8; digest::SHA384_OUTPUT_LEN] = ring::rand::generate(&rng).expect("Failed to sign nonce").expose();
-        let key = general_purpose::URL_SAFE_NO_PAD.encode(key_value);
-        
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
 
         Self {
             nonce,
-            key,
             created_at: timestamp as i64,
             session_id: session_id.to_string(),
         }
     }
 
-    pub fn take(sid: &str) -> Option<Nonce> {
+    /// Removes and returns the session's nonce
+    ///
+    /// The nonce is consumed by a single `DELETE ... RETURNING`, so when two signins race on the
+    /// same session only one of them receives the nonce, the other gets `Error::NotFound`. Nonces
+    /// past the 5 minute window, by the database's clock, are never returned, they are left for
+    /// the cleanup job.
+    pub async fn take(pool: &DbPool, sid: &str) -> Result<Nonce, Error> {
         use super::schema::nonces::dsl::*;
 
-        let connection = &mut crate::establish_connection();
-        let result = connection.build_transaction()
-            .read_only()
-            .run(|conn| {
+        let sid = sid.to_string();
+        with_connection(pool, move |conn| {
+            diesel::delete(
                 nonces
                     .filter(session_id.eq(&sid))
-                    .first::<Self>(conn)
-            });
-
-        if let Some(_) = result.as_ref().ok() {
-            connection.build_transaction()
-                .read_write()
-                .run(|conn| {
-                    diesel::delete(
-                        nonces
-                            .filter(session_id.eq(&sid))
-                    ).execute(conn)
-                })
-                .unwrap();
-        };
+                    .filter(unexpired())
+            )
+            .get_result::<Self>(conn)
+        }).await
+    }
+
+    /// Deletes every expired nonce, returning how many were deleted. Run by the cleanup jobs.
+    pub fn purge_expired(conn: &mut PgConnection) -> QueryResult<usize> {
+        use super::schema::nonces::dsl::*;
 
-        result.ok()
+        diesel::delete(nonces.filter(diesel::dsl::not(unexpired())))
+            .execute(conn)
     }
 
-    pub fn insert(&self) -> Option<()> {
+    pub async fn insert(&self, pool: &DbPool) -> Result<(), Error> {
         use schema::nonces::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
+        let new_nonce = self.clone();
+        let response = with_connection(pool, move |conn| {
+            conn.build_transaction()
             .read_write()
             .run(|conn| {
                 diesel::insert_into(nonces)
                     .values(
-                        self,
+                        &new_nonce,
                     )
                     .on_conflict(session_id)
                     .do_update()
                     .set((
-                        nonce.eq(&self.nonce),
-                        key.eq(&self.key),
+                        nonce.eq(&new_nonce.nonce),
+                        created_at.eq(&new_nonce.created_at),
                     ))
                     .execute(conn)
-            });
+            })
+        }).await;
 
-        match response.ok() {
-            Some(_) => Some(()),
-            None => None,
-        }
+        response.map(|_| ())
+    }
+
+    /// The tag handed to the client, to be sent back on signin
+    pub fn tag(&self) -> String {
+        hex::encode(self.sign(&Self::get_secret()).as_ref())
     }
 
-    pub fn validate(&self, tag: &str) -> bool {
+    /// Checks the tag the client sent back on signin
+    ///
+    /// Fails if the nonce has expired, was issued to another session, or the tag doesn't match.
+    /// The tag is compared in constant time.
+    pub fn verify(&self, sid: &str, tag: &str) -> bool {
+        self.verify_with(&Self::get_secret(), sid, tag)
+    }
 
-        if self.is_expired() {
+    fn verify_with(&self, secret: &[u8], sid: &str, tag: &str) -> bool {
+        if self.is_expired() || self.session_id != sid {
             return false;
         }
 
-        let secret = Self::get_secret();
-        let msg = format!("{}:{}:{}", self.created_at, self.nonce, secret);
-
-        let key_value = match general_purpose::URL_SAFE_NO_PAD.decode(&self.key) {
-            Ok(data) => data,
-            Err(_) => return false,
+        let Ok(tag) = hex::decode(tag) else {
+            return false;
         };
 
-        let key = hmac::Key::new(hmac::HMAC_SHA384, key_value.as_ref());
+        let key = hmac::Key::new(hmac::HMAC_SHA384, secret);
+        hmac::verify(&key, self.message().as_bytes(), &tag).is_ok()
+    }
+
+    fn sign(&self, secret: &[u8]) -> hmac::Tag {
+        let key = hmac::Key::new(hmac::HMAC_SHA384, secret);
+        hmac::sign(&key, self.message().as_bytes())
+    }
 
-        hmac::verify(&key, &msg.as_ref(), tag.as_ref()).ok().is_some()
+    fn message(&self) -> String {
+        format!("signin:v1:{}:{}:{}", self.session_id, self.created_at, self.nonce)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        timestamp.saturating_sub(self.created_at as u64) > NONCE_TTL_SECS
     }
 
-    pub fn get_hmac(&self) -> String {
+    fn get_secret() -> Vec<u8> {
         dotenv().ok();
-        let secret = Self::get_secret();
+        env::var("NONCE_SECRET")
+            .expect("NONCE_SECRET must be set")
+            .into_bytes()
+    }
+}
 
-        let message = format!("{}:{}:{}", self.created_at, self.nonce, secret);
-        
-        let key_value = general_purpose::URL_SAFE_NO_PAD.decode(self.key.clone()).unwrap();
-        let key = hmac::Key::new(hmac::HMAC_SHA384, key_value.as_ref());
+/// Whether a nonce is still within its window, judged by the database's clock
+fn unexpired() -> diesel::expression::SqlLiteral<Bool> {
+    sql::<Bool>(&format!("created_at >= extract(epoch from now())::bigint - {}", NONCE_TTL_SECS))
+}
 
-        let tag = hmac::sign(&key, message.as_bytes());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let key_string = general_purpose::URL_SAFE_NO_PAD.encode(key_value);
-        println!("{}", &key_string);
+    const SECRET: &[u8] = b"test-nonce-secret";
 
-        hex::encode(tag.as_ref())
+    fn tag_for(nonce: &Nonce) -> String {
+        hex::encode(nonce.sign(SECRET).as_ref())
     }
 
-    pub fn is_expired(&self) -> bool {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        timestamp - (self.created_at as u64) > (60 * 5)
+    #[test]
+    fn accepts_the_issued_tag_from_the_same_session() {
+        let nonce = Nonce::new("session-a");
+
+        assert!(nonce.verify_with(SECRET, "session-a", &tag_for(&nonce)));
     }
 
-    fn get_secret() -> String {
-        dotenv().ok();
-        let secret = env::var("NONCE_SECRET")
-            .expect("NONCE_SECRET must be set")
-            .as_bytes()
-            .to_vec();
+    #[test]
+    fn rejects_the_tag_from_another_session() {
+        let nonce = Nonce::new("session-a");
+
+        assert!(!nonce.verify_with(SECRET, "session-b", &tag_for(&nonce)));
+    }
+
+    #[test]
+    fn rejects_a_tag_minted_for_another_session() {
+        let nonce = Nonce::new("session-a");
+        let mut stolen = nonce.clone();
+        stolen.session_id = "session-b".to_string();
+
+        assert!(!nonce.verify_with(SECRET, "session-a", &tag_for(&stolen)));
+    }
+
+    #[test]
+    fn rejects_an_expired_nonce() {
+        let mut nonce = Nonce::new("session-a");
+        nonce.created_at -= NONCE_TTL_SECS as i64 + 1;
+
+        assert!(nonce.is_expired());
+        assert!(!nonce.verify_with(SECRET, "session-a", &tag_for(&nonce)));
+    }
+
+    #[test]
+    fn rejects_tampered_and_malformed_tags() {
+        let nonce = Nonce::new("session-a");
+        let mut tampered = tag_for(&nonce);
+        tampered.replace_range(0..2, if tampered.starts_with("00") { "01" } else { "00" });
+
+        assert!(!nonce.verify_with(SECRET, "session-a", &tampered));
+        assert!(!nonce.verify_with(SECRET, "session-a", "not hex"));
+        assert!(!nonce.verify_with(SECRET, "session-a", ""));
+        assert!(!nonce.verify_with(b"another-secret", "session-a", &tag_for(&nonce)));
+    }
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn a_nonce_can_only_be_taken_once() {
+        let pool = crate::db::create_pool();
+        let sid = format!("nonce-test-{}", uuid::Uuid::new_v4());
+
+        let nonce = Nonce::new(&sid);
+        nonce.insert(&pool).await.unwrap();
+
+        let (first, second) = tokio::join!(Nonce::take(&pool, &sid), Nonce::take(&pool, &sid));
+        let taken = [&first, &second].iter().filter(|result| result.is_ok()).count();
+        assert_eq!(taken, 1);
+
+        assert!(matches!(Nonce::take(&pool, &sid).await, Err(Error::NotFound)));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn an_expired_nonce_is_never_taken_and_gets_purged() {
+        let pool = crate::db::create_pool();
+        let sid = format!("nonce-test-{}", uuid::Uuid::new_v4());
+
+        let mut nonce = Nonce::new(&sid);
+        nonce.created_at -= NONCE_TTL_SECS as i64 + 1;
+        nonce.insert(&pool).await.unwrap();
+
+        assert!(matches!(Nonce::take(&pool, &sid).await, Err(Error::NotFound)));
+        assert!(with_connection(&pool, Nonce::purge_expired).await.unwrap() >= 1);
 
-        hex::encode(secret)
+        Nonce::new(&sid).insert(&pool).await.unwrap();
+        assert!(Nonce::take(&pool, &sid).await.is_ok());
     }
 }
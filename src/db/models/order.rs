@@ -0,0 +1,273 @@
+use chrono::NaiveDateTime;
+use diesel::{ deserialize, pg::Pg, prelude::* };
+use serde::{ Serialize, Deserialize };
+use uuid::Uuid;
+
+use super::schema;
+use crate::{
+    db::{ keyset, with_connection, Cart, CartOwner, Currency, DbPool, Error, Inventory, Money },
+    net::{ Page, Pagination },
+};
+
+/// The struct to represent an order returned from the postgresql database
+///
+/// This struct is a representation of the schema from the orders table in the commerce database.
+/// Currently this includes fields for the order's uuid, the user who placed it, its total, and when
+/// it was placed. Orders are placed from the user's cart by `Order::place`, and are
+/// never changed afterwards.
+///
+/// order.uuid is the primary key of the table.
+///
+/// # Examples
+///
+/// ```
+/// match Order::place(&pool, user_id).await? {
+///     Some((order, lines)) => ...,
+///     None => // the cart was empty
+/// }
+/// ```
+#[derive(Identifiable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(uuid), table_name = schema::orders)]
+pub struct Order {
+    pub uuid: Uuid,
+    pub user_id: Uuid,
+    pub total: Money,
+    pub created_at: NaiveDateTime,
+}
+
+impl Queryable<schema::orders::SqlType, Pg> for Order {
+    type Row = (Uuid, Uuid, i64, NaiveDateTime, Currency);
+
+    fn build((uuid, user_id, total_amount, created_at, total_currency): Self::Row) -> deserialize::Result<Self> {
+        Ok(Order {
+            uuid,
+            user_id,
+            total: Money::new(total_amount, total_currency),
+            created_at,
+        })
+    }
+}
+
+/// The struct to represent a line of an order returned from the postgresql database
+///
+/// This struct is a representation of the schema from the order_lines table in the commerce
+/// database. The name, price and version of the deal are copied from the deal as it was bought, so
+/// the line reads the same after the deal is edited. deal_id is set to null if the deal is deleted.
+///
+/// (order_line.order_id, order_line.line) is the primary key of the table.
+#[derive(Identifiable, Associations, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(order_id, line), belongs_to(Order, foreign_key = order_id), table_name = schema::order_lines)]
+pub struct OrderLine {
+    pub order_id: Uuid,
+    pub line: i32,
+    pub deal_id: Option<Uuid>,
+    pub deal_version: i32,
+    pub name: String,
+    pub price: Money,
+    pub quantity: i32,
+}
+
+impl Queryable<schema::order_lines::SqlType, Pg> for OrderLine {
+    type Row = (Uuid, i32, Option<Uuid>, i32, String, i64, i32, Currency);
+
+    fn build((order_id, line, deal_id, deal_version, name, price_amount, quantity, price_currency): Self::Row) -> deserialize::Result<Self> {
+        Ok(OrderLine {
+            order_id,
+            line,
+            deal_id,
+            deal_version,
+            name,
+            price: Money::new(price_amount, price_currency),
+            quantity,
+        })
+    }
+}
+
+impl Order {
+    /// Places an order for everything in the user's cart and empties the cart, in one transaction.
+    /// The units bought are taken off the shelf, failing with `Error::InsufficientStock` and
+    /// leaving the cart as it was if any deal is short. Fails with `Error::CurrencyMismatch` if the
+    /// cart holds deals priced in different currencies. Returns `None` if the cart is empty.
+    pub async fn place(pool: &DbPool, user: Uuid) -> Result<Option<(Order, Vec<OrderLine>)>, Error> {
+        use schema::{ order_lines, orders };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let cart_lines = Cart::take(conn, &CartOwner::User(user))?;
+                if cart_lines.is_empty() {
+                    return Ok(None);
+                }
+
+                let units = cart_lines.iter()
+                    .map(|(item, _)| (item.deal_id, item.quantity))
+                    .collect::<Vec<(Uuid, i32)>>();
+                Inventory::commit(conn, cart_lines[0].0.cart_id, &units)?;
+
+                let subtotals = cart_lines.iter()
+                    .map(|(item, deal)| deal.price.checked_mul(item.quantity))
+                    .collect::<Result<Vec<Money>, _>>()?;
+                let Some(total) = Money::sum(subtotals)? else {
+                    return Ok(None);
+                };
+
+                let order = diesel::insert_into(orders::table)
+                    .values((
+                        orders::user_id.eq(user),
+                        orders::total_amount.eq(total.amount),
+                        orders::total_currency.eq(total.currency),
+                    ))
+                    .get_result::<Order>(conn)?;
+
+                let lines = cart_lines.into_iter()
+                    .zip(1..)
+                    .map(|((item, deal), line)| OrderLine {
+                        order_id: order.uuid,
+                        line,
+                        deal_id: Some(item.deal_id),
+                        deal_version: deal.version,
+                        name: deal.name,
+                        price: deal.price,
+                        quantity: item.quantity,
+                    })
+                    .collect::<Vec<OrderLine>>();
+
+                diesel::insert_into(order_lines::table)
+                    .values(lines.iter()
+                        .map(|line| (
+                            order_lines::order_id.eq(line.order_id),
+                            order_lines::line.eq(line.line),
+                            order_lines::deal_id.eq(line.deal_id),
+                            order_lines::deal_version.eq(line.deal_version),
+                            order_lines::name.eq(&line.name),
+                            order_lines::price_amount.eq(line.price.amount),
+                            order_lines::price_currency.eq(line.price.currency),
+                            order_lines::quantity.eq(line.quantity),
+                        ))
+                        .collect::<Vec<_>>()
+                    )
+                    .execute(conn)?;
+
+                Ok::<_, Error>(Some((order, lines)))
+            })
+        }).await
+    }
+
+    /// Loads the order along with its lines
+    pub async fn get(pool: &DbPool, id: Uuid) -> Result<(Order, Vec<OrderLine>), Error> {
+        use schema::orders;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                let order = orders::table
+                    .find(id)
+                    .first::<Order>(conn)?;
+
+                let lines = OrderLine::belonging_to(&order)
+                    .order(schema::order_lines::line.asc())
+                    .load::<OrderLine>(conn)?;
+
+                Ok::<_, Error>((order, lines))
+            })
+        }).await
+    }
+
+    /// Loads a page of the orders placed by the user, or by every user if `user` is `None`, along
+    /// with their lines. The most recent orders come first.
+    pub async fn get_all(
+        pool: &DbPool,
+        user: Option<Uuid>,
+        pagination: Pagination
+    ) -> Result<Page<(Order, Vec<OrderLine>)>, Error> {
+        use schema::{ order_lines, orders };
+
+        let cursor = pagination.cursor::<NaiveDateTime, Uuid>()?;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                let placed_by = || {
+                    let mut query = orders::table.into_boxed();
+                    if let Some(user) = user {
+                        query = query.filter(orders::user_id.eq(user));
+                    }
+                    query
+                };
+
+                let backward = cursor.as_ref().is_some_and(|cursor| cursor.backward);
+                let after = cursor.as_ref().map(|cursor| (cursor.key, cursor.id));
+
+                let rows = keyset!(placed_by(), orders::created_at, orders::uuid, backward, after)
+                    .limit(pagination.get_limit() + 1)
+                    .load::<Order>(conn)?;
+
+                let lines = OrderLine::belonging_to(&rows)
+                    .order(order_lines::line.asc())
+                    .load::<OrderLine>(conn)?
+                    .grouped_by(&rows);
+
+                let total = match pagination.wants_total() {
+                    true => Some(placed_by().count().get_result::<i64>(conn)?),
+                    false => None,
+                };
+
+                let rows = rows.into_iter().zip(lines).collect::<Vec<(Order, Vec<OrderLine>)>>();
+                Ok::<_, Error>(pagination.page(&cursor, rows, |(order, _)| (order.created_at, order.uuid), total))
+            })
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ create_pool, insert_test_user, CreateDeal, Deal, DealChanges };
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn orders_keep_the_deals_as_they_were_bought() {
+        let pool = create_pool();
+        let usd = "USD".parse().unwrap();
+        let deal = Deal::insert(&pool, CreateDeal {
+            name: "Order test deal".to_string(),
+            image: "https://example.com/deal.png".to_string(),
+            price: Money::new(1250, usd),
+            description: "A deal bought by the order tests".to_string(),
+        }).await.unwrap();
+        let deal_id = deal.uuid.unwrap();
+        Inventory::set_on_hand(&pool, deal_id, 10).await.unwrap();
+
+        let user = insert_test_user(&pool, "order").await;
+
+        assert!(Order::place(&pool, user).await.unwrap().is_none());
+
+        Cart::add_item(&pool, &CartOwner::User(user), deal_id, 3).await.unwrap();
+        let (order, lines) = Order::place(&pool, user).await.unwrap().unwrap();
+        assert_eq!(order.total, Money::new(3750, usd));
+        assert_eq!(lines.len(), 1);
+        assert!(Cart::get_lines(&pool, &CartOwner::User(user)).await.unwrap().is_empty());
+        assert!(Order::place(&pool, user).await.unwrap().is_none());
+
+        Deal::update(&pool, deal_id, None, DealChanges {
+            name: Some("Renamed deal".to_string()),
+            price: Some(Money::new(99, "EUR".parse().unwrap())),
+            ..DealChanges::default()
+        }).await.unwrap();
+        Deal::delete(&pool, deal_id, None).await.unwrap();
+
+        let (_, lines) = Order::get(&pool, order.uuid).await.unwrap();
+        assert_eq!(lines[0].name, "Order test deal");
+        assert_eq!((lines[0].price, lines[0].quantity, lines[0].deal_id), (Money::new(1250, usd), 3, None));
+
+        let listed = Order::get_all(&pool, Some(user), Pagination::default()).await.unwrap().items;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0.uuid, order.uuid);
+        assert_eq!(listed[0].1.len(), 1);
+    }
+}
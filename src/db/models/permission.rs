@@ -0,0 +1,125 @@
+use diesel::{
+    deserialize::{ self, FromSql, FromSqlRow },
+    expression::AsExpression,
+    pg::{ Pg, PgValue },
+    prelude::*,
+    serialize::{ self, Output, ToSql },
+    sql_types::Text,
+};
+use serde::{ Serialize, Deserialize };
+use std::{ fmt, str::FromStr };
+use uuid::Uuid;
+
+use super::schema;
+use crate::db::{ with_connection, DbPool, Error };
+
+/// The actions that can be granted to a role
+/// 
+/// Each variant is stored in the permissions table under a dotted name of the form
+/// `<resource>.<action>`, which is also how it is serialized in API responses and accepted in
+/// paths. Roles gain permissions through rows of the role_permissions join table, so what a role
+/// may do can be changed at runtime without touching the code.
+/// 
+/// # Examples
+/// 
+/// ```
+/// if has_permission(&pool, user_id, Permission::ManageDeals).await? {
+///     ...
+/// }
+/// ```
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[diesel(sql_type = Text)]
+pub enum Permission {
+    #[serde(rename = "deals.write")]
+    ManageDeals,
+    #[serde(rename = "users.read")]
+    ReadUsers,
+    #[serde(rename = "roles.write")]
+    ManageRoles,
+    #[serde(rename = "keys.write")]
+    ManageKeys,
+    #[serde(rename = "orders.read")]
+    ReadOrders,
+    #[serde(rename = "categories.write")]
+    ManageCategories,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ManageDeals => "deals.write",
+            Permission::ReadUsers => "users.read",
+            Permission::ManageRoles => "roles.write",
+            Permission::ManageKeys => "keys.write",
+            Permission::ReadOrders => "orders.read",
+            Permission::ManageCategories => "categories.write",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deals.write" => Ok(Permission::ManageDeals),
+            "users.read" => Ok(Permission::ReadUsers),
+            "roles.write" => Ok(Permission::ManageRoles),
+            "keys.write" => Ok(Permission::ManageKeys),
+            "orders.read" => Ok(Permission::ReadOrders),
+            "categories.write" => Ok(Permission::ManageCategories),
+            _ => Err(format!("Unknown permission: {}", s)),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for Permission {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for Permission {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+/// The struct to represent a permission granted to a role, returned from the postgresql database
+/// 
+/// This struct is a representation of the schema from the role_permissions table in the commerce
+/// database, the many to many join between roles and permissions.
+/// 
+/// (role_permission.role_id, role_permission.permission) is the primary key of the table.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(role_id, permission), table_name = schema::role_permissions)]
+pub struct RolePermission {
+    pub role_id: Uuid,
+    pub permission: Permission,
+}
+
+/// Checks whether the role assigned to the user has been granted the permission
+pub async fn has_permission(pool: &DbPool, user_id: Uuid, required: Permission) -> Result<bool, Error> {
+    use schema::{ role_permissions, users };
+
+    with_connection(pool, move |conn| {
+        conn.build_transaction()
+        .read_only()
+        .run(|conn| {
+            diesel::select(diesel::dsl::exists(
+                role_permissions::table
+                    .inner_join(users::table.on(users::role.eq(role_permissions::role_id)))
+                    .filter(users::uuid.eq(user_id))
+                    .filter(role_permissions::permission.eq(required))
+            ))
+            .get_result::<bool>(conn)
+        })
+    }).await
+}
@@ -0,0 +1,203 @@
+use base64::{ Engine as _, engine::general_purpose };
+use chrono::{ Duration, NaiveDateTime, Utc };
+use diesel::prelude::*;
+use ring::{ digest, rand::{ self, SystemRandom } };
+use serde::{ Serialize, Deserialize };
+use uuid::Uuid;
+
+use crate::db::{ models::schema, with_connection, DbPool, Error };
+
+/// The struct to represent a refresh token returned from the postgresql database
+///
+/// This struct is a representation of the schema from the refresh_tokens table in the commerce
+/// database. Currently this includes fields for the token's uuid, the family it belongs to, the
+/// user it was issued to, the sha-256 hash of the token, and when it was created, expires, was
+/// used and was revoked. The token itself is only ever handed to the client, so a leaked table
+/// can't be used to mint access tokens.
+///
+/// Every signin starts a new family, one per device. A refresh token can only be used once, using
+/// it marks it as used and issues the next token of the family. Presenting a token that was already
+/// used means it was copied, so the whole family is revoked and the device has to sign in again.
+///
+/// refresh_token.uuid is the primary key of the table.
+///
+/// # Examples
+///
+/// ```
+/// let issued = RefreshToken::issue(&pool, user_id, Duration::days(30)).await?;
+///
+/// match RefreshToken::refresh(&pool, &issued.token, Duration::days(30)).await? {
+///     RefreshOutcome::Rotated { token, user_id } => ...,
+///     RefreshOutcome::Reused { family_id, user_id } => ...,
+/// }
+/// ```
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(uuid), table_name = schema::refresh_tokens)]
+pub struct RefreshToken {
+    pub uuid: Uuid,
+    pub family_id: Uuid,
+    pub user_id: Uuid,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+/// A refresh token as handed to the client, along with the family it belongs to. Access tokens
+/// minted with it carry the family, so signing out with them can revoke it.
+pub struct IssuedToken {
+    pub token: String,
+    pub family_id: Uuid,
+}
+
+/// The result of presenting a refresh token that exists and has not expired or been revoked
+pub enum RefreshOutcome {
+    /// The token was unused, it has been replaced by the returned token of the same family
+    Rotated { token: IssuedToken, user_id: Uuid },
+    /// The token had already been used, its whole family has been revoked
+    Reused { family_id: Uuid, user_id: Uuid },
+}
+
+impl RefreshToken {
+    /// Creates the row for a new token, returning it along with the token to hand to the client
+    fn new(family_id: Uuid, user_id: Uuid, ttl: Duration) -> (String, Self) {
+        let rng = SystemRandom::new();
+        let token_bytes: [u8; 32] = rand::generate(&rng).expect("Failed to generate refresh token").expose();
+        let token = general_purpose::URL_SAFE_NO_PAD.encode(token_bytes);
+        let now = Utc::now().naive_utc();
+
+        let row = Self {
+            uuid: Uuid::new_v4(),
+            family_id,
+            user_id,
+            token_hash: hash_token(&token),
+            created_at: now,
+            expires_at: now + ttl,
+            used_at: None,
+            revoked_at: None,
+        };
+
+        (token, row)
+    }
+
+    /// Issues the first token of a new family, returning the token to hand to the client
+    pub async fn issue(pool: &DbPool, user_id: Uuid, ttl: Duration) -> Result<IssuedToken, Error> {
+        use schema::refresh_tokens;
+
+        let family_id = Uuid::new_v4();
+        let (token, row) = Self::new(family_id, user_id, ttl);
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::insert_into(refresh_tokens::table)
+                    .values(&row)
+                    .execute(conn)
+            })
+        }).await?;
+
+        Ok(IssuedToken { token, family_id })
+    }
+
+    /// Exchanges a refresh token for the next token of its family
+    ///
+    /// The presented token is locked for the duration of the exchange, so two concurrent uses of
+    /// the same token can't both be rotated, the later one is treated as a reuse. Unknown, expired
+    /// and revoked tokens are reported as `Error::NotFound`.
+    pub async fn refresh(pool: &DbPool, presented: &str, ttl: Duration) -> Result<RefreshOutcome, Error> {
+        use schema::refresh_tokens::dsl::*;
+
+        let presented_hash = hash_token(presented);
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let now = Utc::now().naive_utc();
+                let current = refresh_tokens
+                    .filter(token_hash.eq(&presented_hash))
+                    .for_update()
+                    .first::<RefreshToken>(conn)?;
+
+                if current.revoked_at.is_some() || current.expires_at <= now {
+                    return Err(Error::NotFound);
+                }
+
+                if current.used_at.is_some() {
+                    diesel::update(
+                        refresh_tokens
+                            .filter(family_id.eq(current.family_id))
+                            .filter(revoked_at.is_null())
+                    )
+                    .set(revoked_at.eq(now))
+                    .execute(conn)?;
+
+                    return Ok(RefreshOutcome::Reused {
+                        family_id: current.family_id,
+                        user_id: current.user_id,
+                    });
+                }
+
+                diesel::update(refresh_tokens.find(current.uuid))
+                    .set(used_at.eq(now))
+                    .execute(conn)?;
+
+                let (token, row) = Self::new(current.family_id, current.user_id, ttl);
+                diesel::insert_into(refresh_tokens)
+                    .values(&row)
+                    .execute(conn)?;
+
+                Ok(RefreshOutcome::Rotated {
+                    token: IssuedToken { token, family_id: current.family_id },
+                    user_id: current.user_id,
+                })
+            })
+        }).await
+    }
+
+    /// Revokes every refresh token of the family, signing its device out
+    pub async fn revoke_family(pool: &DbPool, family: Uuid) -> Result<usize, Error> {
+        use schema::refresh_tokens::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::update(
+                    refresh_tokens
+                        .filter(family_id.eq(family))
+                        .filter(revoked_at.is_null())
+                )
+                .set(revoked_at.eq(Utc::now().naive_utc()))
+                .execute(conn)
+            })
+        }).await
+    }
+
+    /// Revokes every refresh token of the user, on all of their devices
+    pub async fn revoke_for_user(pool: &DbPool, user: Uuid) -> Result<usize, Error> {
+        use schema::refresh_tokens::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::update(
+                    refresh_tokens
+                        .filter(user_id.eq(user))
+                        .filter(revoked_at.is_null())
+                )
+                .set(revoked_at.eq(Utc::now().naive_utc()))
+                .execute(conn)
+            })
+        }).await
+    }
+}
+
+/// The hex encoded sha-256 of a refresh token, the only form in which tokens are stored
+fn hash_token(token: &str) -> String {
+    hex::encode(digest::digest(&digest::SHA256, token.as_bytes()))
+}
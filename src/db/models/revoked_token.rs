@@ -0,0 +1,134 @@
+use chrono::{ NaiveDateTime, Utc };
+use diesel::prelude::*;
+use serde::{ Serialize, Deserialize };
+use uuid::Uuid;
+
+use crate::db::{ models::schema, with_connection, DbPool, Error };
+
+/// The struct to represent a revoked access token returned from the postgresql database
+///
+/// This struct is a representation of the schema from the revoked_tokens table in the commerce
+/// database. Currently this includes fields for the token's jti, the user it was issued to, when
+/// the token expires and when it was revoked. A token is only listed until its expires_at, after
+/// that its signature check refuses it anyway and the row is purged by the cleanup jobs.
+///
+/// revoked_token.jti is the primary key of the table.
+///
+/// # Examples
+///
+/// ```
+/// RevokedToken::insert(&pool, RevokedToken::new(jti, user_id, expires_at)).await?;
+/// ```
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(jti), table_name = schema::revoked_tokens)]
+pub struct RevokedToken {
+    pub jti: Uuid,
+    pub user_id: Uuid,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: NaiveDateTime,
+}
+
+/// The struct to represent a user's "log out everywhere" cutoff returned from the postgresql
+/// database
+///
+/// This struct is a representation of the schema from the user_token_cutoffs table in the commerce
+/// database. Every token of the user issued before revoked_before is refused, tokens issued
+/// from it on are unaffected.
+///
+/// user_token_cutoff.user_id is the primary key of the table.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(primary_key(user_id), table_name = schema::user_token_cutoffs)]
+pub struct UserTokenCutoff {
+    pub user_id: Uuid,
+    pub revoked_before: NaiveDateTime,
+}
+
+impl RevokedToken {
+    pub fn new(jti: Uuid, user_id: Uuid, expires_at: NaiveDateTime) -> Self {
+        Self {
+            jti,
+            user_id,
+            expires_at,
+            revoked_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Records the token as revoked, revoking an already revoked token is a no-op
+    pub async fn insert(pool: &DbPool, token: RevokedToken) -> Result<(), Error> {
+        use schema::revoked_tokens;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::insert_into(revoked_tokens::table)
+                    .values(&token)
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .map(|_| ())
+            })
+        }).await
+    }
+
+    /// Loads every revoked token that has not expired yet
+    pub async fn get_live(pool: &DbPool) -> Result<Vec<RevokedToken>, Error> {
+        use schema::revoked_tokens::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                revoked_tokens
+                    .filter(expires_at.gt(Utc::now().naive_utc()))
+                    .load::<RevokedToken>(conn)
+            })
+        }).await
+    }
+
+    /// Deletes the revoked tokens that have expired, returning how many were deleted. Run by the
+    /// cleanup jobs.
+    pub fn purge_expired(conn: &mut PgConnection) -> QueryResult<usize> {
+        use schema::revoked_tokens::dsl::*;
+
+        diesel::delete(revoked_tokens.filter(expires_at.le(Utc::now().naive_utc())))
+            .execute(conn)
+    }
+}
+
+impl UserTokenCutoff {
+    /// Revokes every token issued to the user up to now, returning the new cutoff
+    pub async fn revoke_all(pool: &DbPool, user: Uuid) -> Result<UserTokenCutoff, Error> {
+        use schema::user_token_cutoffs::dsl::*;
+
+        let cutoff = UserTokenCutoff {
+            user_id: user,
+            revoked_before: Utc::now().naive_utc(),
+        };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::insert_into(user_token_cutoffs)
+                    .values(&cutoff)
+                    .on_conflict(user_id)
+                    .do_update()
+                    .set(revoked_before.eq(cutoff.revoked_before))
+                    .get_result::<UserTokenCutoff>(conn)
+            })
+        }).await
+    }
+
+    pub async fn get_all(pool: &DbPool) -> Result<Vec<UserTokenCutoff>, Error> {
+        use schema::user_token_cutoffs::dsl::*;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                user_token_cutoffs
+                    .load::<UserTokenCutoff>(conn)
+            })
+        }).await
+    }
+}
@@ -2,7 +2,10 @@ use uuid::Uuid;
 use diesel::prelude::*;
 use serde::{ Serialize, Deserialize };
 
-use crate::db::models::schema;
+use crate::{
+    db::{ keyset, models::schema, with_connection, DbPool, Error, Permission, RolePermission },
+    net::{ Page, Pagination },
+};
 
 /// The struct to represent a role returned from the postgresql database
 /// 
@@ -24,9 +27,119 @@ use crate::db::models::schema;
 ///     .filter(uuid.eq(role_id))
 ///     .first::<Role>(connection);
 /// ```
-#[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
 #[diesel(primary_key(uuid), table_name = schema::roles)]
 pub struct Role {
     pub uuid: Uuid,
     pub name: String,
 }
+
+impl Role {
+    /// Resolves the role assigned to the given user through the users -> roles join
+    pub async fn get_for_user(pool: &DbPool, user_id: Uuid) -> Result<Role, Error> {
+        use schema::{ roles, users };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                users::table
+                    .inner_join(roles::table)
+                    .filter(users::uuid.eq(user_id))
+                    .select(roles::all_columns)
+                    .first::<Role>(conn)
+            })
+        }).await
+    }
+
+    /// Loads a page of the roles, by name, along with the permissions each has been granted
+    pub async fn get_all_with_permissions(
+        pool: &DbPool,
+        pagination: Pagination
+    ) -> Result<Page<(Role, Vec<Permission>)>, Error> {
+        use schema::{ role_permissions, roles };
+
+        let cursor = pagination.cursor::<String, Uuid>()?;
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                let backward = cursor.as_ref().is_some_and(|cursor| cursor.backward);
+                let after = cursor.as_ref().map(|cursor| (cursor.key.clone(), cursor.id));
+
+                let page_roles = keyset!(roles::table.into_boxed(), roles::name, roles::uuid, !backward, after)
+                    .limit(pagination.get_limit() + 1)
+                    .load::<Role>(conn)?;
+
+                let grants = role_permissions::table
+                    .filter(role_permissions::role_id.eq_any(page_roles.iter().map(|role| role.uuid)))
+                    .load::<RolePermission>(conn)?;
+
+                let total = match pagination.wants_total() {
+                    true => Some(roles::table.count().get_result::<i64>(conn)?),
+                    false => None,
+                };
+
+                let rows = page_roles.into_iter()
+                    .map(|role| {
+                        let granted = grants.iter()
+                            .filter(|grant| grant.role_id == role.uuid)
+                            .map(|grant| grant.permission)
+                            .collect();
+
+                        (role, granted)
+                    })
+                    .collect();
+
+                Ok::<_, Error>(pagination.page(&cursor, rows, |(role, _)| (role.name.clone(), role.uuid), total))
+            })
+        }).await
+    }
+
+    /// Grants the permission to the role, granting an already held permission is a no-op
+    pub async fn grant(pool: &DbPool, role_id: Uuid, permission: Permission) -> Result<(), Error> {
+        use schema::{ role_permissions, roles };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                roles::table
+                    .find(role_id)
+                    .select(roles::uuid)
+                    .first::<Uuid>(conn)?;
+
+                diesel::insert_into(role_permissions::table)
+                    .values(RolePermission { role_id, permission })
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .map(|_| ())
+            })
+        }).await
+    }
+
+    /// Revokes the permission from the role, revoking a permission that is not held is a no-op
+    pub async fn revoke(pool: &DbPool, role_id: Uuid, permission: Permission) -> Result<(), Error> {
+        use schema::{ role_permissions, roles };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                roles::table
+                    .find(role_id)
+                    .select(roles::uuid)
+                    .first::<Uuid>(conn)?;
+
+                diesel::delete(
+                    role_permissions::table
+                        .filter(role_permissions::role_id.eq(role_id))
+                        .filter(role_permissions::permission.eq(permission))
+                )
+                .execute(conn)
+                .map(|_| ())
+            })
+        }).await
+    }
+}
@@ -1,12 +1,68 @@
 // @generated automatically by Diesel CLI.
 
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "tsvector", schema = "pg_catalog"))]
+    pub struct Tsvector;
+}
+
+diesel::table! {
+    cart_items (cart_id, deal_id) {
+        cart_id -> Uuid,
+        deal_id -> Uuid,
+        quantity -> Int4,
+        added_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    carts (uuid) {
+        uuid -> Uuid,
+        user_id -> Nullable<Uuid>,
+        session_id -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    categories (uuid) {
+        uuid -> Uuid,
+        parent_id -> Nullable<Uuid>,
+        name -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
+    deal_categories (deal_id, category_id) {
+        deal_id -> Uuid,
+        category_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Tsvector;
+
     deals (uuid) {
         uuid -> Uuid,
         name -> Text,
         image -> Text,
-        price -> Int4,
+        price_amount -> Int8,
         description -> Text,
+        version -> Int4,
+        price_currency -> Text,
+        created_at -> Timestamp,
+        search -> Tsvector,
+    }
+}
+
+diesel::table! {
+    inventory (deal_id) {
+        deal_id -> Uuid,
+        on_hand -> Int4,
+        reserved -> Int4,
     }
 }
 
@@ -21,6 +77,20 @@ diesel::table! {
     jwt_issuers (uuid) {
         uuid -> Uuid,
         name -> Text,
+        algorithm -> Text,
+        verification_key -> Nullable<Text>,
+        enabled -> Bool,
+    }
+}
+
+diesel::table! {
+    jwt_signing_keys (kid) {
+        kid -> Text,
+        algorithm -> Text,
+        encrypted_key -> Text,
+        created_at -> Timestamp,
+        retired_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
     }
 }
 
@@ -28,11 +98,69 @@ diesel::table! {
     nonces (session_id) {
         nonce -> Text,
         session_id -> Text,
-        key -> Text,
         created_at -> Int8,
     }
 }
 
+diesel::table! {
+    order_lines (order_id, line) {
+        order_id -> Uuid,
+        line -> Int4,
+        deal_id -> Nullable<Uuid>,
+        deal_version -> Int4,
+        name -> Text,
+        price_amount -> Int8,
+        quantity -> Int4,
+        price_currency -> Text,
+    }
+}
+
+diesel::table! {
+    orders (uuid) {
+        uuid -> Uuid,
+        user_id -> Uuid,
+        total_amount -> Int8,
+        created_at -> Timestamp,
+        total_currency -> Text,
+    }
+}
+
+diesel::table! {
+    permissions (name) {
+        name -> Text,
+        description -> Text,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (uuid) {
+        uuid -> Uuid,
+        family_id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    revoked_tokens (jti) {
+        jti -> Uuid,
+        user_id -> Uuid,
+        expires_at -> Timestamp,
+        revoked_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    role_permissions (role_id, permission) {
+        role_id -> Uuid,
+        permission -> Text,
+    }
+}
+
 diesel::table! {
     roles (uuid) {
         uuid -> Uuid,
@@ -49,6 +177,23 @@ diesel::table! {
         last_activity -> Timestamp,
         ip -> Nullable<Text>,
         user_id -> Nullable<Uuid>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    stock_reservations (cart_id, deal_id) {
+        cart_id -> Uuid,
+        deal_id -> Uuid,
+        quantity -> Int4,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_token_cutoffs (user_id) {
+        user_id -> Uuid,
+        revoked_before -> Timestamp,
     }
 }
 
@@ -61,16 +206,44 @@ diesel::table! {
     }
 }
 
-diesel::joinable!(nonces -> sessions (session_id));
+diesel::joinable!(cart_items -> carts (cart_id));
+diesel::joinable!(cart_items -> deals (deal_id));
+diesel::joinable!(carts -> users (user_id));
+diesel::joinable!(deal_categories -> categories (category_id));
+diesel::joinable!(deal_categories -> deals (deal_id));
+diesel::joinable!(inventory -> deals (deal_id));
+diesel::joinable!(order_lines -> deals (deal_id));
+diesel::joinable!(order_lines -> orders (order_id));
+diesel::joinable!(orders -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(role_permissions -> permissions (permission));
+diesel::joinable!(role_permissions -> roles (role_id));
 diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(stock_reservations -> carts (cart_id));
+diesel::joinable!(stock_reservations -> deals (deal_id));
+diesel::joinable!(user_token_cutoffs -> users (user_id));
 diesel::joinable!(users -> roles (role));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    cart_items,
+    carts,
+    categories,
+    deal_categories,
     deals,
+    inventory,
     issuers,
     jwt_issuers,
+    jwt_signing_keys,
     nonces,
+    order_lines,
+    orders,
+    permissions,
+    refresh_tokens,
+    revoked_tokens,
+    role_permissions,
     roles,
     sessions,
+    stock_reservations,
+    user_token_cutoffs,
     users,
 );
@@ -5,8 +5,10 @@ use serde::{ Serialize, Deserialize };
 use super::schema;
 use crate::db::{
     crypt,
-    establish_connection,
     gen_salt,
+    with_connection,
+    DbPool,
+    Error,
 };
 
 /// The struct to represent a user returned from the postgresql database
@@ -24,11 +26,11 @@ use crate::db::{
 /// // this assumes you are using diesel
 /// use commerce::db::models::schema::users::dsl::*;
 ///
-/// let connection = &mut establish_connection();
-///
-/// let response = users
-///     .filter(uuid.eq(user_id))
-///     .first::<User>(connection);
+/// let response = with_connection(&pool, move |conn| {
+///     users
+///         .filter(uuid.eq(user_id))
+///         .first::<User>(conn)
+/// }).await;
 /// ```
 #[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
 #[diesel(primary_key(uuid), table_name = schema::users)]
@@ -41,54 +43,66 @@ pub struct User {
 }
 
 impl User {
-    pub fn get(user_id: Uuid) -> Option<User> {
+    pub async fn get(pool: &DbPool, user_id: Uuid) -> Result<User, Error> {
         use schema::users::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
-        .read_only()
-        .run(|conn| {
-            users
-                .filter(uuid.eq(user_id))
-                .first::<User>(conn)
-        });
-
-        response.ok()
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                users
+                    .filter(uuid.eq(user_id))
+                    .first::<User>(conn)
+            })
+        }).await
     }
 
-    pub fn get_from_auth(user_email: &str, user_password: &str) -> Option<User> {
+    pub async fn get_from_auth(pool: &DbPool, user_email: &str, user_password: &str) -> Result<User, Error> {
         use schema::users::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
-        .read_only()
-        .run(|conn| {
-            users
-                .filter(email.eq(user_email))
-                .filter(password.eq(crypt(user_password, password)))
-                .first::<User>(conn)
-            });
-
-        response.ok()
+        let user_email = user_email.to_string();
+        let user_password = user_password.to_string();
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                users
+                    .filter(email.eq(&user_email))
+                    .filter(password.eq(crypt(&user_password, password)))
+                    .first::<User>(conn)
+            })
+        }).await
     }
 
-    pub fn insert(user_email: &str, user_password: &str) -> Option<User> {
+    pub async fn insert(pool: &DbPool, user_email: &str, user_password: &str) -> Result<User, Error> {
         use schema::users::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
-        .read_write()
-        .run(|conn| {
-            diesel::insert_into(users)
-                .values((
-                    email.eq(user_email),
-                    password.eq(crypt(user_password, gen_salt("bf"))),
-                ))
-                .get_result::<User>(conn)
-        });
-
-        response.ok()
+        let user_email = user_email.to_string();
+        let user_password = user_password.to_string();
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::insert_into(users)
+                    .values((
+                        email.eq(&user_email),
+                        password.eq(crypt(&user_password, gen_salt("bf"))),
+                    ))
+                    .get_result::<User>(conn)
+            })
+        }).await
     }
 }
 
+/// Signs up a user with a random email and password, for the database tests that need one to own
+/// their carts, orders or sessions. Returns the user's uuid.
+#[cfg(test)]
+pub async fn insert_test_user(pool: &DbPool, label: &str) -> Uuid {
+    let email = format!("{}-test-{}@example.com", label, Uuid::new_v4());
+    let password = Uuid::new_v4().to_string();
 
+    User::insert(pool, &email, &password).await
+        .expect("test user could not be inserted")
+        .uuid
+        .unwrap()
+}
@@ -3,9 +3,9 @@ use serde::{ Serialize, Deserialize };
 use uuid::Uuid;
 
 use super::schema;
-use crate::db::establish_connection;
+use crate::db::{ with_connection, DbPool, Error };
 
-#[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone, Debug)]
 #[diesel(primary_key(id), table_name = schema::sessions)]
 pub struct UserSession {
     pub id: String,
@@ -15,6 +15,7 @@ pub struct UserSession {
     pub last_activity: chrono::NaiveDateTime,
     pub ip: Option<String>,
     pub user_id: Option<Uuid>,
+    pub created_at: chrono::NaiveDateTime,
 }
 
 impl UserSession {
@@ -39,37 +40,106 @@ impl UserSession {
             last_activity: now,
             ip,
             user_id,
+            created_at: now,
         }
     }
 
-    /// Due to the way axum session layer currently works, on session load the layer is given a sort of
-    /// initial session id, and then on session store a new session id is generated and used for the
-    /// rest of the session. As such, when we receive a request on a route that requires a valid
-    /// session id to already exist in the database (i.e. the fk requirement of nonces on the sessions
-    /// table), we must insert the new session id if it does not already exist. Hopefully this is a
-    /// temporary fix.
-    pub fn redundant_guarantee(sid: &str) -> Result<usize, diesel::result::Error>{
+    /// Deletes every expired session along with the nonces issued to it, returning how many
+    /// sessions were deleted. Run by the cleanup jobs.
+    pub fn purge_expired(conn: &mut PgConnection) -> QueryResult<usize> {
+        use schema::{ nonces, sessions };
+
+        let now = chrono::Utc::now().naive_utc();
+        let expired = sessions::table
+            .filter(sessions::expires_at.lt(now))
+            .select(sessions::id);
+
+        diesel::delete(nonces::table.filter(nonces::session_id.eq_any(expired)))
+            .execute(conn)?;
+
+        diesel::delete(sessions::table.filter(sessions::expires_at.lt(now)))
+            .execute(conn)
+    }
+
+    /// Deletes the session along with its nonce, returning how many sessions were deleted
+    pub async fn delete(pool: &DbPool, sid: &str) -> Result<usize, Error> {
+        use schema::{ nonces, sessions };
+
+        let sid = sid.to_string();
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                diesel::delete(nonces::table.filter(nonces::session_id.eq(&sid)))
+                    .execute(conn)?;
+
+                diesel::delete(sessions::table.filter(sessions::id.eq(&sid)))
+                    .execute(conn)
+            })
+        }).await
+    }
+
+    /// Loads the unexpired sessions signed in as the user, most recently active first
+    pub async fn get_for_user(pool: &DbPool, user: Uuid) -> Result<Vec<UserSession>, Error> {
         use schema::sessions::dsl::*;
 
-        let connection = &mut establish_connection();
-        let response = connection.build_transaction()
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_only()
+            .run(|conn| {
+                sessions
+                    .filter(user_id.eq(user))
+                    .filter(expires_at.gt(chrono::Utc::now().naive_utc()))
+                    .order(last_activity.desc())
+                    .load::<UserSession>(conn)
+            })
+        }).await
+    }
+
+    /// Deletes a single session of the user along with its nonce, `Error::NotFound` if the user
+    /// has no such session
+    pub async fn delete_for_user_by_id(pool: &DbPool, user: Uuid, sid: &str) -> Result<(), Error> {
+        use schema::{ nonces, sessions };
+
+        let sid = sid.to_string();
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
+            .read_write()
+            .run(|conn| {
+                let session = sessions::table
+                    .filter(sessions::id.eq(&sid))
+                    .filter(sessions::user_id.eq(user));
+
+                diesel::delete(nonces::table.filter(nonces::session_id.eq_any(session.select(sessions::id))))
+                    .execute(conn)?;
+
+                match diesel::delete(session).execute(conn)? {
+                    0 => Err(Error::NotFound),
+                    _ => Ok(()),
+                }
+            })
+        }).await
+    }
+
+    /// Deletes every session signed in as the user along with their nonces, returning how many
+    /// sessions were deleted
+    pub async fn delete_for_user(pool: &DbPool, user: Uuid) -> Result<usize, Error> {
+        use schema::{ nonces, sessions };
+
+        with_connection(pool, move |conn| {
+            conn.build_transaction()
             .read_write()
             .run(|conn| {
-                diesel::insert_into(sessions)
-                        .values(
-                            UserSession::new(
-                                sid.to_string(),
-                                None,
-                                None,
-                                None,
-                                None,
-                                None
-                            )
-                        )
-                        .on_conflict_do_nothing()
-                        .execute(conn)
-            });
-
-        response
+                let signed_in = sessions::table
+                    .filter(sessions::user_id.eq(user))
+                    .select(sessions::id);
+
+                diesel::delete(nonces::table.filter(nonces::session_id.eq_any(signed_in)))
+                    .execute(conn)?;
+
+                diesel::delete(sessions::table.filter(sessions::user_id.eq(user)))
+                    .execute(conn)
+            })
+        }).await
     }
 }
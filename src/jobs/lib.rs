@@ -0,0 +1,115 @@
+use diesel::{ Connection, RunQueryDsl };
+use log::{ debug, error, info };
+use rand::Rng;
+use std::time::Duration;
+
+use crate::db::{ self, pg_try_advisory_xact_lock, with_connection, Cart, DbPool, Inventory, Nonce, RevokedToken, UserSession };
+use crate::jobs::Job;
+
+/// The jobs purging expired state: sessions, signin nonces, token revocations, abandoned
+/// anonymous carts and stock reservations
+pub fn cleanup_jobs() -> Vec<Job> {
+    vec![
+        Job {
+            name: "expired_sessions",
+            lock_id: 0x636f_6d6d_0001,
+            interval: Duration::from_secs(15 * 60),
+            run: UserSession::purge_expired,
+        },
+        Job {
+            name: "expired_nonces",
+            lock_id: 0x636f_6d6d_0002,
+            interval: Duration::from_secs(5 * 60),
+            run: Nonce::purge_expired,
+        },
+        Job {
+            name: "expired_token_revocations",
+            lock_id: 0x636f_6d6d_0003,
+            interval: Duration::from_secs(15 * 60),
+            run: RevokedToken::purge_expired,
+        },
+        Job {
+            name: "abandoned_carts",
+            lock_id: 0x636f_6d6d_0004,
+            interval: Duration::from_secs(60 * 60),
+            run: Cart::purge_abandoned,
+        },
+        Job {
+            name: "expired_stock_reservations",
+            lock_id: 0x636f_6d6d_0005,
+            interval: Duration::from_secs(60),
+            run: Inventory::release_expired,
+        },
+    ]
+}
+
+/// Runs the job once unless another instance is running it right now
+/// 
+/// Returns how many rows the job cleaned up, or `None` when the job's advisory lock was held
+/// elsewhere and the run was skipped.
+pub async fn run_job(pool: &DbPool, job: Job) -> Result<Option<usize>, db::Error> {
+    with_connection(pool, move |conn| {
+        conn.transaction(|conn| {
+            let locked = diesel::select(pg_try_advisory_xact_lock(job.lock_id))
+                .get_result::<bool>(conn)?;
+
+            if !locked {
+                return Ok(None);
+            }
+
+            (job.run)(conn).map(Some)
+        })
+    }).await
+}
+
+/// Runs every job once, one after the other, returning whether all of them succeeded
+pub async fn run_jobs_once(pool: &DbPool, jobs: Vec<Job>) -> bool {
+    let mut succeeded = true;
+
+    for job in jobs {
+        succeeded &= report(job, run_job(pool, job).await);
+    }
+
+    succeeded
+}
+
+/// Spawns a task per job running it on its interval for the lifetime of the server
+/// 
+/// The first run is delayed by a random fraction of the interval, and every later run by the
+/// interval give or take 10%, so instances started together don't all reach for the lock at once.
+pub fn spawn_jobs(pool: DbPool, jobs: Vec<Job>) {
+    for job in jobs {
+        let pool = pool.clone();
+
+        tokio::spawn(async move {
+            let first_run = rand::thread_rng().gen_range(Duration::ZERO..job.interval);
+            tokio::time::sleep(first_run).await;
+
+            loop {
+                report(job, run_job(&pool, job).await);
+                tokio::time::sleep(jittered(job.interval)).await;
+            }
+        });
+    }
+}
+
+/// Logs the outcome of a run, returning whether it succeeded
+fn report(job: Job, outcome: Result<Option<usize>, db::Error>) -> bool {
+    match outcome {
+        Ok(Some(0)) => debug!("Job {} found nothing to clean up", job.name),
+        Ok(Some(count)) => info!("Job {} cleaned up {} rows", job.name, count),
+        Ok(None) => debug!("Job {} skipped, another instance holds its lock", job.name),
+        Err(e) => {
+            error!("Job {} failed: {}", job.name, e);
+            return false;
+        },
+    }
+
+    true
+}
+
+/// The interval give or take a random 10%
+fn jittered(interval: Duration) -> Duration {
+    let jitter = interval / 10;
+    interval - jitter + rand::thread_rng().gen_range(Duration::ZERO..=jitter * 2)
+}
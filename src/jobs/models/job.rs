@@ -0,0 +1,29 @@
+use diesel::{ pg::PgConnection, QueryResult };
+use std::time::Duration;
+
+/// A database maintenance task run on a schedule by every instance of the api
+/// 
+/// Each run happens inside a transaction holding the postgres advisory lock `lock_id`, taken with
+/// `pg_try_advisory_xact_lock`. When several instances are up, whichever takes the lock first
+/// runs the job, and the others skip that tick rather than repeating the work. The lock is
+/// released when the transaction ends, so a crashed instance never holds on to it.
+/// 
+/// `run` returns how many rows the job cleaned up, which is logged.
+/// 
+/// # Examples
+/// 
+/// ```
+/// let job = Job {
+///     name: "nonces",
+///     lock_id: 0x6e6f_6e63,
+///     interval: Duration::from_secs(300),
+///     run: Nonce::purge_expired,
+/// };
+/// ```
+#[derive(Clone, Copy)]
+pub struct Job {
+    pub name: &'static str,
+    pub lock_id: i64,
+    pub interval: Duration,
+    pub run: fn(&mut PgConnection) -> QueryResult<usize>,
+}
@@ -0,0 +1,5 @@
+pub mod job;
+
+pub use self::{
+    job::*,
+};
@@ -1,52 +1,376 @@
 use chrono::{ DateTime, Duration, Utc };
-use data_encoding::BASE64URL_NOPAD;
+use data_encoding::BASE64;
 use dotenvy::dotenv;
-use std::env;
-use jsonwebtoken::{ encode, decode, Algorithm, DecodingKey, EncodingKey, Header, Validation };
+use serde::Deserialize;
+use std::{ env, fs, str::FromStr };
+use jsonwebtoken::{ encode, decode, decode_header, errors::ErrorKind, Algorithm, DecodingKey, Header, Validation };
 use rand::{ thread_rng, distributions::{ Standard, Distribution } };
+use ring::{
+    aead::{ Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN },
+    rand::{ SecureRandom, SystemRandom },
+    signature::{ EcdsaKeyPair, Ed25519KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING, ECDSA_P384_SHA384_FIXED_SIGNING },
+};
+use uuid::{ uuid, Uuid };
 
-use crate::jwt::models::claims::Claims;
+use crate::db::{ DbPool, JwtIssuer };
+use crate::jwt::models::{ claims::{ Claims, ACCESS_TOKEN_TTL_SECS }, key_error::KeyError, key_ring::KeyRing, revocation_list::RevocationList, signing_key::SigningKey, token_error::TokenError };
+
+/// The issuer uuid used when JWT_ISSUER_ID is not set, registered by the jwt_issuer_keys migration
+const DEFAULT_ISSUER_ID: Uuid = uuid!("d582df1f-3642-4191-b822-0c9a73719259");
+
+/// The audience used when JWT_AUDIENCE is not set
+const DEFAULT_AUDIENCE: &str = "commerce-api";
+
+/// How long a rotated out key keeps verifying tokens when JWT_KEY_GRACE_PERIOD is not set
+const DEFAULT_GRACE_PERIOD_SECS: i64 = 24 * 60 * 60;
+
+/// How long a refresh token is valid for when JWT_REFRESH_TTL is not set
+const DEFAULT_REFRESH_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// The only claim read from a token before its signature has been checked
+#[derive(Deserialize)]
+struct UnverifiedIssuer {
+    iss: Uuid,
+}
 
 // Encrypt the JWT
-pub fn encrypt_jwt(secret: &String, claims: Claims) -> Result<String, jsonwebtoken::errors::Error> {
-    let header = Header::new(Algorithm::HS256);
-    let signature = EncodingKey::from_base64_secret(secret).unwrap();
+pub fn encrypt_jwt(key: &SigningKey, claims: Claims) -> Result<String, jsonwebtoken::errors::Error> {
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
 
     let jwt = encode(
         &header,
         &claims,
-        &signature,
+        key.encoding_key(),
     )?;
 
     Ok(jwt)
 }
 
-// Decrypt the JWT
-pub fn decrypt_jwt(secret: &String, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+/// Decrypt the JWT
+/// 
+/// Verifies a token minted by any issuer registered in the jwt_issuers table. The unverified iss
+/// claim is only used to pick the issuer, the token is then checked against that issuer's own
+/// algorithm and key, and must carry our audience, a matching iss, and a valid exp and nbf.
+/// Tokens from unknown or disabled issuers are refused without checking the signature.
+/// 
+/// Tokens minted by this api are verified with the key from the key ring named by their kid rather
+/// than the issuer's stored key, but the local issuer must still be registered and enabled. A kid
+/// the ring doesn't know reloads the ring once, in case another instance rotated the key.
+/// 
+/// Tokens that pass every check are finally refused if they have been revoked.
+pub async fn decrypt_jwt(
+    pool: &DbPool,
+    key_ring: &KeyRing,
+    revocations: &RevocationList,
+    token: &str,
+) -> Result<Claims, TokenError> {
+    let header = decode_header(token)?;
+
+    let mut peek = Validation::new(header.alg);
+    peek.insecure_disable_signature_validation();
+    peek.validate_exp = false;
+    peek.required_spec_claims.clear();
+
+    let issuer_id = decode::<UnverifiedIssuer>(token, &DecodingKey::from_secret(&[]), &peek)?
+        .claims
+        .iss;
+
+    let issuer = JwtIssuer::get(pool, issuer_id).await?;
+    if !issuer.enabled {
+        return Err(TokenError::DisabledIssuer);
+    }
+
+    let signing_key;
+    let issuer_key;
+    let (algorithm, key) = if issuer.uuid == get_issuer_id() {
+        let kid = header.kid.ok_or(TokenError::UnknownKey)?;
+
+        signing_key = match key_ring.find(&kid) {
+            Some(key) => key,
+            None => {
+                key_ring.refresh(pool).await?;
+                key_ring.find(&kid).ok_or(TokenError::UnknownKey)?
+            },
+        };
+
+        (signing_key.algorithm, signing_key.decoding_key())
+    } else {
+        let algorithm = Algorithm::from_str(&issuer.algorithm)?;
+        let verification_key = issuer.verification_key.as_deref().ok_or(TokenError::UnknownKey)?;
+        issuer_key = get_decoding_key(algorithm, verification_key)?;
+        (algorithm, &issuer_key)
+    };
+
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[issuer.uuid.to_string()]);
+    validation.set_audience(&[get_audience()]);
+    validation.set_required_spec_claims(&["sub", "iss", "aud", "exp", "nbf"]);
+    validation.validate_nbf = true;
+
     // decodes base64, and validates signature and claims
-    let claims = decode::<Claims>(
-        &token,
-        &DecodingKey::from_base64_secret(secret).unwrap(),
-        &Validation::new(Algorithm::HS256),
-    )?.claims;
+    let claims = decode::<Claims>(token, key, &validation)?.claims;
+
+    if revocations.is_revoked(&claims) {
+        return Err(TokenError::Revoked);
+    }
 
     Ok(claims)
 }
 
-// generate a unique secret for each user when they're created
+/// Builds the key used to verify an issuer's tokens from the key material stored for it
+///
+/// Blank keys are refused, an empty HMAC secret would verify tokens anyone can sign.
+fn get_decoding_key(algorithm: Algorithm, key: &str) -> Result<DecodingKey, jsonwebtoken::errors::Error> {
+    if key.trim().is_empty() {
+        return Err(ErrorKind::InvalidKeyFormat.into());
+    }
+
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => DecodingKey::from_base64_secret(key),
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(key.as_bytes()),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(key.as_bytes()),
+        _ => DecodingKey::from_rsa_pem(key.as_bytes()),
+    }
+}
+
+/// Generates a random base64 encoded secret for the HMAC algorithms
 pub fn gen_secret() -> String {
     let mut rng = thread_rng();
     let secret: Vec<u8> = Standard.sample_iter(&mut rng).take(256).collect();
-    BASE64URL_NOPAD.encode(secret.as_slice())
+    BASE64.encode(secret.as_slice())
+}
+
+/// Generates the material for a new signing key, a base64 secret for HMAC algorithms and a PEM
+/// encoded PKCS#8 private key for the ECDSA and EdDSA ones. RSA keys can't be generated here.
+pub fn gen_key_material(algorithm: Algorithm) -> Result<String, KeyError> {
+    let rng = SystemRandom::new();
+    let rejected = |_| KeyError::Unsupported(algorithm);
+
+    let pkcs8 = match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => return Ok(gen_secret()),
+        Algorithm::ES256 => EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).map_err(rejected)?,
+        Algorithm::ES384 => EcdsaKeyPair::generate_pkcs8(&ECDSA_P384_SHA384_FIXED_SIGNING, &rng).map_err(rejected)?,
+        Algorithm::EdDSA => Ed25519KeyPair::generate_pkcs8(&rng).map_err(rejected)?,
+        _ => return Err(KeyError::Unsupported(algorithm)),
+    };
+
+    Ok(pem::encode(&pem::Pem {
+        tag: "PRIVATE KEY".to_string(),
+        contents: pkcs8.as_ref().to_vec(),
+    }))
+}
+
+/// Encrypts key material for the jwt_signing_keys table
+///
+/// The material is sealed with AES-256-GCM under JWT_KEY_ENCRYPTION_KEY, with the kid as
+/// associated data so a row's material can't be moved to another kid. Stored as the base64 of a
+/// random nonce followed by the ciphertext.
+pub fn seal_key_material(kid: &str, material: &str) -> Result<String, KeyError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).map_err(|_| KeyError::Encryption)?;
+
+    let mut sealed = material.as_bytes().to_vec();
+    get_key_encryption_key()
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(kid.as_bytes()), &mut sealed)
+        .map_err(|_| KeyError::Encryption)?;
+
+    Ok(BASE64.encode(&[nonce.as_slice(), &sealed].concat()))
+}
+
+/// Decrypts key material sealed by `seal_key_material` for the key with the given kid
+pub fn open_key_material(kid: &str, sealed: &str) -> Result<String, KeyError> {
+    let sealed = BASE64.decode(sealed.as_bytes()).map_err(|_| KeyError::Encryption)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(KeyError::Encryption);
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| KeyError::Encryption)?;
+    let mut in_out = ciphertext.to_vec();
+    let material = get_key_encryption_key()
+        .open_in_place(nonce, Aad::from(kid.as_bytes()), &mut in_out)
+        .map_err(|_| KeyError::Encryption)?;
+
+    String::from_utf8(material.to_vec()).map_err(|_| KeyError::Encryption)
+}
+
+/// The key signing keys are encrypted with in the database, JWT_KEY_ENCRYPTION_KEY, 32 base64
+/// encoded bytes
+///
+/// # Panics
+/// This function will panic if JWT_KEY_ENCRYPTION_KEY is missing or isn't 32 base64 encoded bytes.
+fn get_key_encryption_key() -> LessSafeKey {
+    dotenv().ok();
+    let key = env::var("JWT_KEY_ENCRYPTION_KEY").expect("JWT_KEY_ENCRYPTION_KEY must be set");
+    let key = BASE64.decode(key.as_bytes()).expect("JWT_KEY_ENCRYPTION_KEY must be base64");
+    let key = UnboundKey::new(&AES_256_GCM, &key).expect("JWT_KEY_ENCRYPTION_KEY must be 32 bytes");
+
+    LessSafeKey::new(key)
+}
+
+/// The algorithm this api signs its tokens with, JWT_ALGORITHM or HS256 by default
+pub fn get_algorithm() -> Algorithm {
+    dotenv().ok();
+    env::var("JWT_ALGORITHM")
+        .map(|alg| Algorithm::from_str(&alg).expect("JWT_ALGORITHM must be a supported jwt algorithm"))
+        .unwrap_or(Algorithm::HS256)
+}
+
+/// The kid of the configured signing key, JWT_KEY_ID. When not set, symmetric keys get a random
+/// kid and asymmetric ones one derived from their public key.
+pub fn get_key_id() -> Option<String> {
+    dotenv().ok();
+    env::var("JWT_KEY_ID").ok()
+}
+
+/// Reads the configured signing key
+/// 
+/// HMAC algorithms sign with JWT_SECRET, the asymmetric ones with the PEM private key at
+/// JWT_PRIVATE_KEY_PATH. A missing or unreadable key is a configuration error, so this panics.
+pub fn get_key_material(algorithm: Algorithm) -> String {
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => get_secret(),
+        _ => {
+            dotenv().ok();
+            let path = env::var("JWT_PRIVATE_KEY_PATH")
+                .expect("JWT_PRIVATE_KEY_PATH must be set for asymmetric algorithms");
+
+            fs::read_to_string(path).expect("JWT_PRIVATE_KEY_PATH must be readable")
+        },
+    }
+}
+
+/// How often the active signing key is rotated, JWT_KEY_ROTATION_INTERVAL in seconds. Keys are
+/// only rotated on demand when it is not set.
+pub fn get_rotation_interval() -> Option<Duration> {
+    dotenv().ok();
+    env::var("JWT_KEY_ROTATION_INTERVAL")
+        .ok()
+        .map(|secs| Duration::seconds(
+            secs.parse::<i64>().expect("JWT_KEY_ROTATION_INTERVAL must be a number of seconds")
+        ))
+}
+
+/// How long a rotated out key keeps verifying tokens, JWT_KEY_GRACE_PERIOD in seconds (default a
+/// day). This should outlast the tokens the key signed.
+pub fn get_grace_period() -> Duration {
+    dotenv().ok();
+    let secs = str::parse::<i64>(
+        &env::var("JWT_KEY_GRACE_PERIOD").unwrap_or_default()
+    ).unwrap_or(DEFAULT_GRACE_PERIOD_SECS);
+
+    Duration::seconds(secs)
 }
 
 pub fn get_secret() -> String {
     dotenv().ok();
-    return env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    env::var("JWT_SECRET").expect("JWT_SECRET must be set")
+}
+
+/// The uuid of this api's entry in the jwt_issuers table, stamped as the iss claim of our tokens
+pub fn get_issuer_id() -> Uuid {
+    dotenv().ok();
+    env::var("JWT_ISSUER_ID")
+        .map(|id| Uuid::parse_str(&id).expect("JWT_ISSUER_ID must be a uuid"))
+        .unwrap_or(DEFAULT_ISSUER_ID)
+}
+
+/// The audience every token accepted by this api must be minted for
+pub fn get_audience() -> String {
+    dotenv().ok();
+    env::var("JWT_AUDIENCE").unwrap_or(DEFAULT_AUDIENCE.to_string())
+}
+
+/// How long a refresh token can be used for, JWT_REFRESH_TTL in seconds (default 30 days). Each
+/// refresh issues a token with a fresh lifetime, so a device only has to sign in again once it
+/// has been idle for this long.
+pub fn get_refresh_ttl() -> Duration {
+    dotenv().ok();
+    let secs = str::parse::<i64>(
+        &env::var("JWT_REFRESH_TTL").unwrap_or_default()
+    ).unwrap_or(DEFAULT_REFRESH_TTL_SECS);
+
+    Duration::seconds(secs)
 }
 
+/// The cookie carrying the access token for browser clients, expiring along with the token
 pub fn get_auth_cookie(token: &String) -> String {
-    let expires: DateTime<Utc> = Utc::now() + Duration::minutes(1);
+    let expires: DateTime<Utc> = Utc::now() + Duration::seconds(ACCESS_TOKEN_TTL_SECS as i64);
     
     format!("token={}; Expires={}; Path=/; SameSite=None; Secure", token, expires.to_rfc2822())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::set_test_secrets;
+
+    #[test]
+    fn sealed_key_material_only_opens_for_its_kid() {
+        set_test_secrets();
+        let material = gen_secret();
+
+        let sealed = seal_key_material("kid-a", &material).unwrap();
+        assert!(!sealed.contains(&material));
+        assert_ne!(sealed, seal_key_material("kid-a", &material).unwrap());
+
+        assert_eq!(open_key_material("kid-a", &sealed).unwrap(), material);
+        assert!(open_key_material("kid-b", &sealed).is_err());
+        assert!(open_key_material("kid-a", &material).is_err());
+    }
+
+    #[test]
+    fn symmetric_keys_get_random_kids() {
+        let secret = gen_secret();
+
+        let first = SigningKey::from_secret(Algorithm::HS256, None, &secret).unwrap();
+        let second = SigningKey::from_secret(Algorithm::HS256, None, &secret).unwrap();
+        assert_ne!(first.kid, second.kid);
+    }
+
+    #[test]
+    fn blank_verification_keys_are_refused() {
+        for algorithm in [Algorithm::HS256, Algorithm::RS256, Algorithm::ES256] {
+            assert!(get_decoding_key(algorithm, "").is_err());
+            assert!(get_decoding_key(algorithm, "  ").is_err());
+        }
+    }
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn tokens_of_issuers_without_a_key_are_refused() {
+        use crate::db::{ create_pool, models::schema::jwt_issuers, with_connection };
+        use diesel::prelude::*;
+
+        set_test_secrets();
+        let pool = create_pool();
+        let issuer = JwtIssuer {
+            uuid: Uuid::new_v4(),
+            name: "keyless-test-issuer".to_string(),
+            algorithm: "HS256".to_string(),
+            verification_key: None,
+            enabled: true,
+        };
+        let issuer_id = issuer.uuid;
+        with_connection(&pool, move |conn| {
+            diesel::insert_into(jwt_issuers::table).values(issuer).execute(conn)
+        }).await.unwrap();
+
+        // signed with an empty secret, which an empty verification key would accept
+        let mut claims = Claims::new(Uuid::new_v4(), issuer_id, get_audience(), Uuid::new_v4());
+        claims.jti = None;
+        let forged = encode(&Header::new(Algorithm::HS256), &claims, &jsonwebtoken::EncodingKey::from_secret(&[])).unwrap();
+
+        let key_ring = KeyRing::load(&pool).await.unwrap();
+        let revocations = RevocationList::load(&pool).await.unwrap();
+        let result = decrypt_jwt(&pool, &key_ring, &revocations, &forged).await;
+        assert!(matches!(result, Err(TokenError::UnknownKey)));
+
+        with_connection(&pool, move |conn| {
+            diesel::delete(jwt_issuers::table.find(issuer_id)).execute(conn)
+        }).await.unwrap();
+    }
+}
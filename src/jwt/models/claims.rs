@@ -2,36 +2,55 @@ use serde::{ Serialize, Deserialize };
 use std::time::SystemTime;
 use uuid::Uuid;
 
+/// How long an access token is valid for, in seconds
+pub const ACCESS_TOKEN_TTL_SECS: u64 = 3600;
+
 /// The claims object used for handling JWTs
 /// 
 /// JWTs consist of three main parts, the header, the body or the 'claims', and the signature.
-/// This struct is the representation of the claims of a jwt. This struct has six fields; 
+/// This struct is the representation of the claims of a jwt. This struct has nine fields; 
 ///     sub: subject, which is used to store the user uuid of the user that the token is for
 ///     iss: issuer, the uuid of the trusted issuer that issued the token
+///     aud: audience, the service the token is meant for, this api only accepts its own audience
 ///     role: role, the uuid of the role the user has, used for access control
-///     iat: issued at, the time in seconds that the token was issued at
+///     iat: issued at, the time in seconds that the token was issued at. Ours carry fractions of a
+///         second, telling tokens issued just before a "log out everywhere" cutoff from those
+///         issued just after it
 ///     nbf: not before, the time in seconds that the token is not valid before
 ///     exp: expires, the time in seconds that the token is not valid after
+///     jti: jwt id, a unique id for the token so it can be revoked before it expires. Tokens from
+///         other issuers may leave it out, those can only be revoked by a user wide cutoff
+///     fam: family, the refresh token family the token was minted along with, so signing out with
+///         the token can revoke the family too. Only set on our own tokens
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: Uuid,
     pub iss: Uuid,
+    pub aud: String,
     pub role: Uuid,
-    pub iat: u64,
+    pub iat: f64,
     pub nbf: u64,
     pub exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fam: Option<Uuid>,
 }
 
 impl Claims {
-    pub fn new(subject: Uuid, issuer: Uuid, role: Uuid) -> Self {
-        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    pub fn new(subject: Uuid, issuer: Uuid, audience: String, role: Uuid) -> Self {
+        let issued_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let now = issued_at.as_secs();
         Claims {
             sub: subject.to_owned(),
             iss: issuer.to_owned(),
+            aud: audience,
             role: role.to_owned(),
-            iat: now,
+            iat: issued_at.as_secs_f64(),
             nbf: now,
-            exp: now + 3600,
+            exp: now + ACCESS_TOKEN_TTL_SECS,
+            jti: Some(Uuid::new_v4()),
+            fam: None,
         }
     }
 }
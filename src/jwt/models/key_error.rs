@@ -0,0 +1,43 @@
+use jsonwebtoken::Algorithm;
+use std::fmt;
+
+use crate::db;
+
+/// The reasons a signing key can fail to be generated, loaded or rotated
+/// 
+/// `Unsupported` means new keys can't be generated for the algorithm, RSA keys can only be
+/// supplied through JWT_PRIVATE_KEY_PATH. `Invalid` means stored key material could not be parsed,
+/// and `Encryption` that it could not be encrypted for storage or decrypted with
+/// JWT_KEY_ENCRYPTION_KEY.
+#[derive(Debug)]
+pub enum KeyError {
+    Unsupported(Algorithm),
+    Invalid(jsonwebtoken::errors::Error),
+    Encryption,
+    Database(db::Error),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Unsupported(algorithm) => write!(f, "Keys can't be generated for {:?}", algorithm),
+            KeyError::Invalid(e) => write!(f, "Invalid signing key: {}", e),
+            KeyError::Encryption => write!(f, "Unable to encrypt or decrypt signing key"),
+            KeyError::Database(e) => write!(f, "Unable to store signing key: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl From<jsonwebtoken::errors::Error> for KeyError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        KeyError::Invalid(e)
+    }
+}
+
+impl From<db::Error> for KeyError {
+    fn from(e: db::Error) -> Self {
+        KeyError::Database(e)
+    }
+}
@@ -0,0 +1,191 @@
+use chrono::{ NaiveDateTime, Utc };
+use jsonwebtoken::{ jwk::JwkSet, Algorithm };
+use log::{ error, info };
+use std::{ str::FromStr, sync::{ Arc, RwLock }, time::Duration };
+
+use crate::db::{ self, DbPool, JwtSigningKey };
+use crate::jwt::lib::*;
+use crate::jwt::models::{ key_error::KeyError, signing_key::SigningKey };
+
+/// How often the ring is reloaded from the database and checked for a due rotation
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The keys this api signs and verifies its own tokens with
+///
+/// New tokens are always signed with the active key. When the active key is rotated out it becomes
+/// a retiring key, which keeps verifying the tokens it already signed until its grace period ends,
+/// so a rotation never logs anyone out. Tokens name their key in the kid header, which is how the
+/// key to verify them with is picked.
+///
+/// The ring is a cached copy of the jwt_signing_keys table. It is reloaded periodically by
+/// `spawn_rotation`, and whenever a token names a kid the ring doesn't know, so keys rotated by
+/// another instance are picked up without a restart. Cloning the ring is cheap, every clone shares
+/// the same keys.
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: Arc<RwLock<Keys>>,
+}
+
+struct Keys {
+    active: Arc<SigningKey>,
+    active_since: NaiveDateTime,
+    retiring: Vec<(Arc<SigningKey>, NaiveDateTime)>,
+}
+
+impl KeyRing {
+    /// Loads the ring, first registering the key from the environment
+    ///
+    /// The configured key becomes the active key the first time it is seen. A key that is already
+    /// known, by its kid or its material, is left as is, so keys rotated in since are not
+    /// overridden by a restart.
+    pub async fn load(pool: &DbPool) -> Result<KeyRing, KeyError> {
+        let algorithm = get_algorithm();
+        let material = get_key_material(algorithm);
+        let configured = SigningKey::from_material(algorithm, get_key_id(), &material)?;
+
+        let sealed = seal_key_material(&configured.kid, &material)?;
+        let row = JwtSigningKey::new(configured.kid.clone(), format!("{:?}", algorithm), sealed);
+        let kid = configured.kid.clone();
+        let is_configured = move |row: &JwtSigningKey| {
+            row.kid == kid || open_key_material(&row.kid, &row.encrypted_key).is_ok_and(|stored| stored == material)
+        };
+
+        if JwtSigningKey::adopt(pool, row, get_grace_period(), is_configured).await? {
+            info!("Configured signing key {} is now the active key", configured.kid);
+        }
+
+        let keys = Keys::from_rows(JwtSigningKey::get_live(pool).await?)?;
+
+        Ok(KeyRing {
+            keys: Arc::new(RwLock::new(keys)),
+        })
+    }
+
+    /// The key new tokens are signed with
+    pub fn active(&self) -> Arc<SigningKey> {
+        self.keys.read().unwrap().active.clone()
+    }
+
+    /// The key a token with the given kid should be verified with, if it is still accepted
+    pub fn find(&self, kid: &str) -> Option<Arc<SigningKey>> {
+        let keys = self.keys.read().unwrap();
+        let now = Utc::now().naive_utc();
+
+        if keys.active.kid == kid {
+            return Some(keys.active.clone());
+        }
+
+        keys.retiring.iter()
+            .find(|(key, expires_at)| key.kid == kid && *expires_at > now)
+            .map(|(key, _)| key.clone())
+    }
+
+    /// The public keys of every accepted asymmetric key, for the JWKS endpoint
+    pub fn jwks(&self) -> JwkSet {
+        let keys = self.keys.read().unwrap();
+        let now = Utc::now().naive_utc();
+
+        let retiring = keys.retiring.iter()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(key, _)| key);
+
+        JwkSet {
+            keys: std::iter::once(&keys.active)
+                .chain(retiring)
+                .filter_map(|key| key.jwk().cloned())
+                .collect(),
+        }
+    }
+
+    /// Reloads the ring from the database
+    pub async fn refresh(&self, pool: &DbPool) -> Result<(), KeyError> {
+        let keys = Keys::from_rows(JwtSigningKey::get_live(pool).await?)?;
+        *self.keys.write().unwrap() = keys;
+
+        Ok(())
+    }
+
+    /// Generates a new key with the active key's algorithm and makes it the active key
+    ///
+    /// The previous key keeps verifying tokens for JWT_KEY_GRACE_PERIOD. If another instance
+    /// rotated first, its key is kept and no second rotation happens. Returns the new active key.
+    pub async fn rotate(&self, pool: &DbPool) -> Result<Arc<SigningKey>, KeyError> {
+        let current = self.active();
+        let material = gen_key_material(current.algorithm)?;
+        let key = SigningKey::from_material(current.algorithm, None, &material)?;
+        let sealed = seal_key_material(&key.kid, &material)?;
+        let row = JwtSigningKey::new(key.kid.clone(), format!("{:?}", key.algorithm), sealed);
+
+        match JwtSigningKey::rotate(pool, current.kid.clone(), row, get_grace_period()).await {
+            Ok(()) => info!("Rotated signing key {} out for {}", current.kid, key.kid),
+            Err(db::Error::VersionMismatch) => info!("Signing key {} was already rotated", current.kid),
+            Err(e) => return Err(e.into()),
+        }
+
+        self.refresh(pool).await?;
+        Ok(self.active())
+    }
+
+    /// Spawns the task keeping the ring in sync with the database
+    ///
+    /// Every minute the ring is reloaded, and when JWT_KEY_ROTATION_INTERVAL is set, the active key
+    /// is rotated once it is older than the interval. Failures are logged and retried on the next
+    /// tick.
+    pub fn spawn_rotation(self, pool: DbPool) {
+        let rotation_interval = get_rotation_interval();
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(REFRESH_INTERVAL);
+
+            loop {
+                ticks.tick().await;
+
+                if let Err(e) = self.refresh(&pool).await {
+                    error!("Failed to reload signing keys: {}", e);
+                    continue;
+                }
+
+                let Some(interval) = rotation_interval else {
+                    continue;
+                };
+
+                let active_since = self.keys.read().unwrap().active_since;
+                if active_since + interval > Utc::now().naive_utc() {
+                    continue;
+                }
+
+                if let Err(e) = self.rotate(&pool).await {
+                    error!("Scheduled signing key rotation failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+impl Keys {
+    /// Builds the ring from the live rows of the jwt_signing_keys table
+    fn from_rows(rows: Vec<JwtSigningKey>) -> Result<Keys, KeyError> {
+        let mut active = None;
+        let mut retiring = Vec::new();
+
+        for row in rows {
+            let algorithm = Algorithm::from_str(&row.algorithm)?;
+            let material = open_key_material(&row.kid, &row.encrypted_key)?;
+            let key = Arc::new(SigningKey::from_material(algorithm, Some(row.kid), &material)?);
+
+            match (row.retired_at, row.expires_at) {
+                (None, _) => active = Some((key, row.created_at)),
+                (Some(_), Some(expires_at)) => retiring.push((key, expires_at)),
+                (Some(_), None) => (),
+            }
+        }
+
+        let (active, active_since) = active.ok_or(KeyError::Database(db::Error::NotFound))?;
+
+        Ok(Keys {
+            active,
+            active_since,
+            retiring,
+        })
+    }
+}
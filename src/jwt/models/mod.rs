@@ -1,3 +1,15 @@
 pub mod claims;
+pub mod key_error;
+pub mod key_ring;
+pub mod revocation_list;
+pub mod signing_key;
+pub mod token_error;
 
-pub use self::claims::*;
+pub use self::{
+    claims::*,
+    key_error::*,
+    key_ring::*,
+    revocation_list::*,
+    signing_key::*,
+    token_error::*,
+};
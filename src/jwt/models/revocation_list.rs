@@ -0,0 +1,156 @@
+use chrono::{ DateTime, NaiveDateTime, Utc };
+use log::error;
+use std::{ collections::HashMap, sync::{ Arc, RwLock }, time::Duration };
+use uuid::Uuid;
+
+use crate::db::{ self, DbPool, RevokedToken, UserTokenCutoff };
+use crate::jwt::models::claims::Claims;
+
+/// How often the list is reloaded from the database
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The access tokens that were revoked before their exp
+///
+/// Tokens are revoked one at a time by jti on signout, or all at once for a user by a "log out
+/// everywhere" cutoff, which refuses every token of the user issued before it. Cutoffs are compared
+/// with the iat to the microsecond, so a token issued right after one, in the same second, is
+/// still accepted. Every
+/// accepted token is checked against this list after its signature, so it is kept in memory as a
+/// cached copy of the revoked_tokens and user_token_cutoffs tables.
+///
+/// Revocations made through this instance apply immediately. Those made by other instances are
+/// picked up when the list is reloaded by `spawn_refresh`. Cloning the list is cheap, every clone
+/// shares the same revocations.
+#[derive(Clone)]
+pub struct RevocationList {
+    revocations: Arc<RwLock<Revocations>>,
+}
+
+struct Revocations {
+    tokens: HashMap<Uuid, NaiveDateTime>,
+    cutoffs: HashMap<Uuid, NaiveDateTime>,
+}
+
+impl RevocationList {
+    pub async fn load(pool: &DbPool) -> Result<RevocationList, db::Error> {
+        Ok(RevocationList {
+            revocations: Arc::new(RwLock::new(Revocations::load(pool).await?)),
+        })
+    }
+
+    /// Whether the token was revoked, by jti or by a cutoff of its subject
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        let revocations = self.revocations.read().unwrap();
+
+        let by_jti = claims.jti
+            .map(|jti| revocations.tokens.contains_key(&jti))
+            .unwrap_or(false);
+
+        let by_cutoff = revocations.cutoffs.get(&claims.sub)
+            .map(|cutoff| claims.iat < cutoff.and_utc().timestamp_micros() as f64 / 1e6)
+            .unwrap_or(false);
+
+        by_jti || by_cutoff
+    }
+
+    /// Revokes a single token until its exp. Tokens without a jti can only be revoked by a cutoff.
+    pub async fn revoke(&self, pool: &DbPool, claims: &Claims) -> Result<(), db::Error> {
+        let Some(jti) = claims.jti else {
+            return Ok(());
+        };
+
+        let expires_at = DateTime::from_timestamp(claims.exp as i64, 0)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+            .naive_utc();
+
+        RevokedToken::insert(pool, RevokedToken::new(jti, claims.sub, expires_at)).await?;
+        self.revocations.write().unwrap().tokens.insert(jti, expires_at);
+
+        Ok(())
+    }
+
+    /// Revokes every token issued to the user up to now
+    pub async fn revoke_all(&self, pool: &DbPool, user_id: Uuid) -> Result<(), db::Error> {
+        let cutoff = UserTokenCutoff::revoke_all(pool, user_id).await?;
+        self.revocations.write().unwrap().cutoffs.insert(user_id, cutoff.revoked_before);
+
+        Ok(())
+    }
+
+    /// Reloads the list from the database
+    pub async fn refresh(&self, pool: &DbPool) -> Result<(), db::Error> {
+        let revocations = Revocations::load(pool).await?;
+        *self.revocations.write().unwrap() = revocations;
+
+        Ok(())
+    }
+
+    /// Spawns the task reloading the list every 30 seconds. Failures are logged and retried on the
+    /// next tick.
+    pub fn spawn_refresh(self, pool: DbPool) {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(REFRESH_INTERVAL);
+
+            loop {
+                ticks.tick().await;
+
+                if let Err(e) = self.refresh(&pool).await {
+                    error!("Failed to reload token revocations: {}", e);
+                }
+            }
+        });
+    }
+}
+
+impl Revocations {
+    async fn load(pool: &DbPool) -> Result<Revocations, db::Error> {
+        let tokens = RevokedToken::get_live(pool).await?
+            .into_iter()
+            .map(|token| (token.jti, token.expires_at))
+            .collect();
+
+        let cutoffs = UserTokenCutoff::get_all(pool).await?
+            .into_iter()
+            .map(|cutoff| (cutoff.user_id, cutoff.revoked_before))
+            .collect();
+
+        Ok(Revocations {
+            tokens,
+            cutoffs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn claims_issued_at(user: Uuid, issued_at: NaiveDateTime) -> Claims {
+        let mut claims = Claims::new(user, Uuid::new_v4(), "commerce-api".to_string(), Uuid::new_v4());
+        claims.iat = issued_at.and_utc().timestamp_micros() as f64 / 1e6;
+        claims
+    }
+
+    #[test]
+    fn cutoffs_only_revoke_tokens_issued_before_them() {
+        let user = Uuid::new_v4();
+        // the middle of a second, so tokens on either side of the cutoff share its second
+        let cutoff = DateTime::from_timestamp(1_800_000_000, 500_000_000).unwrap().naive_utc();
+        let list = RevocationList {
+            revocations: Arc::new(RwLock::new(Revocations {
+                tokens: HashMap::new(),
+                cutoffs: HashMap::from([(user, cutoff)]),
+            })),
+        };
+
+        assert!(list.is_revoked(&claims_issued_at(user, cutoff - Duration::milliseconds(1))));
+        assert!(!list.is_revoked(&claims_issued_at(user, cutoff + Duration::milliseconds(1))));
+        assert!(!list.is_revoked(&claims_issued_at(Uuid::new_v4(), cutoff - Duration::seconds(1))));
+
+        // whole second iats from other issuers count as issued before a cutoff in their second
+        let mut whole_second = claims_issued_at(user, cutoff);
+        whole_second.iat = whole_second.iat.floor();
+        assert!(list.is_revoked(&whole_second));
+    }
+}
@@ -0,0 +1,169 @@
+use data_encoding::{ BASE64URL_NOPAD, HEXLOWER };
+use jsonwebtoken::{
+    errors::{ Error, ErrorKind },
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+        RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey,
+};
+use ring::{
+    digest::{ digest, SHA256 },
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair,
+        ECDSA_P256_SHA256_FIXED_SIGNING, ECDSA_P384_SHA384_FIXED_SIGNING,
+    },
+};
+
+/// The key this api signs its own tokens with
+///
+/// Symmetric keys (HS256/384/512) can only be checked by this api, so they publish no JWK, and
+/// get a random kid, as one derived from the secret would let anyone holding a token test guesses
+/// of it offline.
+/// Asymmetric keys (RS*, PS*, ES256/384, EdDSA) are loaded from a PKCS#8 private key, and their
+/// public half is published on the JWKS endpoint so that other services can verify our tokens
+/// without sharing a secret. The same JWK is used to build our own decoding key, so what we
+/// publish is exactly what we verify against.
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    jwk: Option<Jwk>,
+}
+
+impl SigningKey {
+    /// Builds a signing key from stored key material, a base64 secret for HMAC algorithms and a
+    /// PEM private key otherwise
+    pub fn from_material(algorithm: Algorithm, kid: Option<String>, material: &str) -> Result<Self, Error> {
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Self::from_secret(algorithm, kid, material),
+            _ => Self::from_pem(algorithm, kid, material.as_bytes()),
+        }
+    }
+
+    /// Builds an HMAC signing key from a base64 encoded secret, with a random kid when none is given
+    pub fn from_secret(algorithm: Algorithm, kid: Option<String>, secret: &str) -> Result<Self, Error> {
+        if !matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(ErrorKind::InvalidAlgorithm.into());
+        }
+
+        let encoding_key = EncodingKey::from_base64_secret(secret)?;
+        let decoding_key = DecodingKey::from_base64_secret(secret)?;
+        let kid = kid.unwrap_or_else(random_key_id);
+
+        Ok(Self {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: None,
+        })
+    }
+
+    /// Builds an asymmetric signing key from a PEM encoded private key
+    ///
+    /// RSA keys may be PKCS#1 (`RSA PRIVATE KEY`) or PKCS#8 (`PRIVATE KEY`), EC and Ed25519 keys
+    /// must be PKCS#8. When no kid is given, one is derived from the public key so that it only
+    /// changes when the key does.
+    pub fn from_pem(algorithm: Algorithm, kid: Option<String>, pem: &[u8]) -> Result<Self, Error> {
+        let parsed = pem::parse(pem).map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?;
+        let der = parsed.contents.as_slice();
+        let rejected = |_| Error::from(ErrorKind::InvalidKeyFormat);
+
+        let (encoding_key, public_key, params) = match algorithm {
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+            | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {
+                let key_pair = match parsed.tag.as_str() {
+                    "RSA PRIVATE KEY" => RsaKeyPair::from_der(der),
+                    _ => RsaKeyPair::from_pkcs8(der),
+                }.map_err(rejected)?;
+
+                let public_key = key_pair.public_key();
+                let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: BASE64URL_NOPAD.encode(public_key.modulus().big_endian_without_leading_zero()),
+                    e: BASE64URL_NOPAD.encode(public_key.exponent().big_endian_without_leading_zero()),
+                });
+
+                (EncodingKey::from_rsa_pem(pem)?, public_key.as_ref().to_vec(), params)
+            },
+            Algorithm::ES256 | Algorithm::ES384 => {
+                let (signing, curve) = match algorithm {
+                    Algorithm::ES256 => (&ECDSA_P256_SHA256_FIXED_SIGNING, EllipticCurve::P256),
+                    _ => (&ECDSA_P384_SHA384_FIXED_SIGNING, EllipticCurve::P384),
+                };
+                let key_pair = EcdsaKeyPair::from_pkcs8(signing, der).map_err(rejected)?;
+
+                // uncompressed point, 0x04 || x || y
+                let point = key_pair.public_key().as_ref();
+                let (x, y) = point[1..].split_at((point.len() - 1) / 2);
+                let params = AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                    key_type: EllipticCurveKeyType::EC,
+                    curve,
+                    x: BASE64URL_NOPAD.encode(x),
+                    y: BASE64URL_NOPAD.encode(y),
+                });
+
+                (EncodingKey::from_ec_pem(pem)?, point.to_vec(), params)
+            },
+            Algorithm::EdDSA => {
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der).map_err(rejected)?;
+
+                let public_key = key_pair.public_key().as_ref();
+                let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: BASE64URL_NOPAD.encode(public_key),
+                });
+
+                (EncodingKey::from_ed_pem(pem)?, public_key.to_vec(), params)
+            },
+            _ => return Err(ErrorKind::InvalidAlgorithm.into()),
+        };
+
+        let kid = kid.unwrap_or_else(|| key_id(&public_key));
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(algorithm),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+        let decoding_key = DecodingKey::from_jwk(&jwk)?;
+
+        Ok(Self {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: Some(jwk),
+        })
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding_key
+    }
+
+    /// The public key to publish on the JWKS endpoint, none for symmetric keys
+    pub fn jwk(&self) -> Option<&Jwk> {
+        self.jwk.as_ref()
+    }
+}
+
+/// A stable id for a key, the first 8 bytes of the SHA-256 of its public half
+fn key_id(key: &[u8]) -> String {
+    HEXLOWER.encode(&digest(&SHA256, key).as_ref()[..8])
+}
+
+/// An id for a key that reveals nothing about it, 8 random bytes
+fn random_key_id() -> String {
+    HEXLOWER.encode(&rand::random::<[u8; 8]>())
+}
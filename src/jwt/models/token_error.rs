@@ -0,0 +1,58 @@
+use std::fmt;
+
+use crate::db;
+use crate::jwt::KeyError;
+
+/// The reasons a bearer token can be refused
+/// 
+/// Everything but `Database` is the client's fault and should be answered with a 401, a
+/// `Database` error means the issuer could not be looked up and the token was never checked.
+#[derive(Debug)]
+pub enum TokenError {
+    Invalid(jsonwebtoken::errors::Error),
+    UnknownIssuer,
+    UnknownKey,
+    DisabledIssuer,
+    Revoked,
+    Database(db::Error),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Invalid(e) => write!(f, "Invalid token: {}", e),
+            TokenError::UnknownIssuer => write!(f, "Token was minted by an unknown issuer"),
+            TokenError::UnknownKey => write!(f, "Token was signed with an unknown key"),
+            TokenError::DisabledIssuer => write!(f, "Token was minted by a disabled issuer"),
+            TokenError::Revoked => write!(f, "Token has been revoked"),
+            TokenError::Database(e) => write!(f, "Unable to look up token issuer: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        TokenError::Invalid(e)
+    }
+}
+
+impl From<db::Error> for TokenError {
+    fn from(e: db::Error) -> Self {
+        match e {
+            db::Error::NotFound => TokenError::UnknownIssuer,
+            e => TokenError::Database(e),
+        }
+    }
+}
+
+impl From<KeyError> for TokenError {
+    fn from(e: KeyError) -> Self {
+        match e {
+            KeyError::Database(db::Error::NotFound) => TokenError::UnknownKey,
+            KeyError::Database(e) => TokenError::Database(e),
+            _ => TokenError::UnknownKey,
+        }
+    }
+}
@@ -5,31 +5,34 @@
 //! https, JWT, access control, encrypted stored passwords, and logging. 
 
 mod db;
+mod jobs;
 mod jwt;
 mod middlewares;
 mod net;
 mod sessionstore;
 
 use axum::{
-    extract::{ Json, Path, Query },
-    http::{ header::SET_COOKIE, StatusCode },
+    extract::{ Json, OriginalUri, Path, Query, State },
+    http::{ header::{ ETAG, SET_COOKIE }, StatusCode },
     response::AppendHeaders,
-    routing::{ get, post, put, },
+    routing::{ delete, get, patch, post, put, },
     Router,
 };
-use axum_auth::AuthBearer;
 use axum_server::tls_rustls::RustlsConfig;
 use axum_sessions::extractors::{ ReadableSession, WritableSession };
+use jsonwebtoken::jwk::JwkSet;
 use dotenvy::dotenv;
-use log::{ debug, trace, info };
+use log::{ debug, error, trace, info, warn };
 use std::{ env, net::SocketAddr, path::PathBuf, time::Duration, collections::HashMap };
-use uuid::{ uuid, Uuid };
+use uuid::Uuid;
 use validator::Validate;
 
 use crate::db::*;
+use crate::jobs::*;
 use crate::jwt::*;
 use crate::middlewares::*;
 use crate::net::*;
+use crate::sessionstore::*;
 
 type ApiResponse<T> = Result<Json<T>, ErrorResponse>;
 type ApiResponseWithHeaders<T> = Result<(AppendHeaders<Vec<(String, String)>>, Json<T>), ErrorResponse>;
@@ -42,6 +45,48 @@ fn parse_path_uuid(params: HashMap<String, String>, key: &str) -> Result<Uuid, E
         .or(Err(AppError::as_response(StatusCode::NOT_FOUND, "Not Found")))
 }
 
+/// Mints an access token for the user and sends it along with their refresh token, in the body and
+/// as a cookie for browser clients
+fn auth_response(user: User, key_ring: &KeyRing, refresh_token: IssuedToken) -> ApiResponseWithHeaders<UserAuthPayload> {
+    let payload = UserAuthPayload::new(user, &key_ring.active(), refresh_token).map_err(|e| {
+        error!("Failed to sign token: {}", e);
+        AppError::as_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+    })?;
+
+    Ok((
+        AppendHeaders(
+            vec!((SET_COOKIE.to_string(), get_auth_cookie(&payload.token)))
+        )
+        , Json(payload)
+    ))
+}
+
+/// Moves the caller's session to a fresh id before it is signed in, so a session id planted on the
+/// caller beforehand (session fixation) never becomes authenticated. The row of the old id is
+/// deleted, the session layer stores the session under its new id once the response is sent.
+async fn regenerate_session(sessions: &SessionBackend, session: &mut WritableSession) -> Result<(), ErrorResponse> {
+    sessions.delete_by_id(session.id()).await?;
+    session.regenerate();
+
+    Ok(())
+}
+
+/// The owner of the caller's cart, the signed in user or else the caller's anonymous session
+fn cart_owner(auth: Option<AuthUser>, session: &ReadableSession) -> CartOwner {
+    match auth {
+        Some(auth) => CartOwner::User(auth.user_id),
+        None => CartOwner::Session(session.id().to_string()),
+    }
+}
+
+fn parse_path_permission(params: HashMap<String, String>, key: &str) -> Result<Permission, ErrorResponse> {
+    let param_value = params.get(key)
+        .ok_or(AppError::as_response(StatusCode::NOT_FOUND, "Not Found"))?;
+
+    param_value.parse::<Permission>()
+        .or(Err(AppError::as_response(StatusCode::NOT_FOUND, "Not Found")))
+}
+
 /// Inits the API and starts the socket
 /// 
 /// The main function of the binary. Configures the log, ports, SSL 
@@ -62,6 +107,9 @@ fn parse_path_uuid(params: HashMap<String, String>, key: &str) -> Result<Uuid, E
 /// # Otherwise if you have cargo watch and want to use that instead, make sure
 /// to exclude the log folder to avoid continuous restarts
 /// cargo watch -x run -i log 
+/// 
+/// # To run the cleanup jobs once and exit instead of starting the server, use
+/// cargo run -- cleanup
 /// ``` 
 /// 
 #[tokio::main]
@@ -71,6 +119,13 @@ async fn main() {
     trace!("main");
 
     dotenv().ok();
+
+    if env::args().nth(1).as_deref() == Some("cleanup") {
+        info!("Running cleanup jobs once...");
+        let succeeded = run_jobs_once(&create_pool(), cleanup_jobs()).await;
+        std::process::exit(if succeeded { 0 } else { 1 });
+    }
+
     let http_port = str::parse::<u16>(
         &env::var("HTTP_PORT").unwrap_or_default()
     ).unwrap_or(7878);
@@ -87,13 +142,60 @@ async fn main() {
     
     // build our application
     info!("Booting up server...");
+    let pool = create_pool();
+    let key_ring = KeyRing::load(&pool).await.expect("Failed to load the jwt signing keys");
+    key_ring.clone().spawn_rotation(pool.clone());
+    let revocations = RevocationList::load(&pool).await.expect("Failed to load the revoked tokens");
+    revocations.clone().spawn_refresh(pool.clone());
+    spawn_jobs(pool.clone(), cleanup_jobs());
+    let sessions = SessionBackend::from_env(pool.clone()).await;
+
+    let state = AppState {
+        pool,
+        key_ring,
+        revocations,
+        sessions,
+    };
+
+    let app = app(state);
+
+    debug!("Spawning http to https redirect server");
+    tokio::spawn(redirect_http_to_https(ports));
+
+    debug!("Grabbing Self Signed Certs for https");
+    let config = RustlsConfig::from_pem_file(
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("self_signed_certs")
+            .join("localhost.crt"),
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("self_signed_certs")
+            .join("localhost.key"),
+    )
+    .await
+    .unwrap();
+
+    // run it with hyper on localhost:8000
+    let addr = SocketAddr::from(([127, 0, 0, 1], ports.https));
+    info!("listening at {}", addr);
+    axum_server::bind_rustls(addr, config)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
+}
+
+/// Builds the api's router, every route with its state behind the middleware stack
+fn app(state: AppState) -> Router {
     let user_routes = Router::new()
-        .route("/:id", get(get_user));
+        .route("/:id", get(get_user))
+        .route("/:id/sessions", get(get_user_sessions))
+        .route("/:id/sessions/:sid", delete(delete_user_session));
 
     let auth_routes = Router::new()
         .route("/nonce", get(nonce))
+        .route("/refresh", post(refresh))
         .route("/signin", post(signin))
         .route("/signout", get(signout))
+        .route("/signout/all", post(signout_everywhere))
         .route("/signup", put(signup));
 
     let debug_routes = Router::new()
@@ -108,58 +210,73 @@ async fn main() {
         .route("/destroy", get(destroy_session));
 
     let item_routes = Router::new()
-        .route("/:id", get(get_item))
+        .route("/:id", get(get_item).put(replace_item).patch(update_item).delete(delete_item))
+        .route("/:id/stock", get(get_item_stock).put(set_item_stock))
+        .route("/:id/categories", get(get_item_categories).put(set_item_categories))
         .route("/all", get(get_items))
+        .route("/search", get(search_items))
         .route("/", post(create_item));
 
+    let cart_routes = Router::new()
+        .route("/", get(get_cart).delete(clear_cart))
+        .route("/items", post(add_cart_item))
+        .route("/items/:id", put(update_cart_item).delete(remove_cart_item));
+
+    let order_routes = Router::new()
+        .route("/", get(get_orders).post(place_order))
+        .route("/:id", get(get_order));
+
+    let admin_routes = Router::new()
+        .route("/roles", get(get_roles))
+        .route("/roles/:id/permissions/:permission", put(grant_permission).delete(revoke_permission))
+        .route("/keys", get(get_keys))
+        .route("/keys/rotate", post(rotate_keys))
+        .route("/categories", post(create_category))
+        .route("/categories/:id", patch(update_category).delete(delete_category));
+
     let all_routes = Router::new()
+        .nest("/admin", admin_routes)
         .nest("/user", user_routes)
         .nest("/auth", auth_routes)
         .nest("/debug", debug_routes)
         .nest("/session", session_routes)
-        .nest("/item", item_routes);
+        .nest("/item", item_routes)
+        .nest("/cart", cart_routes)
+        .nest("/orders", order_routes)
+        .route("/categories", get(get_categories));
 
     let api_routes = Router::new()
-        .nest("/api/v1", all_routes);
-    
-    let app = with_middleware_stack(api_routes)
-        .fallback(fallback);
-
-    debug!("Spawning http to https redirect server");
-    tokio::spawn(redirect_http_to_https(ports));
-
-    debug!("Grabbing Self Signed Certs for https");
-    let config = RustlsConfig::from_pem_file(
-        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("self_signed_certs")
-            .join("localhost.crt"),
-        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("self_signed_certs")
-            .join("localhost.key"),
-    )
-    .await
-    .unwrap();
+        .route("/.well-known/jwks.json", get(jwks))
+        .nest("/api/v1", all_routes)
+        .with_state(state.clone());
 
-    // run it with hyper on localhost:8000
-    let addr = SocketAddr::from(([127, 0, 0, 1], ports.https));
-    info!("listening at {}", addr.to_string());
-    axum_server::bind_rustls(addr, config)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+    with_middleware_stack(api_routes, state.sessions)
+        .fallback(fallback)
 }
 
 async fn fallback() -> ErrorResponse {
     AppError::as_response(StatusCode::NOT_FOUND, "Not Found")
 }
 
+/// GET route for the public keys of the api's token signing keys
+/// 
+/// Serves a JWK set that other services can verify our tokens with, holding the active key and
+/// any rotated out key still in its grace period. Symmetric keys can't be published, so they are
+/// left out of the set.
+async fn jwks(
+    State(key_ring): State<KeyRing>
+) -> Json<JwkSet> {
+    debug!("GET request received on /.well-known/jwks.json route");
+    Json(key_ring.jwks())
+}
+
 async fn dummy_auth(
     mut session: WritableSession
 ) -> String {
     let dummy_id = Uuid::new_v4();
     
     session
-        .insert("user_id", &dummy_id)
+        .insert("user_id", dummy_id)
         .expect("Could not dummy auth");
 
     dummy_id.to_string()
@@ -197,98 +314,160 @@ async fn write_session(
         .expect("Could not store the answer.");
 }
 
-/// Test route for access control checks. Takes in the user's JWT Bearer Token and verifies they
-/// have the 'admin' role uuid.
+/// Test route for access control checks. Authenticates the caller by session or JWT Bearer Token
+/// and verifies the database assigns them the 'admin' role.
 async fn admin(
-    _session: ReadableSession, 
-    AuthBearer(token): AuthBearer
-) -> ApiResponse<User> {
+    State(pool): State<DbPool>,
+    admin: RequireRole<Admin>
+) -> ApiResponse<UserData> {
     debug!("GET request received on /admin route");
-    let claims: Claims = match decrypt_jwt(&get_secret(), &token) {
-        Ok(data) => data,
-        Err(_) => return Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized")),
-    };
 
-    // TODO
-    // add non static checking of role.
-    if claims.role == uuid!("9abe48f7-307a-4ee8-929c-843c16cfc75b") {
-        debug!("Authorized user, admin request fulfilled, sending JSON response");
-        return Ok(Json(User {
-            uuid: Some(uuid!("9abe48f7-307a-4ee8-929c-843c16cfc75b")), 
-            role: uuid!("9abe48f7-307a-4ee8-929c-843c16cfc75b"),
-            email: "a".to_string(),
-            password: "b".to_string(),
-        }));
-    }
+    let user = User::get(&pool, admin.user_id).await?;
 
-    return Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+    debug!("Authorized user, admin request fulfilled, sending JSON response");
+    Ok(Json(UserData::from(user)))
 }
 
-///
-async fn nonce(session: ReadableSession) -> Result<NonceResponse, ErrorResponse> {
+/// GET route for requesting a signin nonce bound to the caller's session. Responds with the tag
+/// that must be sent back as the nonce of the signin request, see `Nonce` for the protocol.
+async fn nonce(
+    State(pool): State<DbPool>,
+    session: ReadableSession
+) -> Result<NonceResponse, ErrorResponse> {
     debug!("GET request received on /nonce route");
     let sid = session.id();
-    let nonce = Nonce::new(&sid);
+    let nonce = Nonce::new(sid);
 
-    UserSession::redundant_guarantee(&sid).unwrap();
-    match nonce.insert() {
-        Some(_) => {
-            Ok(NoncePayload::as_response(nonce.get_hmac()))
-        },
-        None => {
-            Err(AppError::as_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to generate nonce"))
-        },
-    }
+    nonce.insert(&pool).await?;
 
+    Ok(NoncePayload::as_response(nonce.tag()))
 }
 
 /// POST route for user authentication.
 /// 
-/// Route for user authentication. Takes in an email and password, checks database for a match,
+/// Route for user authentication. Takes in an email, password and the tag of the nonce issued to
+/// the caller's session, which is consumed by the attempt. Checks database for a match,
 /// and the returns the user's uuid, email, role uuid, and a generated JWT to be used for
-/// access control and stateless management. Additionally sends a set-cookie header for browser
-/// clients
+/// access control and stateless management, along with a refresh token starting a new token
+/// family for the device. Additionally sends a set-cookie header for browser clients, and moves the
+/// caller's session to a fresh id as it is signed in, merging the session's cart into the user's.
 async fn signin(
+    State(pool): State<DbPool>,
+    State(key_ring): State<KeyRing>,
+    State(sessions): State<SessionBackend>,
     mut session: WritableSession, 
     Json(payload): Json<UserAuth>
 ) -> ApiResponseWithHeaders<UserAuthPayload> {
     debug!("POST request received on /signin route");
     let sid = session.id();
-    let nonce = Nonce::take(sid);
+    let nonce = match Nonce::take(&pool, sid).await {
+        Ok(nonce) => nonce,
+        Err(db::Error::NotFound) => return Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized")),
+        Err(e) => return Err(e.into()),
+    };
 
-    if nonce.is_none() || nonce.unwrap().validate(sid) {
+    if !nonce.verify(sid, &payload.nonce) {
         return Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
     }
 
-    match User::get_from_auth(payload.email.as_str(), payload.password.as_str()) {
-        Some(user) => {
+    match User::get_from_auth(&pool, payload.email.as_str(), payload.password.as_str()).await {
+        Ok(user) => {
             debug!("Auth request successfully fulfilled, sending JSON response");
-            session.insert("user_id", &user.uuid).expect("Failed to set auth session");
-            let payload = UserAuthPayload::from(user);
+            Cart::merge(&pool, session.id(), user.uuid.unwrap()).await?;
+            regenerate_session(&sessions, &mut session).await?;
+            session.insert("user_id", user.uuid).expect("Failed to set auth session");
+            let refresh_token = RefreshToken::issue(&pool, user.uuid.unwrap(), get_refresh_ttl()).await?;
+            session.insert("refresh_family", refresh_token.family_id).expect("Failed to set auth session");
 
-            Ok((
-                AppendHeaders(
-                    vec!((SET_COOKIE.to_string(), get_auth_cookie(&payload.token)))
-                )
-                , Json(payload)
-            ))
+            auth_response(user, &key_ring, refresh_token)
+        },
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Failed to authenticate")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// POST route exchanging a refresh token for a new access token and refresh token.
+/// 
+/// Each refresh token can only be used once. Presenting one that was already used means it was
+/// copied, so every token of its family is revoked and the device has to sign in again.
+async fn refresh(
+    State(pool): State<DbPool>,
+    State(key_ring): State<KeyRing>,
+    Json(payload): Json<RefreshAuth>
+) -> ApiResponseWithHeaders<UserAuthPayload> {
+    debug!("POST request received on /refresh route");
+
+    match RefreshToken::refresh(&pool, &payload.refresh_token, get_refresh_ttl()).await {
+        Ok(RefreshOutcome::Rotated { token, user_id }) => {
+            let user = User::get(&pool, user_id).await?;
+
+            debug!("Refresh request successfully fulfilled, sending JSON response");
+            auth_response(user, &key_ring, token)
         },
-        None => Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Failed to authenticate")),
+        Ok(RefreshOutcome::Reused { family_id, user_id }) => {
+            warn!("Refresh token reused, revoked token family {} of user {}", family_id, user_id);
+            Err(AppError::with_code(StatusCode::UNAUTHORIZED, "refresh_token_reused", "Unauthorized").to_response())
+        },
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized")),
+        Err(e) => Err(e.into()),
     }
 }
 
+/// GET route for signing out. Destroys the caller's session and revokes the refresh token family
+/// of the device, the one named by the bearer token or else the one the session was signed in
+/// with. When the caller authenticated with a bearer token, that token is revoked too so it can't
+/// be used again before it expires.
 async fn signout(
+    State(pool): State<DbPool>,
+    State(revocations): State<RevocationList>,
+    auth: Option<AuthUser>,
     mut session: WritableSession
 ) -> ApiResponse<(String,)> {
     debug!("GET request received on /signout route");
 
+    let claims = auth.and_then(|auth| auth.claims);
+    let family = claims.as_ref()
+        .and_then(|claims| claims.fam)
+        .or_else(|| session.get::<Uuid>("refresh_family"));
+
+    if let Some(claims) = claims {
+        revocations.revoke(&pool, &claims).await?;
+    }
+    if let Some(family) = family {
+        RefreshToken::revoke_family(&pool, family).await?;
+    }
+
     session.destroy();
 
     Ok(Json(("User successfully logged out".to_string(),)))
 }
 
-/// 
+/// POST route signing the caller out on every device. Revokes every access token issued to them so
+/// far, every refresh token, and every session signed in as them.
+async fn signout_everywhere(
+    State(pool): State<DbPool>,
+    State(revocations): State<RevocationList>,
+    State(sessions): State<SessionBackend>,
+    auth: AuthUser,
+    mut session: WritableSession
+) -> ApiResponse<(String,)> {
+    debug!("POST request received on /signout/all route");
+
+    revocations.revoke_all(&pool, auth.user_id).await?;
+    RefreshToken::revoke_for_user(&pool, auth.user_id).await?;
+    sessions.delete_for_user(auth.user_id).await?;
+
+    session.destroy();
+
+    info!("User {} signed out everywhere", auth.user_id);
+    Ok(Json(("User successfully logged out on every device".to_string(),)))
+}
+
+/// PUT route for creating a new user account and authenticating the caller's session as them. The
+/// session is moved to a fresh id as it is signed in, and its cart becomes the user's.
 async fn signup(
+    State(pool): State<DbPool>,
+    State(sessions): State<SessionBackend>,
     mut session: WritableSession, 
     Json(payload): Json<UserAuth>
 ) -> ApiResponse<UserData> {
@@ -299,88 +478,1091 @@ async fn signup(
         Err(_) => return Err(AppError::as_response(StatusCode::BAD_REQUEST, "Input validation failed")),
     };
 
-    match User::insert(payload.email.as_str(), payload.password.as_str())    {
-        Some(user) => {
+    match User::insert(&pool, payload.email.as_str(), payload.password.as_str()).await {
+        Ok(user) => {
             debug!("User request successfully fulfilled, user created, sending JSON response");
+            Cart::merge(&pool, session.id(), user.uuid.unwrap()).await?;
+            regenerate_session(&sessions, &mut session).await?;
             session.insert("user_id", user.uuid).expect("Failed to set user auth session");
             Ok(Json(UserData::from(user)))
         },
-        None => Err(AppError::as_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to create user"))
+        Err(db::Error::UniqueViolation(_)) => Err(AppError::with_code(StatusCode::CONFLICT, "email_taken", "A user with that email already exists").to_response()),
+        Err(e) => Err(e.into()),
     }
 }
 
 /// GET route for getting information related to the specified user associated with the uuid in the
-/// route's path. First checks the provided JWT to verify it is valid, and that it is assigned to
-/// user that is attempting to access the route., or that the user specified in the JWT has 'admin'
-/// role.
+/// route's path. First authenticates the caller by session or JWT, and then checks that the caller
+/// is the user being requested, or that the caller's role has the 'users.read' permission.
 async fn get_user(
-    session: ReadableSession, 
+    State(pool): State<DbPool>,
+    auth: AuthUser,
     Path(params): Path<HashMap<String, String>>
 ) -> ApiResponse<UserData> {
     debug!("GET request received on /user/:uuid route");
 
     let path_user_id = parse_path_uuid(params, "id")?;
-    let session_user_id = session.get::<Uuid>("user_id");
 
-    trace!("Fallback role check for 'admin'");
-    if session_user_id.is_none() || session_user_id.unwrap() != path_user_id {
-        return Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+    if auth.user_id != path_user_id {
+        trace!("Fallback permission check for 'users.read'");
+        if !has_permission(&pool, auth.user_id, Permission::ReadUsers).await? {
+            return Err(AppError::as_response(StatusCode::FORBIDDEN, "Forbidden"));
+        }
     }
 
-    match User::get(path_user_id) {
-        Some(user) => {
+    match User::get(&pool, path_user_id).await {
+        Ok(user) => {
             debug!("User request successfully fulfilled, sending JSON response");
             Ok(Json(UserData::from(user)))
         },
-        None => Err(AppError::as_response(StatusCode::NOT_FOUND, "User not found")),
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "User not found")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// GET route listing the devices signed in as the specified user, most recently active first, with
+/// the user agent and ip each was last used from. The caller's own session is flagged as current.
+/// Like `get_user`, the caller must be the user being requested or have the 'users.read'
+/// permission.
+async fn get_user_sessions(
+    State(pool): State<DbPool>,
+    State(sessions): State<SessionBackend>,
+    auth: AuthUser,
+    session: ReadableSession,
+    OriginalUri(uri): OriginalUri,
+    Path(params): Path<HashMap<String, String>>,
+    pagination: Option<Query<Pagination>>
+) -> ApiResponseWithHeaders<Page<SessionData>> {
+    debug!("GET request received on /user/:uuid/sessions route");
+
+    let path_user_id = parse_path_uuid(params, "id")?;
+    let Query(pagination) = pagination.unwrap_or_default();
+
+    if auth.user_id != path_user_id {
+        trace!("Fallback permission check for 'users.read'");
+        if !has_permission(&pool, auth.user_id, Permission::ReadUsers).await? {
+            return Err(AppError::as_response(StatusCode::FORBIDDEN, "Forbidden"));
+        }
+    }
+
+    // every backend loads the user's sessions whole, so they are paged in memory
+    let page = pagination.page_of(
+        sessions.get_for_user(path_user_id).await?,
+        |s| (s.last_activity, s.id.clone()),
+        false
+    )?
+    .map(|s| SessionData::new(s, session.id()));
+
+    debug!("Sessions request successfully fulfilled, sending JSON page response");
+    Ok((AppendHeaders(page.link_header(&uri)), Json(page)))
+}
+
+/// DELETE route signing the specified user out of one of their sessions. Only the user themselves
+/// can revoke their sessions. Session ids may contain '/', which must be sent percent encoded.
+async fn delete_user_session(
+    State(sessions): State<SessionBackend>,
+    auth: AuthUser,
+    mut session: WritableSession,
+    Path(params): Path<HashMap<String, String>>
+) -> Result<StatusCode, ErrorResponse> {
+    debug!("DELETE request received on /user/:uuid/sessions/:sid route");
+
+    let sid = params.get("sid")
+        .cloned()
+        .ok_or(AppError::as_response(StatusCode::NOT_FOUND, "Not Found"))?;
+    let path_user_id = parse_path_uuid(params, "id")?;
+
+    if auth.user_id != path_user_id {
+        return Err(AppError::as_response(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+
+    match sessions.delete_for_user_by_id(path_user_id, &sid).await {
+        Ok(_) => (),
+        Err(db::Error::NotFound) => return Err(AppError::as_response(StatusCode::NOT_FOUND, "Session not found")),
+        Err(e) => return Err(e.into()),
+    }
+
+    // the session layer would otherwise store the caller's own session again after the response
+    if session.id() == sid {
+        session.destroy();
+    }
+
+    info!("Session revoked by user {}", auth.user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET route listing the roles by name, along with the permissions granted to each.
+async fn get_roles(
+    State(pool): State<DbPool>,
+    _admin: RequirePermission<ManageRoles>,
+    OriginalUri(uri): OriginalUri,
+    pagination: Option<Query<Pagination>>
+) -> ApiResponseWithHeaders<Page<RoleData>> {
+    debug!("GET request received on /admin/roles route");
+
+    let Query(pagination) = pagination.unwrap_or_default();
+    let page = Role::get_all_with_permissions(&pool, pagination).await?
+        .map(RoleData::from);
+
+    debug!("Roles request successfully fulfilled, sending JSON page response");
+    Ok((AppendHeaders(page.link_header(&uri)), Json(page)))
+}
+
+/// PUT route granting the permission in the path to the role in the path.
+async fn grant_permission(
+    State(pool): State<DbPool>,
+    admin: RequirePermission<ManageRoles>,
+    Path(params): Path<HashMap<String, String>>
+) -> Result<StatusCode, ErrorResponse> {
+    debug!("PUT request received on /admin/roles/:uuid/permissions/:permission route");
+
+    let role_id = parse_path_uuid(params.clone(), "id")?;
+    let permission = parse_path_permission(params, "permission")?;
+
+    Role::grant(&pool, role_id, permission).await?;
+
+    info!("Permission {} granted to role {} by user {}", permission, role_id, admin.user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE route revoking the permission in the path from the role in the path.
+async fn revoke_permission(
+    State(pool): State<DbPool>,
+    admin: RequirePermission<ManageRoles>,
+    Path(params): Path<HashMap<String, String>>
+) -> Result<StatusCode, ErrorResponse> {
+    debug!("DELETE request received on /admin/roles/:uuid/permissions/:permission route");
+
+    let role_id = parse_path_uuid(params.clone(), "id")?;
+    let permission = parse_path_permission(params, "permission")?;
+
+    Role::revoke(&pool, role_id, permission).await?;
+
+    info!("Permission {} revoked from role {} by user {}", permission, role_id, admin.user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET route listing the api's token signing keys, the active one and those still in their grace
+/// period.
+async fn get_keys(
+    State(pool): State<DbPool>,
+    _admin: RequirePermission<ManageKeys>
+) -> ApiResponse<Vec<KeyData>> {
+    debug!("GET request received on /admin/keys route");
+
+    let keys = JwtSigningKey::get_live(&pool).await?;
+
+    debug!("Keys request successfully fulfilled, sending JSON array response");
+    Ok(Json(keys.into_iter().map(KeyData::from).collect()))
+}
+
+/// POST route rotating the token signing key. New tokens are signed with a freshly generated key,
+/// while the previous key keeps verifying the tokens it signed until its grace period ends.
+async fn rotate_keys(
+    State(pool): State<DbPool>,
+    State(key_ring): State<KeyRing>,
+    admin: RequirePermission<ManageKeys>
+) -> ApiResponse<Vec<KeyData>> {
+    debug!("POST request received on /admin/keys/rotate route");
+
+    match key_ring.rotate(&pool).await {
+        Ok(key) => info!("Signing key rotated to {} by user {}", key.kid, admin.user_id),
+        Err(KeyError::Database(e)) => return Err(e.into()),
+        Err(KeyError::Unsupported(algorithm)) => return Err(AppError::with_code(
+            StatusCode::CONFLICT, "rotation_unsupported", format!("{:?} keys must be rotated through JWT_PRIVATE_KEY_PATH", algorithm)
+        ).to_response()),
+        Err(e) => {
+            error!("Failed to rotate signing key: {}", e);
+            return Err(AppError::as_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"));
+        },
     }
+
+    let keys = JwtSigningKey::get_live(&pool).await?;
+    Ok(Json(keys.into_iter().map(KeyData::from).collect()))
 }
 
+/// GET route for a single item. The item's version is sent as its ETag, which must be echoed in
+/// the If-Match header of any later PUT, PATCH or DELETE on the item.
 async fn get_item(
+    State(pool): State<DbPool>,
     _session: ReadableSession, 
     Path(params): Path<HashMap<String, String>>
-) -> ApiResponse<Deal> {
+) -> ApiResponseWithHeaders<Deal> {
     debug!("GET request received on /item/:uuid route");
 
     let item_id = parse_path_uuid(params, "id")?;
 
-    match Deal::get(item_id) {
-        Some(item) => {
+    match Deal::get(&pool, item_id).await {
+        Ok(item) => {
             debug!("Item request successfully fulfilled, sending JSON response");
-            Ok(Json(item))
+            Ok((
+                AppendHeaders(vec!((ETAG.to_string(), item.etag()))),
+                Json(item),
+            ))
         },
-        None => Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not found")),
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not found")),
+        Err(e) => Err(e.into()),
     }
 }
 
 async fn create_item(
-    _session: ReadableSession, 
-    Json(payload): Json<Deal>
+    State(pool): State<DbPool>,
+    _editor: RequirePermission<ManageDeals>,
+    Json(payload): Json<CreateDeal>
 ) -> ApiResponse<Deal> {
-    debug!("PUT request received on /item route");
+    debug!("POST request received on /item route");
+
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+    let item = Deal::insert(&pool, payload).await?;
+
+    debug!("Item request successfully fulfilled, item created, sending JSON response");
+    Ok(Json(item))
+}
+
+/// PUT route replacing every editable field of an item. Requires the 'deals.write' permission, and
+/// the If-Match header must carry the item's current ETag.
+async fn replace_item(
+    State(pool): State<DbPool>,
+    _editor: RequirePermission<ManageDeals>,
+    IfMatch(expected_version): IfMatch,
+    Path(params): Path<HashMap<String, String>>,
+    Json(payload): Json<CreateDeal>
+) -> ApiResponseWithHeaders<Deal> {
+    debug!("PUT request received on /item/:uuid route");
+
+    let item_id = parse_path_uuid(params, "id")?;
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+
+    let item = Deal::update(&pool, item_id, expected_version, DealChanges::from(payload)).await?;
+
+    debug!("Item request successfully fulfilled, item replaced, sending JSON response");
+    Ok((
+        AppendHeaders(vec!((ETAG.to_string(), item.etag()))),
+        Json(item),
+    ))
+}
+
+/// PATCH route merging the supplied fields into an item, fields missing from the body are left
+/// untouched. Requires the 'deals.write' permission, and the If-Match header must carry the item's
+/// current ETag.
+async fn update_item(
+    State(pool): State<DbPool>,
+    _editor: RequirePermission<ManageDeals>,
+    IfMatch(expected_version): IfMatch,
+    Path(params): Path<HashMap<String, String>>,
+    Json(payload): Json<DealChanges>
+) -> ApiResponseWithHeaders<Deal> {
+    debug!("PATCH request received on /item/:uuid route");
+
+    let item_id = parse_path_uuid(params, "id")?;
+
+    if payload.is_empty() {
+        return Err(AppError::as_response(StatusCode::UNPROCESSABLE_ENTITY, "No fields to update"));
+    }
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+
+    let item = Deal::update(&pool, item_id, expected_version, payload).await?;
+
+    debug!("Item request successfully fulfilled, item updated, sending JSON response");
+    Ok((
+        AppendHeaders(vec!((ETAG.to_string(), item.etag()))),
+        Json(item),
+    ))
+}
+
+/// DELETE route for an item. Requires the 'deals.write' permission, and the If-Match header must
+/// carry the item's current ETag.
+async fn delete_item(
+    State(pool): State<DbPool>,
+    _editor: RequirePermission<ManageDeals>,
+    IfMatch(expected_version): IfMatch,
+    Path(params): Path<HashMap<String, String>>
+) -> Result<StatusCode, ErrorResponse> {
+    debug!("DELETE request received on /item/:uuid route");
+
+    let item_id = parse_path_uuid(params, "id")?;
+
+    Deal::delete(&pool, item_id, expected_version).await?;
+
+    debug!("Item request successfully fulfilled, item deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET route for the stock of an item, how many units are on hand, reserved for carts, and
+/// available to buy.
+async fn get_item_stock(
+    State(pool): State<DbPool>,
+    Path(params): Path<HashMap<String, String>>
+) -> ApiResponse<StockData> {
+    debug!("GET request received on /item/:uuid/stock route");
+
+    let item_id = parse_path_uuid(params, "id")?;
 
-    match payload.insert() {
-        Some(item) => {
-            debug!("Item request successfully fulfilled, item created, sending JSON response");
-            Ok(Json(item))
+    match Inventory::get(&pool, item_id).await {
+        Ok(stock) => {
+            debug!("Stock request successfully fulfilled, sending JSON response");
+            Ok(Json(StockData::from(stock)))
         },
-        None => Err(AppError::as_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create item"))
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not found")),
+        Err(e) => Err(e.into()),
     }
 }
 
+/// PUT route setting how many units of an item are on hand. Requires the 'deals.write'
+/// permission. The units on hand can't be set below the units reserved for carts.
+async fn set_item_stock(
+    State(pool): State<DbPool>,
+    _editor: RequirePermission<ManageDeals>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(payload): Json<UpdateStock>
+) -> ApiResponse<StockData> {
+    debug!("PUT request received on /item/:uuid/stock route");
+
+    let item_id = parse_path_uuid(params, "id")?;
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+
+    match Inventory::set_on_hand(&pool, item_id, payload.on_hand).await {
+        Ok(stock) => {
+            debug!("Stock request successfully fulfilled, stock updated, sending JSON response");
+            Ok(Json(StockData::from(stock)))
+        },
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not found")),
+        Err(db::Error::InvalidData(_)) => Err(AppError::as_response(StatusCode::CONFLICT, "Fewer units than are reserved")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// GET route listing every item, the newest first, or the items filed under the category in the
+/// category query parameter or any category below it. Paginated by cursor, see `Pagination`, with
+/// the next and prev pages linked in the Link header.
 async fn get_items(
+    State(pool): State<DbPool>,
     _session: ReadableSession,
+    OriginalUri(uri): OriginalUri,
+    Query(query): Query<ItemQuery>,
     pagination: Option<Query<Pagination>>
-) -> ApiResponse<Items> {
+) -> ApiResponseWithHeaders<Page<Deal>> {
     debug!("GET request received on /items route");
 
     let Query(pagination) = pagination.unwrap_or_default();
 
-    match Deal::get_all(pagination) {
-        Some(deals) => {
-            debug!("Items request successfully fulfilled, sending JSON array response");
-            Ok(Json(Items { items: deals, }))
+    let page = match Deal::get_all(&pool, query.category, pagination).await {
+        Ok(page) => page,
+        Err(db::Error::NotFound) => return Err(AppError::as_response(StatusCode::NOT_FOUND, "Category not found")),
+        Err(e) => return Err(e.into()),
+    };
+
+    debug!("Items request successfully fulfilled, sending JSON page response");
+    Ok((AppendHeaders(page.link_header(&uri)), Json(page)))
+}
+
+/// GET route searching the deals, see `ItemSearch` for the query parameters. Paginated by cursor
+/// like /item/all, a cursor only pages through the results of the search and sort it came from.
+async fn search_items(
+    State(pool): State<DbPool>,
+    _session: ReadableSession,
+    OriginalUri(uri): OriginalUri,
+    Query(search): Query<ItemSearch>,
+    pagination: Option<Query<Pagination>>
+) -> ApiResponseWithHeaders<Page<Deal>> {
+    debug!("GET request received on /item/search route");
+
+    search.validate().map_err(|e| AppError::from(e).to_response())?;
+    let Query(pagination) = pagination.unwrap_or_default();
+
+    let page = match Deal::search(&pool, search, pagination).await {
+        Ok(page) => page,
+        Err(db::Error::NotFound) => return Err(AppError::as_response(StatusCode::NOT_FOUND, "Category not found")),
+        Err(e) => return Err(e.into()),
+    };
+
+    debug!("Search request successfully fulfilled, sending JSON page response");
+    Ok((AppendHeaders(page.link_header(&uri)), Json(page)))
+}
+
+/// GET route for the categories an item is filed under.
+async fn get_item_categories(
+    State(pool): State<DbPool>,
+    Path(params): Path<HashMap<String, String>>
+) -> ApiResponse<ItemCategories> {
+    debug!("GET request received on /item/:uuid/categories route");
+
+    let item_id = parse_path_uuid(params, "id")?;
+
+    match Category::get_for_deal(&pool, item_id).await {
+        Ok(categories) => {
+            debug!("Item categories request successfully fulfilled, sending JSON response");
+            Ok(Json(ItemCategories { categories }))
+        },
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not found")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// PUT route filing an item under exactly the categories in the body, in place of the ones it was
+/// under. Requires the 'deals.write' permission.
+async fn set_item_categories(
+    State(pool): State<DbPool>,
+    _editor: RequirePermission<ManageDeals>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(payload): Json<SetItemCategories>
+) -> ApiResponse<ItemCategories> {
+    debug!("PUT request received on /item/:uuid/categories route");
+
+    let item_id = parse_path_uuid(params, "id")?;
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+
+    match Category::set_for_deal(&pool, item_id, payload.category_ids).await {
+        Ok(categories) => {
+            debug!("Item categories request successfully fulfilled, categories set, sending JSON response");
+            Ok(Json(ItemCategories { categories }))
+        },
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not found")),
+        Err(db::Error::ForeignKeyViolation(_)) => Err(AppError::as_response(StatusCode::UNPROCESSABLE_ENTITY, "Category not found")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// GET route for the whole category tree, top level categories with the ones below them nested
+/// in their children.
+async fn get_categories(
+    State(pool): State<DbPool>,
+    _session: ReadableSession
+) -> ApiResponse<Categories> {
+    debug!("GET request received on /categories route");
+
+    let categories = Category::get_all(&pool).await?;
+
+    debug!("Categories request successfully fulfilled, sending JSON tree response");
+    Ok(Json(Categories::from(categories)))
+}
+
+/// POST route creating a category, under the category in parent_id or else at the top level.
+/// Requires the 'categories.write' permission.
+async fn create_category(
+    State(pool): State<DbPool>,
+    admin: RequirePermission<ManageCategories>,
+    Json(payload): Json<CreateCategory>
+) -> ApiResponse<Category> {
+    debug!("POST request received on /admin/categories route");
+
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+
+    match Category::insert(&pool, payload).await {
+        Ok(category) => {
+            info!("Category {} created by user {}", category.uuid, admin.user_id);
+            Ok(Json(category))
+        },
+        Err(db::Error::ForeignKeyViolation(_)) => Err(AppError::as_response(StatusCode::UNPROCESSABLE_ENTITY, "Parent category not found")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// PATCH route renaming a category or moving it under another one, or to the top level with a
+/// null parent_id. Requires the 'categories.write' permission.
+async fn update_category(
+    State(pool): State<DbPool>,
+    admin: RequirePermission<ManageCategories>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(payload): Json<CategoryChanges>
+) -> ApiResponse<Category> {
+    debug!("PATCH request received on /admin/categories/:uuid route");
+
+    let category_id = parse_path_uuid(params, "id")?;
+
+    if payload.is_empty() {
+        return Err(AppError::as_response(StatusCode::UNPROCESSABLE_ENTITY, "No fields to update"));
+    }
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+
+    match Category::update(&pool, category_id, payload).await {
+        Ok(category) => {
+            info!("Category {} updated by user {}", category.uuid, admin.user_id);
+            Ok(Json(category))
+        },
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "Category not found")),
+        Err(db::Error::ForeignKeyViolation(_)) => Err(AppError::as_response(StatusCode::UNPROCESSABLE_ENTITY, "Parent category not found")),
+        Err(db::Error::InvalidData(_)) => Err(AppError::as_response(StatusCode::UNPROCESSABLE_ENTITY, "A category can't be moved under itself")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// DELETE route for a category, which must have no subcategories left. Items filed under it are
+/// taken out of it. Requires the 'categories.write' permission.
+async fn delete_category(
+    State(pool): State<DbPool>,
+    admin: RequirePermission<ManageCategories>,
+    Path(params): Path<HashMap<String, String>>
+) -> Result<StatusCode, ErrorResponse> {
+    debug!("DELETE request received on /admin/categories/:uuid route");
+
+    let category_id = parse_path_uuid(params, "id")?;
+
+    match Category::delete(&pool, category_id).await {
+        Ok(()) => {
+            info!("Category {} deleted by user {}", category_id, admin.user_id);
+            Ok(StatusCode::NO_CONTENT)
+        },
+        Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::NOT_FOUND, "Category not found")),
+        Err(db::Error::ForeignKeyViolation(_)) => Err(AppError::as_response(StatusCode::CONFLICT, "Category has subcategories")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// GET route for the caller's cart, along with the current name and price of every deal in it.
+/// Signed in callers get their own cart, anonymous callers the cart of their session.
+async fn get_cart(
+    State(pool): State<DbPool>,
+    auth: Option<AuthUser>,
+    session: ReadableSession
+) -> ApiResponse<CartData> {
+    debug!("GET request received on /cart route");
+
+    let lines = Cart::get_lines(&pool, &cart_owner(auth, &session)).await?;
+
+    debug!("Cart request successfully fulfilled, sending JSON response");
+    Ok(Json(CartData::try_from(lines)?))
+}
+
+/// POST route adding a quantity of a deal to the caller's cart, on top of any already in it. The
+/// units are reserved for the cart, a 409 with the remaining units is sent if too few are left.
+async fn add_cart_item(
+    State(pool): State<DbPool>,
+    auth: Option<AuthUser>,
+    session: ReadableSession,
+    Json(payload): Json<AddCartItem>
+) -> ApiResponse<CartData> {
+    debug!("POST request received on /cart/items route");
+
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+    let owner = cart_owner(auth, &session);
+
+    match Cart::add_item(&pool, &owner, payload.deal_id, payload.quantity).await {
+        Ok(_) => (),
+        Err(db::Error::ForeignKeyViolation(_)) => return Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not found")),
+        Err(e) => return Err(e.into()),
+    }
+
+    let lines = Cart::get_lines(&pool, &owner).await?;
+
+    debug!("Cart request successfully fulfilled, item added, sending JSON response");
+    Ok(Json(CartData::try_from(lines)?))
+}
+
+/// PUT route setting how many of the deal in the path the caller's cart holds.
+async fn update_cart_item(
+    State(pool): State<DbPool>,
+    auth: Option<AuthUser>,
+    session: ReadableSession,
+    Path(params): Path<HashMap<String, String>>,
+    Json(payload): Json<UpdateCartItem>
+) -> ApiResponse<CartData> {
+    debug!("PUT request received on /cart/items/:uuid route");
+
+    let deal_id = parse_path_uuid(params, "id")?;
+    payload.validate().map_err(|e| AppError::from(e).to_response())?;
+    let owner = cart_owner(auth, &session);
+
+    match Cart::set_quantity(&pool, &owner, deal_id, payload.quantity).await {
+        Ok(_) => (),
+        Err(db::Error::NotFound) => return Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not in cart")),
+        Err(e) => return Err(e.into()),
+    }
+
+    let lines = Cart::get_lines(&pool, &owner).await?;
+
+    debug!("Cart request successfully fulfilled, item updated, sending JSON response");
+    Ok(Json(CartData::try_from(lines)?))
+}
+
+/// DELETE route removing the deal in the path from the caller's cart.
+async fn remove_cart_item(
+    State(pool): State<DbPool>,
+    auth: Option<AuthUser>,
+    session: ReadableSession,
+    Path(params): Path<HashMap<String, String>>
+) -> ApiResponse<CartData> {
+    debug!("DELETE request received on /cart/items/:uuid route");
+
+    let deal_id = parse_path_uuid(params, "id")?;
+    let owner = cart_owner(auth, &session);
+
+    match Cart::remove_item(&pool, &owner, deal_id).await {
+        Ok(_) => (),
+        Err(db::Error::NotFound) => return Err(AppError::as_response(StatusCode::NOT_FOUND, "Item not in cart")),
+        Err(e) => return Err(e.into()),
+    }
+
+    let lines = Cart::get_lines(&pool, &owner).await?;
+
+    debug!("Cart request successfully fulfilled, item removed, sending JSON response");
+    Ok(Json(CartData::try_from(lines)?))
+}
+
+/// DELETE route emptying the caller's cart.
+async fn clear_cart(
+    State(pool): State<DbPool>,
+    auth: Option<AuthUser>,
+    session: ReadableSession
+) -> Result<StatusCode, ErrorResponse> {
+    debug!("DELETE request received on /cart route");
+
+    Cart::clear(&pool, &cart_owner(auth, &session)).await?;
+
+    debug!("Cart request successfully fulfilled, cart cleared");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST route placing an order for everything in the caller's cart. The name and price of every
+/// deal are copied into the order as they are now, the units are taken out of stock, and the cart
+/// is emptied. A 409 with the remaining units of the first short deal is sent if stock ran out.
+async fn place_order(
+    State(pool): State<DbPool>,
+    auth: AuthUser
+) -> ApiResponse<OrderData> {
+    debug!("POST request received on /orders route");
+
+    match Order::place(&pool, auth.user_id).await? {
+        Some(order) => {
+            debug!("Order request successfully fulfilled, order placed, sending JSON response");
+            Ok(Json(OrderData::try_from(order)?))
+        },
+        None => Err(AppError::as_response(StatusCode::UNPROCESSABLE_ENTITY, "Cart is empty")),
+    }
+}
+
+/// GET route listing the caller's orders, most recent first. Callers with the 'orders.read'
+/// permission may list the orders of the user in the user_id query parameter instead, or of every
+/// user with all=true. Leaving both out always lists the caller's own orders.
+async fn get_orders(
+    State(pool): State<DbPool>,
+    auth: AuthUser,
+    OriginalUri(uri): OriginalUri,
+    Query(query): Query<OrderQuery>,
+    pagination: Option<Query<Pagination>>
+) -> ApiResponseWithHeaders<Page<OrderData>> {
+    debug!("GET request received on /orders route");
+
+    let Query(pagination) = pagination.unwrap_or_default();
+
+    let user = match (query.user_id, query.all) {
+        (Some(_), true) => return Err(AppError::as_response(StatusCode::BAD_REQUEST, "user_id and all can't be combined")),
+        (None, false) => Some(auth.user_id),
+        (Some(user), false) if user == auth.user_id => Some(user),
+        (user, _) => {
+            trace!("Fallback permission check for 'orders.read'");
+            if !has_permission(&pool, auth.user_id, Permission::ReadOrders).await? {
+                return Err(AppError::as_response(StatusCode::FORBIDDEN, "Forbidden"));
+            }
+            user
         },
-        None => Err(AppError::as_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to get items")),
+    };
+
+    let page = Order::get_all(&pool, user, pagination).await?
+        .try_map(OrderData::try_from)?;
+
+    debug!("Orders request successfully fulfilled, sending JSON page response");
+    Ok((AppendHeaders(page.link_header(&uri)), Json(page)))
+}
+
+/// GET route for the order with the uuid in the route's path. Only the user who placed it, or
+/// callers with the 'orders.read' permission, may see an order, anyone else gets a 404.
+async fn get_order(
+    State(pool): State<DbPool>,
+    auth: AuthUser,
+    Path(params): Path<HashMap<String, String>>
+) -> ApiResponse<OrderData> {
+    debug!("GET request received on /orders/:uuid route");
+
+    let order_id = parse_path_uuid(params, "id")?;
+
+    let order = match Order::get(&pool, order_id).await {
+        Ok(order) => order,
+        Err(db::Error::NotFound) => return Err(AppError::as_response(StatusCode::NOT_FOUND, "Order not found")),
+        Err(e) => return Err(e.into()),
+    };
+
+    if order.0.user_id != auth.user_id {
+        trace!("Fallback permission check for 'orders.read'");
+        if !has_permission(&pool, auth.user_id, Permission::ReadOrders).await? {
+            return Err(AppError::as_response(StatusCode::NOT_FOUND, "Order not found"));
+        }
+    }
+
+    debug!("Order request successfully fulfilled, sending JSON response");
+    Ok(Json(OrderData::try_from(order)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{ body::Body, extract::ConnectInfo, http::{ header::{ AUTHORIZATION, CONTENT_TYPE, COOKIE }, Method, Request } };
+    use serde_json::{ json, Value };
+    use std::{ net::Ipv4Addr, sync::{ atomic::{ AtomicU32, Ordering }, Once } };
+    use tower::ServiceExt;
+
+    static SECRETS: Once = Once::new();
+
+    /// Sets the secrets the api needs to boot, unless the environment already has them. Every test
+    /// must use the same ones, the signing key is registered in the database shared by all of them.
+    pub fn set_test_secrets() {
+        SECRETS.call_once(|| {
+            let secrets = [
+                ("SESSION_SECRET", "test-session-secret-".repeat(4)),
+                ("JWT_SECRET", "dGVzdC1qd3Qtc2VjcmV0LWZvci10aGUtcm91dGVyLXRlc3Rz".to_string()),
+                ("NONCE_SECRET", "test-nonce-secret".to_string()),
+                ("JWT_KEY_ENCRYPTION_KEY", "dGVzdC1rZXktZW5jcnlwdGlvbi1rZXktMzItYnl0ZXM=".to_string()),
+            ];
+            for (name, value) in secrets {
+                if env::var(name).is_err() {
+                    env::set_var(name, value);
+                }
+            }
+        });
+    }
+    static NEXT_IP: AtomicU32 = AtomicU32::new(1);
+
+    /// A client of the api driving the router directly, keeping its session cookie between requests
+    /// like a browser would, and sending its bearer token once it has one
+    struct Client {
+        app: Router,
+        pool: DbPool,
+        cookie: Option<String>,
+        bearer: Option<String>,
+    }
+
+    impl Client {
+        /// A client of an api over the database at DATABASE_URL, with sessions kept in postgres
+        async fn new() -> Self {
+            set_test_secrets();
+
+            let pool = create_pool();
+            let state = AppState {
+                pool: pool.clone(),
+                key_ring: KeyRing::load(&pool).await.unwrap(),
+                revocations: RevocationList::load(&pool).await.unwrap(),
+                sessions: SessionBackend::Postgres(PostgresSessionStore::new(pool.clone(), SessionPolicy::default())),
+            };
+
+            Client {
+                app: app(state),
+                pool,
+                cookie: None,
+                bearer: None,
+            }
+        }
+
+        async fn send(&mut self, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(cookie) = &self.cookie {
+                request = request.header(COOKIE, cookie);
+            }
+            if let Some(token) = &self.bearer {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let body = match body {
+                Some(body) => {
+                    request = request.header(CONTENT_TYPE, "application/json");
+                    Body::from(body.to_string())
+                },
+                None => Body::empty(),
+            };
+
+            // every request comes from an ip of its own, keeping the rate limiter out of the way
+            let ip = Ipv4Addr::from(0x0a00_0000 + NEXT_IP.fetch_add(1, Ordering::Relaxed));
+            let mut request = request.body(body).unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from((ip, 443))));
+
+            let response = self.app.clone().oneshot(request).await.unwrap();
+            let cookie = response.headers()
+                .get_all(SET_COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .find(|value| value.starts_with("sid="));
+            if let Some(cookie) = cookie {
+                self.cookie = cookie.split(';').next().map(str::to_string);
+            }
+
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+        }
+
+        async fn get(&mut self, uri: &str) -> (StatusCode, Value) {
+            self.send(Method::GET, uri, None).await
+        }
+
+        async fn post(&mut self, uri: &str, body: Value) -> (StatusCode, Value) {
+            self.send(Method::POST, uri, Some(body)).await
+        }
+
+        /// Signs up a user with a random email, returning the user's uuid, email and password
+        async fn new_user(&self) -> (Uuid, String, String) {
+            let email = format!("router-test-{}@example.com", Uuid::new_v4());
+            let password = Uuid::new_v4().to_string();
+            let user = User::insert(&self.pool, &email, &password).await.unwrap();
+
+            (user.uuid.unwrap(), email, password)
+        }
+
+        /// Signs the client in as a new user, returning the user's uuid
+        async fn sign_in_new_user(&mut self) -> Uuid {
+            let (user, email, password) = self.new_user().await;
+            let nonce = self.nonce().await;
+            let (status, _) = self.signin(&email, &password, &nonce).await;
+            assert_eq!(status, StatusCode::OK);
+
+            user
+        }
+
+        /// Fetches a nonce for the client's session, starting one if it has none yet
+        async fn nonce(&mut self) -> String {
+            let (status, body) = self.get("/api/v1/auth/nonce").await;
+            assert_eq!(status, StatusCode::OK);
+
+            body["nonce"].as_str().unwrap().to_string()
+        }
+
+        async fn signin(&mut self, email: &str, password: &str, nonce: &str) -> (StatusCode, Value) {
+            self.post("/api/v1/auth/signin", json!({ "email": email, "password": password, "nonce": nonce })).await
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn signout_revokes_the_refresh_token_family() {
+        for bearer in [true, false] {
+            let mut client = Client::new().await;
+            let (_, email, password) = client.new_user().await;
+
+            let nonce = client.nonce().await;
+            let (status, auth) = client.signin(&email, &password, &nonce).await;
+            assert_eq!(status, StatusCode::OK);
+            let refresh_token = auth["refresh_token"].as_str().unwrap().to_string();
+            if bearer {
+                // without the session cookie, so the family is read from the token
+                client.bearer = auth["token"].as_str().map(str::to_string);
+                client.cookie = None;
+            }
+
+            let (status, _) = client.get("/api/v1/auth/signout").await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (status, _) = client.post("/api/v1/auth/refresh", json!({ "refresh_token": refresh_token })).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    /// Places an order of a single new deal for the user, returning the order's uuid
+    async fn place_order(pool: &DbPool, user: Uuid) -> Uuid {
+        let deal = Deal::insert(pool, CreateDeal {
+            name: "Router test deal".to_string(),
+            image: "https://example.com/deal.png".to_string(),
+            price: Money::new(500, "USD".parse().unwrap()),
+            description: "A deal bought by the router tests".to_string(),
+        }).await.unwrap();
+        let deal_id = deal.uuid.unwrap();
+        Inventory::set_on_hand(pool, deal_id, 1).await.unwrap();
+        Cart::add_item(pool, &CartOwner::User(user), deal_id, 1).await.unwrap();
+
+        Order::place(pool, user).await.unwrap().unwrap().0.uuid
+    }
+
+    /// Gives the user the admin role, and with it the 'orders.read' permission
+    async fn make_admin(pool: &DbPool, user: Uuid) {
+        use crate::db::models::schema::{ roles, users };
+        use diesel::prelude::*;
+
+        with_connection(pool, move |conn| {
+            let admin = roles::table
+                .filter(roles::name.eq("admin"))
+                .select(roles::uuid)
+                .first::<Uuid>(conn)?;
+
+            diesel::update(users::table.filter(users::uuid.eq(user)))
+                .set(users::role.eq(admin))
+                .execute(conn)
+        }).await.unwrap();
+    }
+
+    fn listed_orders(page: &Value) -> Vec<(Uuid, Uuid)> {
+        page["items"].as_array().unwrap()
+            .iter()
+            .map(|order| (
+                order["id"].as_str().unwrap().parse().unwrap(),
+                order["user_id"].as_str().unwrap().parse().unwrap(),
+            ))
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn order_listings_default_to_the_callers_own_orders() {
+        let mut admin = Client::new().await;
+        let admin_id = admin.sign_in_new_user().await;
+        make_admin(&admin.pool, admin_id).await;
+        let mut customer = Client::new().await;
+        let customer_id = customer.sign_in_new_user().await;
+
+        let admin_order = place_order(&admin.pool, admin_id).await;
+        let customer_order = place_order(&customer.pool, customer_id).await;
+
+        let (status, page) = admin.get("/api/v1/orders").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(listed_orders(&page), vec![(admin_order, admin_id)]);
+
+        let (status, page) = admin.get("/api/v1/orders?all=true&limit=100").await;
+        assert_eq!(status, StatusCode::OK);
+        let listed = listed_orders(&page);
+        assert!(listed.contains(&(admin_order, admin_id)));
+        assert!(listed.contains(&(customer_order, customer_id)));
+
+        let (status, page) = admin.get(&format!("/api/v1/orders?user_id={}", customer_id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(listed_orders(&page), vec![(customer_order, customer_id)]);
+
+        let (status, _) = admin.get(&format!("/api/v1/orders?user_id={}&all=true", customer_id)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, page) = customer.get("/api/v1/orders").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(listed_orders(&page), vec![(customer_order, customer_id)]);
+
+        let (status, _) = customer.get("/api/v1/orders?all=true").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    /// Moves the nonce with the tag back past the end of its 5 minute window
+    async fn expire_nonce(pool: &DbPool, tag: &str) {
+        use crate::db::models::schema::nonces;
+        use diesel::prelude::*;
+
+        let tag = tag.to_string();
+        let expired = with_connection(pool, move |conn| {
+            let nonce = nonces::table
+                .load::<Nonce>(conn)?
+                .into_iter()
+                .find(|nonce| nonce.tag() == tag)
+                .ok_or(db::Error::NotFound)?;
+
+            diesel::update(nonces::table.find(&nonce.session_id))
+                .set(nonces::created_at.eq(nonce.created_at - 6 * 60))
+                .execute(conn)
+                .map_err(db::Error::from)
+        }).await;
+
+        assert_eq!(expired.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn nonces_can_only_be_answered_once() {
+        let mut client = Client::new().await;
+        let (_, email, password) = client.new_user().await;
+
+        let (status, _) = client.signin(&email, &password, "no nonce was asked for").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let nonce = client.nonce().await;
+        let (status, _) = client.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = client.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn failed_signins_consume_the_nonce() {
+        let mut client = Client::new().await;
+        let (_, email, password) = client.new_user().await;
+
+        let nonce = client.nonce().await;
+        let (status, _) = client.signin(&email, "not the password", &nonce).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = client.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let nonce = client.nonce().await;
+        let (status, _) = client.signin(&email, &password, "0badc0de").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = client.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let nonce = client.nonce().await;
+        let (status, _) = client.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn nonces_only_work_from_their_session() {
+        let mut owner = Client::new().await;
+        let mut thief = Client::new().await;
+        let (_, email, password) = owner.new_user().await;
+
+        let nonce = owner.nonce().await;
+        let (status, _) = thief.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // a session with a nonce of its own can't answer with another session's tag either
+        thief.nonce().await;
+        let (status, _) = thief.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = owner.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn expired_nonces_are_refused() {
+        let mut client = Client::new().await;
+        let (_, email, password) = client.new_user().await;
+
+        let nonce = client.nonce().await;
+        expire_nonce(&client.pool, &nonce).await;
+        let (status, _) = client.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let nonce = client.nonce().await;
+        let (status, _) = client.signin(&email, &password, &nonce).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn sessions_and_roles_are_paginated() {
+        let mut devices = Vec::new();
+        let (user, email, password) = Client::new().await.new_user().await;
+        for _ in 0..3 {
+            let mut device = Client::new().await;
+            let nonce = device.nonce().await;
+            let (status, _) = device.signin(&email, &password, &nonce).await;
+            assert_eq!(status, StatusCode::OK);
+            devices.push(device);
+        }
+        let client = &mut devices[2];
+
+        let uri = format!("/api/v1/user/{}/sessions?limit=2&total=true", user);
+        let (status, first) = client.get(&uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["items"].as_array().unwrap().len(), 2);
+        assert_eq!(first["total"], 3);
+        assert_eq!(first["items"][0]["current"], true);
+
+        let (status, last) = client.get(&format!("{}&cursor={}", uri, first["next"].as_str().unwrap())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(last["items"].as_array().unwrap().len(), 1);
+        assert!(last["next"].is_null());
+        assert_ne!(last["items"][0]["id"], first["items"][0]["id"]);
+        assert_ne!(last["items"][0]["id"], first["items"][1]["id"]);
+
+        make_admin(&client.pool, user).await;
+        let (status, roles) = client.get("/api/v1/admin/roles?limit=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(roles["items"].as_array().unwrap().len(), 1);
+        assert_eq!(roles["items"][0]["name"], "admin");
+        assert!(roles["next"].is_string());
     }
 }
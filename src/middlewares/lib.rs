@@ -1,13 +1,17 @@
 use axum::{
     BoxError,
     error_handling::HandleErrorLayer,
-    http::{ Method, StatusCode },
+    extract::{ ConnectInfo, State },
+    http::{ HeaderMap, Method, Request, StatusCode },
+    middleware::{ self, Next },
+    response::{ IntoResponse, Response },
     Router,
 };
-use axum_sessions::{SessionLayer, SameSite};
+use axum_sessions::{ SessionHandle, SessionLayer, SameSite };
 use dotenvy::dotenv;
-use http::{ HeaderValue, header::{ HeaderName, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE } };
-use std::{ env, time::Duration };
+use http::{ HeaderValue, header::{ HeaderName, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, USER_AGENT } };
+use log::warn;
+use std::{ env, net::{ IpAddr, SocketAddr }, sync::Arc, time::Duration };
 use tower::{ ServiceBuilder, timeout::TimeoutLayer };
 use tower_governor::{ errors::display_error, governor::GovernorConfigBuilder, GovernorLayer };
 use tower_http::{
@@ -18,22 +22,28 @@ use tower_http::{
 };
 use tracing::Level;
 
-use crate::RequestId;
-use crate::sessionstore::PostgresSessionStore;
+use crate::{ AppError, RequestId };
+use crate::sessionstore::{ outage_reported, watch_for_outages, SessionBackend };
 
-pub fn with_middleware_stack(service: Router) -> Router {
+pub fn with_middleware_stack(service: Router, sessions: SessionBackend) -> Router {
     // security
     let cors_layer = CorsLayer::new()
         .allow_methods([
             Method::GET, 
             Method::POST,
-            Method::PUT
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
         ])
         .allow_headers([
             ACCEPT,
             ACCEPT_ENCODING,
             AUTHORIZATION,
             CONTENT_TYPE,
+            IF_MATCH,
+        ])
+        .expose_headers([
+            ETAG,
         ])
         .allow_origin([
             "http://::1:3000".parse::<HeaderValue>().unwrap(),
@@ -96,29 +106,182 @@ pub fn with_middleware_stack(service: Router) -> Router {
 
     // session
     dotenv().ok();
-    let db_url = env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set");
     let secret = env::var("SESSION_SECRET")
         .expect("SESSION_SECRET must be set");
 
-    let store = PostgresSessionStore::new(db_url);
+    let policy = sessions.policy();
 
-    let session_layer = SessionLayer::new(store, secret.as_bytes())
+    let session_layer = SessionLayer::new(sessions, secret.as_bytes())
         .with_cookie_name("sid")
         .with_cookie_domain("127.0.0.1")
         .with_same_site_policy(SameSite::Strict)
-        .with_session_ttl(Some(std::time::Duration::from_secs(60 * 60 * 8)))
+        .with_session_ttl(policy.idle_timeout.to_std().ok())
         .with_secure(true);
+
+    let client_layer = middleware::from_fn_with_state(Arc::new(get_trusted_proxies()), record_client);
     
     // data
     let compression_layer = CompressionLayer::new().gzip(true);
 
     service
         .layer(compression_layer)
+        .layer(client_layer)
+        .layer(middleware::from_fn(halt_on_store_outage))
         .layer(session_layer)
+        .layer(middleware::from_fn(catch_store_outages))
         .layer(trace_layer)
         .layer(request_id_layer)
         .layer(timeout_layer)
         .layer(governor_layer)
         .layer(cors_layer)
-}
\ No newline at end of file
+}
+
+/// Answers with a 503 when the session store couldn't reach the database
+///
+/// Runs outside the session layer, replacing the whole response, including any cookie the layer
+/// set, so callers keep their session cookie through an outage instead of being handed a fresh
+/// session.
+async fn catch_store_outages<B>(request: Request<B>, next: Next<B>) -> Response {
+    match watch_for_outages(next.run(request)).await {
+        (_, Some(e)) => AppError::from(e).into_response(),
+        (response, None) => response,
+    }
+}
+
+/// Stops requests whose session couldn't be loaded because of a database outage before they reach
+/// their handler, which would otherwise run with an empty session
+async fn halt_on_store_outage<B>(request: Request<B>, next: Next<B>) -> Response {
+    if outage_reported() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// The longest user agent kept on a session, longer ones are truncated
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// Records the caller's user agent and ip on their session
+///
+/// Runs inside the session layer, so the values are saved to the session's row in the sessions
+/// table along with the rest of the session when the response is sent. The session is only marked
+/// as changed when a value differs from the one already recorded.
+async fn record_client<B>(
+    State(trusted_proxies): State<Arc<Vec<IpAddr>>>,
+    request: Request<B>,
+    next: Next<B>
+) -> Response {
+    if let Some(session_handle) = request.extensions().get::<SessionHandle>() {
+        let peer = request.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        let user_agent = request.headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(MAX_USER_AGENT_LEN).collect::<String>());
+
+        let ip = peer.map(|peer| client_ip(peer, request.headers(), &trusted_proxies));
+
+        let mut session = session_handle.write().await;
+        if let Some(user_agent) = user_agent {
+            session.insert("user_agent", user_agent).ok();
+        }
+        if let Some(ip) = ip {
+            session.insert("ip", ip.to_string()).ok();
+        }
+    }
+
+    next.run(request).await
+}
+
+/// Resolves the ip of the client behind the connection
+///
+/// `X-Forwarded-For` is only believed when the connection comes from a trusted proxy. Its entries
+/// are walked from the right, each one appended by the proxy in front of it, and the first that
+/// isn't a trusted proxy is the client. Anything else falls back to the connection's own address,
+/// so clients can't spoof their ip by sending the header themselves.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    let forwarded_for = headers.get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<&str>>();
+
+    let mut client = peer;
+    for hop in forwarded_for.into_iter().rev() {
+        let Ok(hop) = hop.parse::<IpAddr>() else {
+            break;
+        };
+
+        client = hop;
+        if !trusted_proxies.contains(&hop) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Gets the proxies trusted to set `X-Forwarded-For` from the comma separated TRUSTED_PROXIES env
+/// variable, none by default
+fn get_trusted_proxies() -> Vec<IpAddr> {
+    dotenv().ok();
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .filter_map(|proxy| match proxy.parse::<IpAddr>() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                warn!("Ignoring invalid TRUSTED_PROXIES entry {}", proxy);
+                None
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let headers = forwarded_for("203.0.113.7");
+
+        assert_eq!(client_ip(ip("198.51.100.1"), &headers, &[]), ip("198.51.100.1"));
+        assert_eq!(client_ip(ip("198.51.100.1"), &headers, &[ip("10.0.0.1")]), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn takes_the_first_untrusted_hop_from_the_right() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let headers = forwarded_for("192.0.2.55, 203.0.113.7, 10.0.0.2");
+
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &trusted), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn stops_at_malformed_hops() {
+        let trusted = [ip("10.0.0.1")];
+
+        assert_eq!(client_ip(ip("10.0.0.1"), &forwarded_for("203.0.113.7, junk"), &trusted), ip("10.0.0.1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), &HeaderMap::new(), &trusted), ip("10.0.0.1"));
+    }
+}
@@ -0,0 +1,77 @@
+use axum::{
+    async_trait,
+    extract::{ FromRef, FromRequestParts },
+    http::{ header::AUTHORIZATION, request::Parts, StatusCode },
+    Extension,
+};
+use axum_sessions::SessionHandle;
+use log::debug;
+use uuid::Uuid;
+
+use crate::db::DbPool;
+use crate::jwt::{ decrypt_jwt, Claims, KeyRing, RevocationList, TokenError };
+use crate::net::{ AppError, ErrorResponse };
+
+/// Extractor for the authenticated caller of a route
+/// 
+/// Resolves the caller's user uuid from the session cookie if the session has been signed in,
+/// falling back to a JWT in the `Authorization: Bearer` header for API clients. The token may be
+/// minted by any enabled issuer registered in the jwt_issuers table. Requests with neither, or
+/// with a token that fails verification, are rejected with 401 Unauthorized.
+/// 
+/// `claims` holds the verified claims when the caller was authenticated by a bearer token, and is
+/// `None` for session authenticated callers.
+/// 
+/// The session is only read, so this must be listed before any `WritableSession` argument of the
+/// handler, otherwise the read waits on the handler's own write lock.
+/// 
+/// # Examples
+/// 
+/// ```
+/// async fn get_profile(AuthUser { user_id, .. }: AuthUser, ...) -> ... {
+///     User::get(&pool, user_id).await?;
+/// }
+/// ```
+pub struct AuthUser {
+    pub user_id: Uuid,
+    pub claims: Option<Claims>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    DbPool: FromRef<S>,
+    KeyRing: FromRef<S>,
+    RevocationList: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Ok(Extension(session_handle)) = Extension::<SessionHandle>::from_request_parts(parts, state).await {
+            let session_user_id = session_handle.read().await.get::<Uuid>("user_id");
+
+            if let Some(user_id) = session_user_id {
+                return Ok(AuthUser { user_id, claims: None });
+            }
+        }
+
+        let token = parts.headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized"))?;
+
+        let pool = DbPool::from_ref(state);
+        let key_ring = KeyRing::from_ref(state);
+        let revocations = RevocationList::from_ref(state);
+
+        match decrypt_jwt(&pool, &key_ring, &revocations, token).await {
+            Ok(claims) => Ok(AuthUser { user_id: claims.sub, claims: Some(claims) }),
+            Err(TokenError::Database(e)) => Err(e.into()),
+            Err(e) => {
+                debug!("Rejected bearer token: {}", e);
+                Err(AppError::as_response(StatusCode::UNAUTHORIZED, "Unauthorized"))
+            },
+        }
+    }
+}
@@ -0,0 +1,9 @@
+pub mod auth_user;
+pub mod require_permission;
+pub mod require_role;
+
+pub use self::{
+    auth_user::*,
+    require_permission::*,
+    require_role::*,
+};
@@ -0,0 +1,92 @@
+use axum::{
+    async_trait,
+    extract::{ FromRef, FromRequestParts },
+    http::{ request::Parts, StatusCode },
+};
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+use crate::db::{ has_permission, DbPool, Permission };
+use crate::jwt::{ KeyRing, RevocationList };
+use crate::middlewares::AuthUser;
+use crate::net::{ AppError, ErrorResponse };
+
+/// A permission that can be required of the caller by `RequirePermission`
+/// 
+/// Implementors are zero sized markers naming one of the `Permission` variants, so the required
+/// permission can be stated in a handler's signature.
+pub trait PermissionName {
+    const PERMISSION: Permission;
+}
+
+/// Marker for `Permission::ManageDeals`
+pub struct ManageDeals;
+
+impl PermissionName for ManageDeals {
+    const PERMISSION: Permission = Permission::ManageDeals;
+}
+
+/// Marker for `Permission::ManageRoles`
+pub struct ManageRoles;
+
+impl PermissionName for ManageRoles {
+    const PERMISSION: Permission = Permission::ManageRoles;
+}
+
+/// Marker for `Permission::ManageKeys`
+pub struct ManageKeys;
+
+impl PermissionName for ManageKeys {
+    const PERMISSION: Permission = Permission::ManageKeys;
+}
+
+/// Marker for `Permission::ManageCategories`
+pub struct ManageCategories;
+
+impl PermissionName for ManageCategories {
+    const PERMISSION: Permission = Permission::ManageCategories;
+}
+
+/// Extractor guarding a route behind a permission
+/// 
+/// Authenticates the caller like `AuthUser`, then checks the permissions granted to their role in
+/// the role_permissions table. Unauthenticated callers are rejected with 401 Unauthorized, and
+/// callers whose role lacks the permission with 403 Forbidden.
+/// 
+/// # Examples
+/// 
+/// ```
+/// async fn create_item(_editor: RequirePermission<ManageDeals>, ...) -> ... {
+///     ...
+/// }
+/// ```
+pub struct RequirePermission<P: PermissionName> {
+    pub user_id: Uuid,
+    _permission: PhantomData<P>,
+}
+
+#[async_trait]
+impl<S, P> FromRequestParts<S> for RequirePermission<P>
+where
+    DbPool: FromRef<S>,
+    KeyRing: FromRef<S>,
+    RevocationList: FromRef<S>,
+    S: Send + Sync,
+    P: PermissionName,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AuthUser { user_id, .. } = AuthUser::from_request_parts(parts, state).await?;
+        let pool = DbPool::from_ref(state);
+
+        if has_permission(&pool, user_id, P::PERMISSION).await? {
+            Ok(Self {
+                user_id,
+                _permission: PhantomData,
+            })
+        } else {
+            Err(AppError::as_response(StatusCode::FORBIDDEN, "Forbidden"))
+        }
+    }
+}
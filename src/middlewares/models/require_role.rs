@@ -0,0 +1,72 @@
+use axum::{
+    async_trait,
+    extract::{ FromRef, FromRequestParts },
+    http::{ request::Parts, StatusCode },
+};
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+use crate::db::{ self, DbPool, Role };
+use crate::jwt::{ KeyRing, RevocationList };
+use crate::middlewares::AuthUser;
+use crate::net::{ AppError, ErrorResponse };
+
+/// A role that can be required of the caller by `RequireRole`
+/// 
+/// Implementors are zero sized markers naming a row of the roles table, the name is matched
+/// against the role the database assigns to the caller.
+pub trait RoleName {
+    const NAME: &'static str;
+}
+
+/// The administrator role, required for managing the catalog
+pub struct Admin;
+
+impl RoleName for Admin {
+    const NAME: &'static str = "admin";
+}
+
+/// Extractor guarding a route behind a role
+/// 
+/// Authenticates the caller like `AuthUser`, then resolves their role through the users -> roles
+/// join rather than trusting the role claimed in a token, so role changes take effect
+/// immediately. Unauthenticated callers are rejected with 401 Unauthorized, and callers holding
+/// any other role with 403 Forbidden.
+/// 
+/// # Examples
+/// 
+/// ```
+/// async fn create_item(_admin: RequireRole<Admin>, ...) -> ... {
+///     ...
+/// }
+/// ```
+pub struct RequireRole<R: RoleName> {
+    pub user_id: Uuid,
+    _role: PhantomData<R>,
+}
+
+#[async_trait]
+impl<S, R> FromRequestParts<S> for RequireRole<R>
+where
+    DbPool: FromRef<S>,
+    KeyRing: FromRef<S>,
+    RevocationList: FromRef<S>,
+    S: Send + Sync,
+    R: RoleName,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AuthUser { user_id, .. } = AuthUser::from_request_parts(parts, state).await?;
+        let pool = DbPool::from_ref(state);
+
+        match Role::get_for_user(&pool, user_id).await {
+            Ok(role) if role.name == R::NAME => Ok(Self {
+                user_id,
+                _role: PhantomData,
+            }),
+            Ok(_) | Err(db::Error::NotFound) => Err(AppError::as_response(StatusCode::FORBIDDEN, "Forbidden")),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
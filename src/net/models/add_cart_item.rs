@@ -0,0 +1,16 @@
+use serde::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+
+/// The body of a request adding a deal to the caller's cart, the quantity defaults to 1
+#[derive(Deserialize, Validate)]
+pub struct AddCartItem {
+    pub deal_id: Uuid,
+    #[serde(default = "one")]
+    #[validate(range(min = 1, max = 999, message = "must be between 1 and 999"))]
+    pub quantity: i32,
+}
+
+fn one() -> i32 {
+    1
+}
@@ -1,7 +1,9 @@
-use axum::{ extract::Json, http::StatusCode };
+use axum::{ extract::Json, http::StatusCode, response::{ IntoResponse, Response } };
 use log::error;
+use validator::ValidationErrors;
 
-use crate::net::models::error_json::ErrorJson;
+use crate::db;
+use crate::net::models::error_json::{ ErrorJson, StockShortage };
 
 pub type ErrorResponse = (StatusCode, Json<ErrorJson>);
 
@@ -11,23 +13,119 @@ pub struct AppError {
 }
 
 impl AppError {
+    /// Creates an error whose machine readable code is derived from the status, e.g. a 404 is
+    /// reported with the code "not_found". Use `with_code` when clients need to tell apart
+    /// failures that share a status.
     pub fn new<S: Into<String>>(status: StatusCode, message: S) -> AppError {
+        let code = status.canonical_reason()
+            .unwrap_or("error")
+            .to_lowercase()
+            .replace(' ', "_");
+
+        AppError::with_code(status, code, message)
+    }
+
+    pub fn with_code<C: Into<String>, S: Into<String>>(status: StatusCode, code: C, message: S) -> AppError {
         Self {
-            status: status,
-            err: ErrorJson::new(message.into()),
+            status,
+            err: ErrorJson::new(code.into(), message.into()),
         }
     }
 
     pub fn to_response(&self) -> ErrorResponse {
         self.print_error();
-        return (self.status, Json(self.err.clone()));
+        (self.status, Json(self.err.clone()))
     }
 
     pub fn as_response<S: Into<String>>(status: StatusCode, message: S) -> ErrorResponse {
-        return AppError::new(status, message.into()).to_response();
+        AppError::new(status, message.into()).to_response()
     }
 
     pub fn print_error(&self) {
-        error!("Request failed with code {} and message: \"{}\"", self.status, self.err.message);
+        error!("Request failed with code {} ({}) and message: \"{}\"", self.status, self.err.code, self.err.message);
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        self.to_response().into_response()
+    }
+}
+
+/// Maps repository failures to the status and stable error code reported to clients. Constraint
+/// details and driver messages are only logged, the client receives a generic message.
+impl From<db::Error> for AppError {
+    fn from(e: db::Error) -> Self {
+        error!("Database operation failed: {}", e);
+
+        match e {
+            db::Error::NotFound => AppError::with_code(
+                StatusCode::NOT_FOUND, "not_found", "Not Found"
+            ),
+            db::Error::UniqueViolation(_) => AppError::with_code(
+                StatusCode::CONFLICT, "already_exists", "A matching record already exists"
+            ),
+            db::Error::SerializationFailure => AppError::with_code(
+                StatusCode::CONFLICT, "serialization_failure", "The request conflicted with a concurrent update, please retry"
+            ),
+            db::Error::VersionMismatch => AppError::with_code(
+                StatusCode::PRECONDITION_FAILED, "version_mismatch", "The record was modified since it was last read"
+            ),
+            db::Error::InsufficientStock { deal, remaining } => {
+                let mut app_error = AppError::with_code(
+                    StatusCode::CONFLICT, "insufficient_stock", "Not enough of the item is in stock"
+                );
+                app_error.err.shortage = Some(Box::new(StockShortage { deal_id: deal, remaining }));
+                app_error
+            },
+            db::Error::CurrencyMismatch => AppError::with_code(
+                StatusCode::UNPROCESSABLE_ENTITY, "currency_mismatch", "Items priced in different currencies can't be combined"
+            ),
+            db::Error::ForeignKeyViolation(_) => AppError::with_code(
+                StatusCode::UNPROCESSABLE_ENTITY, "invalid_reference", "The request references a record that does not exist"
+            ),
+            db::Error::InvalidData(_) => AppError::with_code(
+                StatusCode::UNPROCESSABLE_ENTITY, "invalid_data", "The request contains invalid data"
+            ),
+            db::Error::Connection(_) => AppError::with_code(
+                StatusCode::SERVICE_UNAVAILABLE, "database_unavailable", "The service is temporarily unavailable"
+            ),
+            db::Error::Internal(_) => AppError::with_code(
+                StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal Server Error"
+            ),
+        }
+    }
+}
+
+impl From<db::Error> for ErrorResponse {
+    fn from(e: db::Error) -> Self {
+        AppError::from(e).to_response()
+    }
+}
+
+/// Maps failed input validation to a 422 listing the failed rules of every offending field, e.g.
+/// `{ "code": "validation_failed", ..., "fields": { "price": ["must be greater than 0"] } }`
+impl From<ValidationErrors> for AppError {
+    fn from(e: ValidationErrors) -> Self {
+        let fields = e.field_errors()
+            .into_iter()
+            .map(|(field, errors)| {
+                let messages = errors.iter()
+                    .map(|error| error.message
+                        .as_ref()
+                        .unwrap_or(&error.code)
+                        .to_string()
+                    )
+                    .collect();
+
+                (field.to_string(), messages)
+            })
+            .collect();
+
+        let mut app_error = AppError::with_code(
+            StatusCode::UNPROCESSABLE_ENTITY, "validation_failed", "Input validation failed"
+        );
+        app_error.err.fields = Some(fields);
+        app_error
     }
 }
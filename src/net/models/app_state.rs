@@ -0,0 +1,43 @@
+use axum::extract::FromRef;
+
+use crate::db::DbPool;
+use crate::jwt::{ KeyRing, RevocationList };
+use crate::sessionstore::SessionBackend;
+
+/// The shared state handed to every handler
+/// 
+/// Holds the resources that live for the lifetime of the server, the database connection pool,
+/// the ring of keys our tokens are signed with, the list of revoked tokens and the store sessions
+/// are kept in. Handlers pull out only the pieces they need through `State<T>` thanks to the
+/// `FromRef` impls below.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: DbPool,
+    pub key_ring: KeyRing,
+    pub revocations: RevocationList,
+    pub sessions: SessionBackend,
+}
+
+impl FromRef<AppState> for DbPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for KeyRing {
+    fn from_ref(state: &AppState) -> Self {
+        state.key_ring.clone()
+    }
+}
+
+impl FromRef<AppState> for RevocationList {
+    fn from_ref(state: &AppState) -> Self {
+        state.revocations.clone()
+    }
+}
+
+impl FromRef<AppState> for SessionBackend {
+    fn from_ref(state: &AppState) -> Self {
+        state.sessions.clone()
+    }
+}
@@ -0,0 +1,46 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db::{ self, CartItem, Deal, Money };
+
+/// The caller's cart as sent to clients, with the current name and price of every deal in it. The
+/// total is null when the cart is empty, or when a deal in it has since been repriced in another
+/// currency than the rest.
+#[derive(Serialize)]
+pub struct CartData {
+    pub items: Vec<CartLineData>,
+    pub total: Option<Money>,
+}
+
+/// A deal in the cart, with the price of the whole line
+#[derive(Serialize)]
+pub struct CartLineData {
+    pub deal_id: Uuid,
+    pub name: String,
+    pub image: String,
+    pub price: Money,
+    pub quantity: i32,
+    pub subtotal: Money,
+}
+
+impl TryFrom<Vec<(CartItem, Deal)>> for CartData {
+    type Error = db::Error;
+
+    fn try_from(lines: Vec<(CartItem, Deal)>) -> Result<Self, Self::Error> {
+        let items = lines.into_iter()
+            .map(|(item, deal)| Ok(CartLineData {
+                deal_id: item.deal_id,
+                subtotal: deal.price.checked_mul(item.quantity)?,
+                name: deal.name,
+                image: deal.image,
+                price: deal.price,
+                quantity: item.quantity,
+            }))
+            .collect::<Result<Vec<CartLineData>, db::Error>>()?;
+
+        Ok(CartData {
+            total: Money::sum(items.iter().map(|item| item.subtotal)).ok().flatten(),
+            items,
+        })
+    }
+}
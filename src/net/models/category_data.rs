@@ -0,0 +1,86 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::db::Category;
+
+/// A category as sent to clients in the category tree, with the categories below it
+#[derive(Serialize, Debug)]
+pub struct CategoryTree {
+    pub uuid: Uuid,
+    pub name: String,
+    pub children: Vec<CategoryTree>,
+}
+
+/// The whole category tree, the top level categories with their children nested in them, siblings
+/// in the order they were loaded in
+#[derive(Serialize)]
+pub struct Categories {
+    pub categories: Vec<CategoryTree>,
+}
+
+/// The categories an item is filed under
+#[derive(Serialize)]
+pub struct ItemCategories {
+    pub categories: Vec<Category>,
+}
+
+impl From<Vec<Category>> for Categories {
+    fn from(categories: Vec<Category>) -> Self {
+        let mut children = HashMap::<Option<Uuid>, Vec<Category>>::new();
+        for category in categories {
+            children.entry(category.parent_id).or_default().push(category);
+        }
+
+        Self {
+            categories: grow(&mut children, None),
+        }
+    }
+}
+
+/// Takes the children of the parent out of the map, each with its own children nested in it
+fn grow(children: &mut HashMap<Option<Uuid>, Vec<Category>>, parent: Option<Uuid>) -> Vec<CategoryTree> {
+    children.remove(&parent)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|category| CategoryTree {
+            children: grow(children, Some(category.uuid)),
+            uuid: category.uuid,
+            name: category.name,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(id: u128, parent: Option<u128>, name: &str) -> Category {
+        Category {
+            uuid: Uuid::from_u128(id),
+            parent_id: parent.map(Uuid::from_u128),
+            name: name.to_string(),
+            created_at: chrono::NaiveDateTime::default(),
+        }
+    }
+
+    #[test]
+    fn nests_children_under_their_parents() {
+        let tree = Categories::from(vec![
+            category(3, Some(1), "Boots"),
+            category(1, None, "Clothing"),
+            category(4, None, "Garden"),
+            category(2, Some(1), "Shirts"),
+            category(5, Some(3), "Winter boots"),
+        ]).categories;
+
+        fn names(trees: &[CategoryTree]) -> Vec<&str> {
+            trees.iter().map(|tree| tree.name.as_str()).collect()
+        }
+
+        assert_eq!(names(&tree), vec!["Clothing", "Garden"]);
+        assert_eq!(names(&tree[0].children), vec!["Boots", "Shirts"]);
+        assert_eq!(names(&tree[0].children[0].children), vec!["Winter boots"]);
+        assert!(tree[1].children.is_empty());
+    }
+}
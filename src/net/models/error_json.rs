@@ -1,14 +1,32 @@
 use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
 
 #[derive(Clone, Serialize)]
 pub struct ErrorJson {
+    pub code: String,
     pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<HashMap<String, Vec<String>>>,
+    #[serde(flatten)]
+    pub shortage: Option<Box<StockShortage>>,
+}
+
+/// The deal a request ran out of stock of, and how many of its units are left. Boxed in
+/// `ErrorJson`, as it is rarely present.
+#[derive(Clone, Serialize)]
+pub struct StockShortage {
+    pub deal_id: Uuid,
+    pub remaining: i32,
 }
 
 impl ErrorJson {
-    pub fn new(message: String) -> ErrorJson {
+    pub fn new(code: String, message: String) -> ErrorJson {
         Self {
-            message: message,
+            code,
+            message,
+            fields: None,
+            shortage: None,
         }
     }
 }
@@ -0,0 +1,55 @@
+use axum::{ async_trait, extract::FromRequestParts, http::{ header::IF_MATCH, request::Parts, StatusCode } };
+
+use crate::net::models::app_error::{ AppError, ErrorResponse };
+
+/// Extractor for the If-Match precondition header used for optimistic concurrency
+/// 
+/// Writes to versioned resources must state which version of the resource they were based on, by
+/// echoing the ETag they last read in the If-Match header. The extracted value is the expected
+/// version, or None if the client sent `If-Match: *` to overwrite whatever is stored.
+/// 
+/// Requests without the header are rejected with 428 Precondition Required, and headers that are
+/// not an ETag this API handed out are rejected with 412 Precondition Failed.
+/// 
+/// # Examples
+/// 
+/// ```
+/// async fn update_item(IfMatch(expected_version): IfMatch, ...) -> ... {
+///     Deal::update(&pool, id, expected_version, changes).await?;
+/// }
+/// ```
+pub struct IfMatch(pub Option<i32>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let value = parts.headers.get(IF_MATCH)
+            .ok_or(AppError::with_code(
+                StatusCode::PRECONDITION_REQUIRED,
+                "precondition_required",
+                "The If-Match header is required to modify this resource",
+            ).to_response())?
+            .to_str()
+            .unwrap_or_default()
+            .trim();
+
+        if value == "*" {
+            return Ok(IfMatch(None));
+        }
+
+        value.trim_start_matches("W/")
+            .trim_matches('"')
+            .parse::<i32>()
+            .map(|version| IfMatch(Some(version)))
+            .or(Err(AppError::with_code(
+                StatusCode::PRECONDITION_FAILED,
+                "version_mismatch",
+                "The If-Match header does not match a known version of this resource",
+            ).to_response()))
+    }
+}
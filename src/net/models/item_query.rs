@@ -0,0 +1,9 @@
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// The filters of the item listing. category keeps the items filed under the category or any
+/// category below it.
+#[derive(Deserialize, Default)]
+pub struct ItemQuery {
+    pub category: Option<Uuid>,
+}
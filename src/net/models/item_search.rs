@@ -0,0 +1,87 @@
+use serde::Deserialize;
+use uuid::Uuid;
+use validator::{ Validate, ValidationError };
+
+use crate::db::Currency;
+
+/// The query of a deal search, every part of which is optional
+///
+/// q is matched against the name and description of the deals with postgres full text search, in
+/// the syntax of web search engines: "quoted phrases", `or` and `-excluded` terms. The price range
+/// is inclusive and in minor units, so it is only accepted along with a currency. category
+/// keeps the deals filed under the category or any category below it. Combined with `Pagination`
+/// for the page of results.
+#[derive(Deserialize, Validate, Clone, Debug, Default)]
+#[validate(schema(function = "validate_price_range"))]
+pub struct ItemSearch {
+    #[validate(length(min = 1, max = 256, message = "must be between 1 and 256 characters"))]
+    pub q: Option<String>,
+    #[validate(range(min = 0, message = "must be at least 0"))]
+    pub min_price: Option<i64>,
+    #[validate(range(min = 0, message = "must be at least 0"))]
+    pub max_price: Option<i64>,
+    pub currency: Option<Currency>,
+    pub category: Option<Uuid>,
+    #[serde(default)]
+    pub sort: ItemSort,
+}
+
+/// The order of the search results, ties are broken by uuid so pages never overlap
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemSort {
+    /// Best match first, or `Newest` when there are no search terms
+    #[default]
+    Relevance,
+    PriceAsc,
+    PriceDesc,
+    Name,
+    Newest,
+}
+
+/// Amounts in minor units only compare within a currency, a price bound without one would match
+/// e.g. 5000 yen along with $50.00
+fn validate_price_range(search: &ItemSearch) -> Result<(), ValidationError> {
+    match (search.min_price, search.max_price) {
+        (Some(_), _) | (_, Some(_)) if search.currency.is_none() => {
+            let mut error = ValidationError::new("currency");
+            error.message = Some("min_price and max_price must be paired with a currency".into());
+            Err(error)
+        },
+        (Some(min), Some(max)) if min > max => {
+            let mut error = ValidationError::new("range");
+            error.message = Some("min_price must not be greater than max_price".into());
+            Err(error)
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed_rules(search: &ItemSearch) -> Vec<String> {
+        match search.validate() {
+            Ok(()) => vec![],
+            Err(e) => e.field_errors()
+                .into_values()
+                .flatten()
+                .map(|error| error.code.to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn price_bounds_need_a_currency() {
+        let usd = Some("USD".parse().unwrap());
+
+        assert_eq!(failed_rules(&ItemSearch { min_price: Some(5000), ..ItemSearch::default() }), vec!["currency"]);
+        assert_eq!(failed_rules(&ItemSearch { max_price: Some(5000), ..ItemSearch::default() }), vec!["currency"]);
+        assert!(failed_rules(&ItemSearch { min_price: Some(5000), max_price: Some(9000), currency: usd, ..ItemSearch::default() }).is_empty());
+        assert!(failed_rules(&ItemSearch { currency: usd, ..ItemSearch::default() }).is_empty());
+        assert!(failed_rules(&ItemSearch::default()).is_empty());
+
+        assert_eq!(failed_rules(&ItemSearch { min_price: Some(9000), max_price: Some(5000), currency: usd, ..ItemSearch::default() }), vec!["range"]);
+    }
+}
@@ -0,0 +1,29 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::convert::From;
+
+use crate::db::JwtSigningKey;
+
+/// A signing key as shown to admins, never including the private key
+#[derive(Serialize)]
+pub struct KeyData {
+    pub kid: String,
+    pub algorithm: String,
+    pub active: bool,
+    pub created_at: NaiveDateTime,
+    pub retired_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl From<JwtSigningKey> for KeyData {
+    fn from(key: JwtSigningKey) -> Self {
+        KeyData {
+            active: key.retired_at.is_none(),
+            kid: key.kid,
+            algorithm: key.algorithm,
+            created_at: key.created_at,
+            retired_at: key.retired_at,
+            expires_at: key.expires_at,
+        }
+    }
+}
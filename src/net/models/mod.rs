@@ -1,22 +1,53 @@
+pub mod add_cart_item;
 pub mod app_error;
+pub mod app_state;
+pub mod cart_data;
+pub mod category_data;
 pub mod error_json;
-pub mod items;
+pub mod if_match;
+pub mod item_query;
+pub mod item_search;
+pub mod key_data;
 pub mod nonce_payload;
+pub mod order_data;
+pub mod order_query;
 pub mod pagination;
 pub mod ports;
+pub mod refresh_auth;
 pub mod request_id;
+pub mod role_data;
+pub mod session_data;
+pub mod set_item_categories;
+pub mod stock_data;
+pub mod update_cart_item;
+pub mod update_stock;
 pub mod user_auth;
 pub mod user_auth_payload;
 pub mod user_data;
 
 pub use self::{
+    add_cart_item::*,
     app_error::*,
-    error_json::*,
-    items::*,
+    app_state::*,
+    cart_data::*,
+    category_data::*,
+    if_match::*,
+    item_query::*,
+    item_search::*,
+    key_data::*,
     nonce_payload::*,
+    order_data::*,
+    order_query::*,
     pagination::*,
     ports::*,
+    refresh_auth::*,
     request_id::*,
+    role_data::*,
+    session_data::*,
+    set_item_categories::*,
+    stock_data::*,
+    update_cart_item::*,
+    update_stock::*,
     user_auth::*,
     user_auth_payload::*,
     user_data::*,
@@ -12,11 +12,11 @@ pub struct NoncePayload {
 impl NoncePayload {
     pub fn new(nonce: String) -> Self {
         Self {
-            nonce: nonce,
+            nonce,
         }
     }
 
     pub fn as_response<S: Into<String>>(nonce: S) -> NonceResponse {
-        (StatusCode::UNAUTHORIZED, Json::from(Self::new(nonce.into())))
+        (StatusCode::OK, Json::from(Self::new(nonce.into())))
     }
 }
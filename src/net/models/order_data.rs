@@ -0,0 +1,48 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db::{ self, Money, Order, OrderLine };
+
+/// An order as sent to clients, with the deals it was placed for as they were bought
+#[derive(Serialize)]
+pub struct OrderData {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub lines: Vec<OrderLineData>,
+    pub total: Money,
+    pub created_at: NaiveDateTime,
+}
+
+/// A line of an order, with the price of the whole line. deal_id is null once the deal has been
+/// deleted.
+#[derive(Serialize)]
+pub struct OrderLineData {
+    pub deal_id: Option<Uuid>,
+    pub name: String,
+    pub price: Money,
+    pub quantity: i32,
+    pub subtotal: Money,
+}
+
+impl TryFrom<(Order, Vec<OrderLine>)> for OrderData {
+    type Error = db::Error;
+
+    fn try_from((order, lines): (Order, Vec<OrderLine>)) -> Result<Self, Self::Error> {
+        Ok(OrderData {
+            id: order.uuid,
+            user_id: order.user_id,
+            lines: lines.into_iter()
+                .map(|line| Ok(OrderLineData {
+                    deal_id: line.deal_id,
+                    subtotal: line.price.checked_mul(line.quantity)?,
+                    name: line.name,
+                    price: line.price,
+                    quantity: line.quantity,
+                }))
+                .collect::<Result<Vec<OrderLineData>, db::Error>>()?,
+            total: order.total,
+            created_at: order.created_at,
+        })
+    }
+}
@@ -0,0 +1,12 @@
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// The filters of an order listing, the caller's own orders when neither is given. Only callers
+/// with the 'orders.read' permission may list the orders of another user by user_id, or of every
+/// user with all=true.
+#[derive(Deserialize, Default)]
+pub struct OrderQuery {
+    pub user_id: Option<Uuid>,
+    #[serde(default)]
+    pub all: bool,
+}
@@ -1,11 +1,58 @@
-use serde::{ de, Deserialize, Deserializer };
+use axum::http::{ header::LINK, Uri };
+use data_encoding::BASE64URL_NOPAD;
+use serde::{ de::{ self, DeserializeOwned }, Deserialize, Deserializer, Serialize };
 use std::{ fmt, str::FromStr };
+use uuid::Uuid;
 
-#[derive(Clone, Debug, Deserialize)]
+use crate::db::Error;
+
+/// The most items a page may hold, larger limits are capped to it
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// How many items a page holds when the client doesn't say
+pub const DEFAULT_PAGE_SIZE: i64 = 10;
+
+/// The query parameters of every list endpoint
+///
+/// Lists are paginated by keyset, the first page is requested without a cursor and every other
+/// page with the next or prev cursor of the page next to it, see `Page`. Cursors are opaque to
+/// clients. limit is capped to `MAX_PAGE_SIZE`, and total=true adds the number of matching items
+/// to the page, which costs an extra query.
+#[derive(Clone, Debug, Deserialize, Default)]
 pub struct Pagination {
     #[serde(default, deserialize_with = "empty_string_as_none")]
-    offset: Option<i64>,
+    cursor: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
     limit: Option<i64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    total: Option<bool>,
+}
+
+/// Where a page starts, next to the item with the sort key `key` and id `id`
+///
+/// A cursor points after the item, or before it when `backward` is set. K is whatever the list is
+/// sorted by, the id breaks ties, the item's uuid unless it has none. Sent to clients base64url
+/// encoded, see `Cursor::encode`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Cursor<K, I = Uuid> {
+    #[serde(rename = "k")]
+    pub key: K,
+    pub id: I,
+    #[serde(rename = "b", default)]
+    pub backward: bool,
+}
+
+/// A page of a list, sent as `{ "items": [...], "next": "...", "prev": null }`
+///
+/// next and prev are the cursors of the pages after and before this one, null at either end of
+/// the list. total is only present when asked for.
+#[derive(Clone, Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
 }
 
 /// Serde deserialization decorator to map empty Strings to None,
@@ -23,20 +70,294 @@ where
 }
 
 impl Pagination {
-    pub fn get_offset(&self) -> i64 {
-        self.offset.unwrap_or(0)
+    pub fn new(cursor: Option<String>, limit: Option<i64>, total: bool) -> Self {
+        Self {
+            cursor,
+            limit,
+            total: Some(total),
+        }
     }
 
+    /// The number of items on the page, between 1 and `MAX_PAGE_SIZE`
     pub fn get_limit(&self) -> i64 {
-        self.limit.unwrap_or(10)
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Whether the client asked for the total number of items
+    pub fn wants_total(&self) -> bool {
+        self.total.unwrap_or(false)
+    }
+
+    /// Decodes the cursor the page starts at, `None` for the first page. Fails with
+    /// `Error::InvalidData` if the cursor wasn't issued for a list sorted by a K with I ids.
+    pub fn cursor<K: DeserializeOwned, I: DeserializeOwned>(&self) -> Result<Option<Cursor<K, I>>, Error> {
+        self.cursor.as_deref()
+            .map(Cursor::decode)
+            .transpose()
+    }
+
+    /// Builds the page from the rows a keyset query loaded for it
+    ///
+    /// The query must load up to `get_limit() + 1` rows past the cursor, in the order of the list
+    /// when going forward and in reverse when going backward, the extra row tells whether there is
+    /// another page beyond this one. `key` gives the sort key and id of a row for its cursor.
+    pub fn page<T, K: Serialize, I: Serialize>(
+        &self,
+        cursor: &Option<Cursor<K, I>>,
+        mut rows: Vec<T>,
+        key: impl Fn(&T) -> (K, I),
+        total: Option<i64>,
+    ) -> Page<T> {
+        let limit = self.get_limit() as usize;
+        let more = rows.len() > limit;
+        rows.truncate(limit);
+
+        let backward = cursor.as_ref().is_some_and(|cursor| cursor.backward);
+        if backward {
+            rows.reverse();
+        }
+
+        let cursor_at = |row: Option<&T>, backward: bool| row.map(|row| {
+            let (key, id) = key(row);
+            Cursor { key, id, backward }.encode()
+        });
+
+        // going forward there are items before the page if it was asked for by cursor, and after
+        // it if the query found more, going backward the other way round
+        let (before, after) = match backward {
+            false => (cursor.is_some(), more),
+            true => (more, true),
+        };
+
+        Page {
+            next: after.then(|| cursor_at(rows.last(), false)).flatten(),
+            prev: before.then(|| cursor_at(rows.first(), true)).flatten(),
+            items: rows,
+            total,
+        }
+    }
+
+    /// Builds the page from a whole list loaded in memory, for lists that can't be queried by
+    /// keyset, like the sessions kept by some session backends
+    ///
+    /// The rows are sorted here by `key`, ascending or descending, the id breaking ties, and the
+    /// page is then picked out like a keyset query would.
+    pub fn page_of<T, K, I>(
+        &self,
+        mut rows: Vec<T>,
+        key: impl Fn(&T) -> (K, I),
+        ascending: bool,
+    ) -> Result<Page<T>, Error>
+    where
+        K: Serialize + DeserializeOwned + Ord,
+        I: Serialize + DeserializeOwned + Ord,
+    {
+        let cursor = self.cursor::<K, I>()?;
+        let total = self.wants_total().then_some(rows.len() as i64);
+        let take = self.get_limit() as usize + 1;
+
+        rows.sort_by(|a, b| match ascending {
+            true => key(a).cmp(&key(b)),
+            false => key(b).cmp(&key(a)),
+        });
+
+        let rows = match &cursor {
+            None => rows.into_iter().take(take).collect(),
+            Some(cursor) => {
+                let position = |row: &T| {
+                    let (k, i) = key(row);
+                    let order = k.cmp(&cursor.key).then(i.cmp(&cursor.id));
+                    match ascending {
+                        true => order,
+                        false => order.reverse(),
+                    }
+                };
+
+                match cursor.backward {
+                    false => rows.into_iter()
+                        .filter(|row| position(row).is_gt())
+                        .take(take)
+                        .collect(),
+                    true => rows.into_iter()
+                        .rev()
+                        .filter(|row| position(row).is_lt())
+                        .take(take)
+                        .collect(),
+                }
+            },
+        };
+
+        Ok(self.page(&cursor, rows, key, total))
     }
 }
 
-impl Default for Pagination {
-    fn default() -> Self {
-        Self {
-            offset: Some(0),
-            limit: Some(10),
+impl<K: Serialize, I: Serialize> Cursor<K, I> {
+    pub fn encode(&self) -> String {
+        // serializing a key, uuid and bool can't fail
+        BASE64URL_NOPAD.encode(&serde_json::to_vec(self).unwrap())
+    }
+}
+
+impl<K: DeserializeOwned, I: DeserializeOwned> Cursor<K, I> {
+    pub fn decode(encoded: &str) -> Result<Self, Error> {
+        BASE64URL_NOPAD.decode(encoded.as_bytes())
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| Error::InvalidData(format!("Invalid cursor: {}", encoded)))
+    }
+}
+
+impl<T> Page<T> {
+    /// Converts the items of the page, keeping its cursors
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next: self.next,
+            prev: self.prev,
+            total: self.total,
         }
     }
+
+    /// Converts the items of the page, keeping its cursors, failing if any conversion fails
+    pub fn try_map<U, E>(self, f: impl FnMut(T) -> Result<U, E>) -> Result<Page<U>, E> {
+        Ok(Page {
+            items: self.items.into_iter().map(f).collect::<Result<Vec<U>, E>>()?,
+            next: self.next,
+            prev: self.prev,
+            total: self.total,
+        })
+    }
+
+    /// The RFC 8288 Link header pointing at the next and prev pages, if there are any. The links
+    /// repeat the query of the request the page was loaded for, with the cursor swapped.
+    pub fn link_header(&self, uri: &Uri) -> Vec<(String, String)> {
+        let query = uri.query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty() && !pair.starts_with("cursor="))
+            .collect::<Vec<&str>>();
+
+        let link = |cursor: &String, rel: &str| {
+            let query = query.iter()
+                .copied()
+                .chain(Some(format!("cursor={}", cursor).as_str()))
+                .collect::<Vec<&str>>()
+                .join("&");
+
+            format!("<{}?{}>; rel=\"{}\"", uri.path(), query, rel)
+        };
+
+        let links = [(&self.next, "next"), (&self.prev, "prev")]
+            .into_iter()
+            .filter_map(|(cursor, rel)| cursor.as_ref().map(|cursor| link(cursor, rel)))
+            .collect::<Vec<String>>();
+
+        match links.is_empty() {
+            true => Vec::new(),
+            false => vec!((LINK.to_string(), links.join(", "))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(count: u128) -> Vec<Uuid> {
+        (1..=count).map(Uuid::from_u128).collect()
+    }
+
+    fn key(id: &Uuid) -> (u128, Uuid) {
+        (id.as_u128(), *id)
+    }
+
+    #[test]
+    fn caps_the_page_size() {
+        assert_eq!(Pagination::default().get_limit(), DEFAULT_PAGE_SIZE);
+        assert_eq!(Pagination::new(None, Some(1_000_000), false).get_limit(), MAX_PAGE_SIZE);
+        assert_eq!(Pagination::new(None, Some(0), false).get_limit(), 1);
+    }
+
+    #[test]
+    fn cursors_round_trip_and_reject_garbage() {
+        let cursor = Cursor { key: 42_i64, id: Uuid::from_u128(7), backward: true };
+        assert_eq!(Cursor::<i64>::decode(&cursor.encode()).unwrap(), cursor);
+
+        assert!(Cursor::<i64>::decode("not a cursor").is_err());
+        assert!(Cursor::<Uuid>::decode(&cursor.encode()).is_err());
+        assert!(Cursor::<i64, i64>::decode(&cursor.encode()).is_err());
+    }
+
+    #[test]
+    fn pages_forward_and_backward() {
+        let pagination = Pagination::new(None, Some(2), false);
+
+        let first = pagination.page(&None, ids(3), key, None);
+        assert_eq!(first.items, ids(2));
+        assert!(first.prev.is_none());
+
+        let after = Pagination::new(first.next.clone(), Some(2), false);
+        let cursor = after.cursor::<u128, Uuid>().unwrap();
+        assert_eq!(cursor, Some(Cursor { key: 2, id: Uuid::from_u128(2), backward: false }));
+        let last = after.page(&cursor, ids(3).split_off(2), key, Some(3));
+        assert_eq!(last.items, vec![Uuid::from_u128(3)]);
+        assert!(last.next.is_none());
+        assert_eq!(last.total, Some(3));
+
+        let before = Pagination::new(last.prev.clone(), Some(2), false);
+        let cursor = before.cursor::<u128, Uuid>().unwrap();
+        assert_eq!(cursor, Some(Cursor { key: 3, id: Uuid::from_u128(3), backward: true }));
+        // loaded in reverse, and there is nothing before the first two
+        let back = before.page(&cursor, vec![Uuid::from_u128(2), Uuid::from_u128(1)], key, None);
+        assert_eq!(back.items, ids(2));
+        assert!(back.prev.is_none());
+        assert_eq!(back.next, first.next);
+    }
+
+    #[test]
+    fn pages_lists_loaded_in_memory() {
+        // sorted newest first, the name breaking the tie between the two 2s
+        let rows = vec![(1, "a"), (3, "c"), (2, "b"), (2, "a"), (4, "d")];
+        let key = |row: &(i32, &str)| (row.0, row.1.to_string());
+        let names = |page: &Page<(i32, &str)>| page.items.iter().map(|row| row.1).collect::<String>();
+
+        let first = Pagination::new(None, Some(2), true).page_of(rows.clone(), key, false).unwrap();
+        assert_eq!(names(&first), "dc");
+        assert_eq!(first.total, Some(5));
+        assert!(first.prev.is_none());
+
+        let second = Pagination::new(first.next.clone(), Some(2), false).page_of(rows.clone(), key, false).unwrap();
+        assert_eq!(names(&second), "ba");
+
+        let last = Pagination::new(second.next.clone(), Some(2), false).page_of(rows.clone(), key, false).unwrap();
+        assert_eq!(names(&last), "a");
+        assert!(last.next.is_none());
+
+        let back = Pagination::new(last.prev.clone(), Some(2), false).page_of(rows.clone(), key, false).unwrap();
+        assert_eq!(names(&back), "ba");
+        assert_eq!(back.next, second.next);
+
+        let garbage = Pagination::new(first.next.clone(), Some(2), false).page_of(rows, |row| (row.0, row.0), false);
+        assert!(garbage.is_err());
+    }
+
+    #[test]
+    fn links_keep_the_query() {
+        let page = Page::<Uuid> {
+            items: Vec::new(),
+            next: Some("abc".to_string()),
+            prev: Some("xyz".to_string()),
+            total: None,
+        };
+        let uri = "/api/v1/item/search?q=desk&cursor=old&limit=5".parse::<Uri>().unwrap();
+
+        assert_eq!(page.link_header(&uri), vec!((
+            "link".to_string(),
+            "</api/v1/item/search?q=desk&limit=5&cursor=abc>; rel=\"next\", \
+             </api/v1/item/search?q=desk&limit=5&cursor=xyz>; rel=\"prev\"".to_string(),
+        )));
+
+        let empty = Page::<Uuid> { items: Vec::new(), next: None, prev: None, total: None };
+        assert!(empty.link_header(&uri).is_empty());
+    }
 }
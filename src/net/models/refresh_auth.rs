@@ -0,0 +1,6 @@
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct RefreshAuth {
+    pub refresh_token: String,
+}
@@ -0,0 +1,22 @@
+use uuid::Uuid;
+use serde::Serialize;
+use std::convert::From;
+
+use crate::db::{ Permission, Role };
+
+#[derive(Serialize)]
+pub struct RoleData {
+    pub uuid: Uuid,
+    pub name: String,
+    pub permissions: Vec<Permission>,
+}
+
+impl From<(Role, Vec<Permission>)> for RoleData {
+    fn from((role, permissions): (Role, Vec<Permission>)) -> Self {
+        RoleData {
+            uuid: role.uuid,
+            name: role.name,
+            permissions,
+        }
+    }
+}
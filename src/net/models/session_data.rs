@@ -0,0 +1,29 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::db::UserSession;
+
+/// A signed in session as shown to its user, never including the session's data
+#[derive(Serialize)]
+pub struct SessionData {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub last_activity: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub current: bool,
+}
+
+impl SessionData {
+    /// Builds the listing of a session, flagging it as current when it is the caller's own
+    pub fn new(session: UserSession, current_sid: &str) -> Self {
+        SessionData {
+            current: session.id == current_sid,
+            id: session.id,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            last_activity: session.last_activity,
+            expires_at: session.expires_at,
+        }
+    }
+}
@@ -0,0 +1,10 @@
+use serde::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+
+/// The body of a request filing an item under exactly the given categories
+#[derive(Deserialize, Validate)]
+pub struct SetItemCategories {
+    #[validate(length(max = 64, message = "must be at most 64 categories"))]
+    pub category_ids: Vec<Uuid>,
+}
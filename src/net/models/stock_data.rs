@@ -0,0 +1,24 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db::Inventory;
+
+/// The stock of a deal as sent to clients
+#[derive(Serialize)]
+pub struct StockData {
+    pub deal_id: Uuid,
+    pub on_hand: i32,
+    pub reserved: i32,
+    pub available: i32,
+}
+
+impl From<Inventory> for StockData {
+    fn from(stock: Inventory) -> Self {
+        StockData {
+            available: stock.available(),
+            deal_id: stock.deal_id,
+            on_hand: stock.on_hand,
+            reserved: stock.reserved,
+        }
+    }
+}
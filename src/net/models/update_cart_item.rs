@@ -0,0 +1,9 @@
+use serde::Deserialize;
+use validator::Validate;
+
+/// The body of a request setting how many of a deal the caller's cart holds
+#[derive(Deserialize, Validate)]
+pub struct UpdateCartItem {
+    #[validate(range(min = 1, max = 999, message = "must be between 1 and 999"))]
+    pub quantity: i32,
+}
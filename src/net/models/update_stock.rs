@@ -0,0 +1,9 @@
+use serde::Deserialize;
+use validator::Validate;
+
+/// The body of a request setting how many units of a deal are on hand
+#[derive(Deserialize, Validate)]
+pub struct UpdateStock {
+    #[validate(range(min = 0, message = "must be at least 0"))]
+    pub on_hand: i32,
+}
@@ -6,5 +6,6 @@ pub struct UserAuth {
     #[validate(email)]
     pub email: String,
     pub password: String,
+    /// The tag returned by `/auth/nonce` for the caller's session
     pub nonce: String,
 }
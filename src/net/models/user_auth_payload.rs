@@ -1,24 +1,28 @@
-use uuid::uuid;
 use serde::Serialize;
-use std::convert::From;
 
-use crate::db::User;
+use crate::db::{ IssuedToken, User };
 use crate::jwt::lib::*;
-use crate::jwt::models::claims::Claims;
+use crate::jwt::{ Claims, SigningKey };
 
 
 #[derive(Serialize)]
 pub struct UserAuthPayload {
     pub token: String,
+    pub refresh_token: String,
 }
 
-impl From<User> for UserAuthPayload {
-    fn from(user: User) -> Self {
-        let claims = Claims::new(user.uuid.unwrap(), uuid!("d582df1f-3642-4191-b822-0c9a73719259"), user.role);
-        let token = encrypt_jwt(&get_secret(), claims).unwrap();
+impl UserAuthPayload {
+    /// Mints an access token for the user, signed with the api's signing key, to be sent along
+    /// with the refresh token it can be renewed with. The access token names the refresh token's
+    /// family, see `Claims`.
+    pub fn new(user: User, signing_key: &SigningKey, refresh_token: IssuedToken) -> Result<Self, jsonwebtoken::errors::Error> {
+        let mut claims = Claims::new(user.uuid.unwrap(), get_issuer_id(), get_audience(), user.role);
+        claims.fam = Some(refresh_token.family_id);
+        let token = encrypt_jwt(signing_key, claims)?;
 
-        Self {
-            token: token,
-        }
+        Ok(Self {
+            token,
+            refresh_token: refresh_token.token,
+        })
     }
 }
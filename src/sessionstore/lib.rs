@@ -1,33 +1,61 @@
 use async_trait::async_trait;
 use async_session::{ Result, Session, serde_json, SessionStore };
-use diesel::{ pg::PgConnection, prelude::* };
-use dotenvy::dotenv;
-use log::{ trace };
-use std::env;
+use chrono::{ NaiveDateTime, Utc };
+use diesel::prelude::*;
+use log::{ debug, error, warn };
+use std::{ cell::RefCell, fmt };
 use uuid::Uuid;
 
-use crate::db::UserSession;
+use crate::db::{ self, with_connection, DbPool, UserSession };
+use crate::sessionstore::SessionPolicy;
 
-#[derive(Default, Debug, Clone)]
+/// Lookups and revocations of the sessions signed in as a user
+///
+/// Implemented by every session store next to `SessionStore`, so users can list and sign out their
+/// devices whichever backend holds the sessions. Only sessions that are still live by the store's
+/// `SessionPolicy` are listed.
+#[async_trait]
+pub trait UserSessionIndex {
+    /// Loads the live sessions signed in as the user, most recently active first
+    async fn get_for_user(&self, user: Uuid) -> std::result::Result<Vec<UserSession>, db::Error>;
+
+    /// Deletes a single session of the user, `db::Error::NotFound` if the user has no such session
+    async fn delete_for_user_by_id(&self, user: Uuid, sid: &str) -> std::result::Result<(), db::Error>;
+
+    /// Deletes every session signed in as the user, returning how many were deleted
+    async fn delete_for_user(&self, user: Uuid) -> std::result::Result<usize, db::Error>;
+
+    /// Deletes the session with the id, if there is one
+    async fn delete_by_id(&self, sid: &str) -> std::result::Result<(), db::Error>;
+}
+
+/// The session store keeping sessions in the sessions table
+///
+/// Sessions are expired by the store's `SessionPolicy`. Every store refreshes the session's
+/// last_activity and pushes its expires_at back out, up to the absolute lifetime counted from the
+/// row's created_at, and loading a session past either timeout finds nothing.
+#[derive(Clone)]
 pub struct PostgresSessionStore {
-    pub database_url: String,
+    pub pool: DbPool,
+    pub policy: SessionPolicy,
+}
+
+impl fmt::Debug for PostgresSessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostgresSessionStore")
+            .field("pool", &self.pool.status())
+            .field("policy", &self.policy)
+            .finish()
+    }
 }
 
 impl PostgresSessionStore {
-    pub fn new(database_url: String) -> Self {
+    pub fn new(pool: DbPool, policy: SessionPolicy) -> Self {
         Self {
-            database_url,
+            pool,
+            policy,
         }
     }
-
-    pub fn connection(&self) -> PgConnection {
-        trace!("Starting connection to PostgreSQL...");
-        dotenv().ok();
-    
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        PgConnection::establish(&database_url)
-            .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
-    }
 }
 
 #[async_trait]
@@ -35,116 +63,243 @@ impl SessionStore for PostgresSessionStore {
     async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
         use crate::schema::sessions::dsl::*;
 
-        let sid = Session::id_from_cookie_value(&cookie_value).unwrap().to_string();
-        let user_session = UserSession::new(
-            sid.clone(),
-            None,
-            None,
-            None,
-            None,
-            None
-        );
+        let Some(sid) = session_id(&cookie_value) else {
+            return Ok(None);
+        };
 
-        let mut connection = self.connection();
-        let result = connection.build_transaction()
+        let result = with_connection(&self.pool, move |conn| {
+            conn.build_transaction()
             .read_only()
             .run(|conn| {
                 sessions
                     .filter(id.eq(&sid))
-                    .filter(expires_at.ge(&user_session.last_activity))
                     .first::<UserSession>(conn)
-            });
+            })
+        }).await;
         
         match result {
-            Ok(data) => {
-                Ok(data.session_data
-                    .map(|session| serde_json::from_str::<Session>(&session))
-                    .transpose()?)
-            },
-            Err(_) => {
-                Err(async_session::Error::msg("The jank continues"))
-            },
+            Ok(row) => Ok(session_from_row(row, &self.policy)),
+            Err(db::Error::NotFound) => Ok(None),
+            Err(e) => Err(store_error(e)),
         }
     }
 
     async fn store_session(&self, session: Session) -> Result<Option<String>> {
         use crate::schema::sessions::dsl::*;
 
-        let sid = session.id().to_string();
-        let s_data = Some(serde_json::to_string(&session)?);
-        let s_user_id = session.get::<Uuid>("user_id");
-
-        let expiry = session.expiry().map(|s| s.naive_utc());
+        let mut user_session = session_row(&session, None, &self.policy)?;
+        let policy = self.policy;
 
-        let user_session = UserSession::new(
-            sid,
-            s_data.clone(),
-            expiry,
-            None,
-            None,
-            s_user_id
-        );
-
-        let mut connection = self.connection();
-        let result = connection.build_transaction()
+        let result = with_connection(&self.pool, move |conn| {
+            conn.build_transaction()
             .read_write()
             .run(|conn| {
+                let created = sessions
+                    .find(&user_session.id)
+                    .select(created_at)
+                    .first::<NaiveDateTime>(conn)
+                    .optional()?
+                    .unwrap_or(user_session.created_at);
+
+                user_session.created_at = created;
+                user_session.expires_at = policy.expires_at(created, user_session.last_activity);
+
                 diesel::insert_into(sessions)
                     .values(&user_session)
                     .on_conflict(id)
                     .do_update()
                     .set((
                         session_data.eq(&user_session.session_data),
+                        expires_at.eq(&user_session.expires_at),
+                        user_agent.eq(&user_session.user_agent),
                         last_activity.eq(&user_session.last_activity),
+                        ip.eq(&user_session.ip),
+                        user_id.eq(&user_session.user_id),
                     ))
                     .execute(conn)
-            });
+            })
+        }).await;
 
         match result {
             Ok(_) => Ok(session.into_cookie_value()),
-            Err(e) => Err(async_session::Error::new(e)),
+            Err(e) => Err(store_error(e)),
         }
 
     }
 
     async fn destroy_session(&self, session: Session) -> Result {
-        use crate::schema::sessions::dsl::*;
+        match UserSession::delete(&self.pool, session.id()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(store_error(e)),
+        }
+    }
 
-        let sid = session.id();
+    async fn clear_store(&self) -> Result {
+        use crate::schema::{ nonces, sessions };
 
-        let mut connection = self.connection();
-        let result = connection.build_transaction()
+        let result = with_connection(&self.pool, |conn| {
+            conn.build_transaction()
             .read_write()
             .run(|conn| {
-                diesel::delete(
-                    sessions.filter(id.eq(sid))
-                )
-                .execute(conn)
-            });
+                diesel::delete(nonces::table).execute(conn)?;
+                diesel::delete(sessions::table).execute(conn)
+            })
+        }).await;
 
         match result {
             Ok(_) => Ok(()),
-            Err(e) => Err(async_session::Error::new(e)),
+            Err(e) => Err(store_error(e)),
         }
     }
+}
 
-    async fn clear_store(&self) -> Result {
-        use crate::schema::sessions::dsl::*;
+#[async_trait]
+impl UserSessionIndex for PostgresSessionStore {
+    async fn get_for_user(&self, user: Uuid) -> std::result::Result<Vec<UserSession>, db::Error> {
+        let now = Utc::now().naive_utc();
 
-        let mut connection = self.connection();
+        Ok(UserSession::get_for_user(&self.pool, user).await?
+            .into_iter()
+            .filter(|session| self.policy.is_live(session, now))
+            .collect())
+    }
 
-        let result = connection.build_transaction()
-            .read_write()
-            .run(|conn| {
-                diesel::delete(
-                    sessions
-                )
-                .execute(conn)
-            });
+    async fn delete_for_user_by_id(&self, user: Uuid, sid: &str) -> std::result::Result<(), db::Error> {
+        UserSession::delete_for_user_by_id(&self.pool, user, sid).await
+    }
 
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(async_session::Error::new(e)),
-        }
+    async fn delete_for_user(&self, user: Uuid) -> std::result::Result<usize, db::Error> {
+        UserSession::delete_for_user(&self.pool, user).await
+    }
+
+    async fn delete_by_id(&self, sid: &str) -> std::result::Result<(), db::Error> {
+        UserSession::delete(&self.pool, sid).await.map(|_| ())
+    }
+}
+
+/// The id of the session a cookie belongs to, `None` for malformed cookies
+pub fn session_id(cookie_value: &str) -> Option<String> {
+    match Session::id_from_cookie_value(cookie_value) {
+        Ok(sid) => Some(sid),
+        Err(_) => {
+            debug!("Ignoring malformed session cookie");
+            None
+        },
+    }
+}
+
+/// Builds the row a session is stored as
+///
+/// The session's user, user agent and ip are copied out of its data, and its expiry is set by the
+/// policy from when it was created, now unless `created_at` carries over the time of a stored row.
+pub fn session_row(session: &Session, created_at: Option<NaiveDateTime>, policy: &SessionPolicy) -> serde_json::Result<UserSession> {
+    let mut row = UserSession::new(
+        session.id().to_string(),
+        Some(serde_json::to_string(session)?),
+        None,
+        session.get::<String>("user_agent"),
+        session.get::<String>("ip"),
+        session.get::<Uuid>("user_id"),
+    );
+
+    row.created_at = created_at.unwrap_or(row.created_at);
+    row.expires_at = policy.expires_at(row.created_at, row.last_activity);
+
+    Ok(row)
+}
+
+/// Reads a session back out of its row, `None` if the policy has expired it or its data can't be
+/// read
+pub fn session_from_row(row: UserSession, policy: &SessionPolicy) -> Option<Session> {
+    if !policy.is_live(&row, Utc::now().naive_utc()) {
+        return None;
+    }
+
+    match row.session_data.map(|session| serde_json::from_str::<Session>(&session)) {
+        Some(Ok(session)) => Some(session),
+        Some(Err(e)) => {
+            warn!("Ignoring session {} with unreadable data: {}", row.id, e);
+            None
+        },
+        None => None,
+    }
+}
+
+tokio::task_local! {
+    /// The database outage hit by the session store while serving the current request, if any
+    static OUTAGE: RefCell<Option<db::Error>>;
+}
+
+/// Runs a request while watching the session store for database outages
+///
+/// The session layer swallows load failures, carrying on with a fresh session as if the caller had
+/// none, and reports store failures as a bare 500. So that an outage doesn't sign callers out or
+/// hide behind a 500, the store reports connection failures here, and they are returned along with
+/// the request's output to be turned into a 503.
+pub async fn watch_for_outages<F: std::future::Future>(request: F) -> (F::Output, Option<db::Error>) {
+    OUTAGE.scope(RefCell::new(None), async {
+        let output = request.await;
+        (output, take_outage())
+    }).await
+}
+
+/// Takes the outage the session store hit while serving the current request, if any
+fn take_outage() -> Option<db::Error> {
+    OUTAGE.try_with(|outage| outage.borrow_mut().take()).ok().flatten()
+}
+
+/// Whether the session store has hit an outage while serving the current request
+pub fn outage_reported() -> bool {
+    OUTAGE.try_with(|outage| outage.borrow().is_some()).unwrap_or(false)
+}
+
+/// Logs a failed store operation and wraps it for the session layer, reporting it as an outage
+/// when the database couldn't be reached
+pub fn store_error(e: db::Error) -> async_session::Error {
+    error!("Session store operation failed: {}", e);
+    let error = async_session::Error::msg(e.to_string());
+
+    if matches!(e, db::Error::Connection(_)) {
+        OUTAGE.try_with(|outage| *outage.borrow_mut() = Some(e)).ok();
+    }
+
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_diesel::{ postgres::{ Manager, Pool }, Runtime };
+
+    /// A pool whose connections are always refused
+    fn unreachable_pool() -> DbPool {
+        Pool::builder(Manager::new("postgres://localhost:1/commerce", Runtime::Tokio1))
+            .runtime(Runtime::Tokio1)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn malformed_cookies_load_no_session() {
+        let store = PostgresSessionStore::new(unreachable_pool(), SessionPolicy::default());
+
+        let (loaded, outage) = watch_for_outages(store.load_session("not base64!".to_string())).await;
+
+        assert!(matches!(loaded, Ok(None)));
+        assert!(outage.is_none());
+    }
+
+    #[tokio::test]
+    async fn database_outages_are_reported() {
+        let store = PostgresSessionStore::new(unreachable_pool(), SessionPolicy::default());
+        let cookie_value = Session::new().into_cookie_value().unwrap();
+
+        let (loaded, outage) = watch_for_outages(store.load_session(cookie_value)).await;
+        assert!(loaded.is_err());
+        assert!(matches!(outage, Some(db::Error::Connection(_))));
+
+        let (stored, outage) = watch_for_outages(store.store_session(Session::new())).await;
+        assert!(stored.is_err());
+        assert!(matches!(outage, Some(db::Error::Connection(_))));
     }
 }
@@ -1,3 +1,7 @@
+pub mod models;
 pub mod lib;
 
-pub use self::lib::*;
+pub use self::{
+    models::*,
+    lib::*,
+};
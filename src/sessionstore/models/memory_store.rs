@@ -0,0 +1,103 @@
+use async_trait::async_trait;
+use async_session::{ Result, Session, SessionStore };
+use chrono::Utc;
+use std::{ collections::HashMap, sync::{ Arc, RwLock } };
+use uuid::Uuid;
+
+use crate::db::{ self, UserSession };
+use crate::sessionstore::{ session_from_row, session_id, session_row, SessionPolicy, UserSessionIndex };
+
+/// The session store keeping sessions in the memory of the server
+///
+/// Meant for local development and tests, sessions are lost on restart and aren't shared between
+/// instances. Sessions are kept as the same rows `PostgresSessionStore` writes and expired by the
+/// same `SessionPolicy`, expired rows are dropped whenever a session is stored. Cloning the store is
+/// cheap, every clone shares the same sessions.
+#[derive(Clone, Debug)]
+pub struct MemorySessionStore {
+    pub policy: SessionPolicy,
+    pub sessions: Arc<RwLock<HashMap<String, UserSession>>>,
+}
+
+impl MemorySessionStore {
+    pub fn new(policy: SessionPolicy) -> Self {
+        Self {
+            policy,
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let Some(sid) = session_id(&cookie_value) else {
+            return Ok(None);
+        };
+
+        let row = self.sessions.read().unwrap().get(&sid).cloned();
+        Ok(row.and_then(|row| session_from_row(row, &self.policy)))
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let mut sessions = self.sessions.write().unwrap();
+        let now = Utc::now().naive_utc();
+        sessions.retain(|_, row| self.policy.is_live(row, now));
+
+        let created_at = sessions.get(session.id()).map(|row| row.created_at);
+        let row = session_row(&session, created_at, &self.policy)?;
+        sessions.insert(row.id.clone(), row);
+
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        self.sessions.write().unwrap().remove(session.id());
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        self.sessions.write().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserSessionIndex for MemorySessionStore {
+    async fn get_for_user(&self, user: Uuid) -> std::result::Result<Vec<UserSession>, db::Error> {
+        let now = Utc::now().naive_utc();
+        let mut rows = self.sessions.read().unwrap()
+            .values()
+            .filter(|row| row.user_id == Some(user) && self.policy.is_live(row, now))
+            .cloned()
+            .collect::<Vec<UserSession>>();
+
+        rows.sort_by_key(|row| std::cmp::Reverse(row.last_activity));
+        Ok(rows)
+    }
+
+    async fn delete_for_user_by_id(&self, user: Uuid, sid: &str) -> std::result::Result<(), db::Error> {
+        let mut sessions = self.sessions.write().unwrap();
+
+        match sessions.get(sid) {
+            Some(row) if row.user_id == Some(user) => {
+                sessions.remove(sid);
+                Ok(())
+            },
+            _ => Err(db::Error::NotFound),
+        }
+    }
+
+    async fn delete_for_user(&self, user: Uuid) -> std::result::Result<usize, db::Error> {
+        let mut sessions = self.sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, row| row.user_id != Some(user));
+
+        Ok(before - sessions.len())
+    }
+
+    async fn delete_by_id(&self, sid: &str) -> std::result::Result<(), db::Error> {
+        self.sessions.write().unwrap().remove(sid);
+        Ok(())
+    }
+}
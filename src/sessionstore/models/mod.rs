@@ -0,0 +1,11 @@
+pub mod memory_store;
+pub mod redis_store;
+pub mod session_backend;
+pub mod session_policy;
+
+pub use self::{
+    memory_store::*,
+    redis_store::*,
+    session_backend::*,
+    session_policy::*,
+};
@@ -0,0 +1,242 @@
+use async_trait::async_trait;
+use async_session::{ Result, Session, serde_json, SessionStore };
+use chrono::Utc;
+use redis::{ aio::ConnectionManager, AsyncCommands, RedisError };
+use std::fmt;
+use uuid::Uuid;
+
+use crate::db::{ self, UserSession };
+use crate::sessionstore::{ session_from_row, session_id, session_row, store_error, SessionPolicy, UserSessionIndex };
+
+/// The prefix of every key the store writes
+const KEY_PREFIX: &str = "commerce:";
+
+/// The session store keeping sessions in a Redis compatible key-value store
+///
+/// Each session is kept as the JSON of the same row `PostgresSessionStore` writes, under
+/// `commerce:session:<sid>`, and is expired by the same `SessionPolicy`. The key is given the
+/// row's expires_at as its expiry, so the server drops expired sessions by itself and no cleanup
+/// job is needed. The sessions signed in as a user are indexed by the set
+/// `commerce:user:<uuid>:sessions`, whose members may outlive their sessions and are pruned
+/// whenever the set is read.
+///
+/// Connection failures are reported as `db::Error::Connection`, so an unreachable server is
+/// answered with a 503 just like an unreachable database.
+#[derive(Clone)]
+pub struct RedisSessionStore {
+    pub connection: ConnectionManager,
+    pub policy: SessionPolicy,
+}
+
+impl fmt::Debug for RedisSessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisSessionStore")
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl RedisSessionStore {
+    /// Connects to the server at the url, e.g. `redis://127.0.0.1:6379`
+    pub async fn connect(url: &str, policy: SessionPolicy) -> std::result::Result<Self, db::Error> {
+        let client = redis::Client::open(url).map_err(redis_error)?;
+        let connection = ConnectionManager::new(client).await.map_err(redis_error)?;
+
+        Ok(Self {
+            connection,
+            policy,
+        })
+    }
+
+    async fn get_row(&self, sid: &str) -> std::result::Result<Option<UserSession>, db::Error> {
+        let mut conn = self.connection.clone();
+        let json: Option<String> = conn.get(session_key(sid)).await.map_err(redis_error)?;
+
+        Ok(json.and_then(|json| serde_json::from_str::<UserSession>(&json).ok()))
+    }
+
+    async fn put_row(&self, row: &UserSession, previous: Option<&UserSession>) -> std::result::Result<(), db::Error> {
+        let mut conn = self.connection.clone();
+        let json = serde_json::to_string(row).map_err(|e| db::Error::Internal(e.to_string()))?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("SET").arg(session_key(&row.id)).arg(json)
+            .arg("PXAT").arg(row.expires_at.and_utc().timestamp_millis())
+            .ignore();
+
+        if let Some(previous_user) = previous.and_then(|previous| previous.user_id) {
+            if row.user_id != Some(previous_user) {
+                pipe.srem(user_key(previous_user), &row.id).ignore();
+            }
+        }
+
+        if let Some(user) = row.user_id {
+            pipe.sadd(user_key(user), &row.id).ignore()
+                .expire(user_key(user), self.policy.absolute_timeout.num_seconds()).ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(redis_error)
+    }
+
+    async fn delete_row(&self, row: &UserSession) -> std::result::Result<(), db::Error> {
+        let mut conn = self.connection.clone();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(session_key(&row.id)).ignore();
+        if let Some(user) = row.user_id {
+            pipe.srem(user_key(user), &row.id).ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(redis_error)
+    }
+
+    /// Loads the rows of every session in the user's index, pruning the members whose session is
+    /// gone
+    async fn get_user_rows(&self, user: Uuid) -> std::result::Result<Vec<UserSession>, db::Error> {
+        let mut conn = self.connection.clone();
+        let sids: Vec<String> = conn.smembers(user_key(user)).await.map_err(redis_error)?;
+        if sids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let keys = sids.iter().map(|sid| session_key(sid)).collect::<Vec<String>>();
+        let rows: Vec<Option<String>> = redis::cmd("MGET").arg(&keys)
+            .query_async(&mut conn)
+            .await
+            .map_err(redis_error)?;
+
+        let rows = rows.into_iter()
+            .map(|json| json.and_then(|json| serde_json::from_str::<UserSession>(&json).ok()))
+            .collect::<Vec<Option<UserSession>>>();
+
+        let gone = sids.iter()
+            .zip(&rows)
+            .filter(|(_, row)| row.is_none())
+            .map(|(sid, _)| sid.clone())
+            .collect::<Vec<String>>();
+
+        if !gone.is_empty() {
+            conn.srem::<_, _, ()>(user_key(user), gone).await.map_err(redis_error)?;
+        }
+
+        Ok(rows.into_iter().flatten().collect())
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let Some(sid) = session_id(&cookie_value) else {
+            return Ok(None);
+        };
+
+        match self.get_row(&sid).await {
+            Ok(row) => Ok(row.and_then(|row| session_from_row(row, &self.policy))),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let previous = self.get_row(session.id()).await.map_err(store_error)?;
+        let row = session_row(&session, previous.as_ref().map(|row| row.created_at), &self.policy)?;
+
+        let result = if row.expires_at <= Utc::now().naive_utc() {
+            self.delete_row(&row).await
+        } else {
+            self.put_row(&row, previous.as_ref()).await
+        };
+
+        match result {
+            Ok(_) => Ok(session.into_cookie_value()),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        match self.delete_by_id(session.id()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    async fn clear_store(&self) -> Result {
+        let mut conn = self.connection.clone();
+
+        let keys = {
+            let mut iter = conn.scan_match::<_, String>(format!("{}*", KEY_PREFIX)).await
+                .map_err(|e| store_error(redis_error(e)))?;
+
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            keys
+        };
+
+        for chunk in keys.chunks(512) {
+            conn.del::<_, ()>(chunk).await.map_err(|e| store_error(redis_error(e)))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserSessionIndex for RedisSessionStore {
+    async fn get_for_user(&self, user: Uuid) -> std::result::Result<Vec<UserSession>, db::Error> {
+        let now = Utc::now().naive_utc();
+        let mut rows = self.get_user_rows(user).await?
+            .into_iter()
+            .filter(|row| row.user_id == Some(user) && self.policy.is_live(row, now))
+            .collect::<Vec<UserSession>>();
+
+        rows.sort_by_key(|row| std::cmp::Reverse(row.last_activity));
+        Ok(rows)
+    }
+
+    async fn delete_for_user_by_id(&self, user: Uuid, sid: &str) -> std::result::Result<(), db::Error> {
+        match self.get_row(sid).await? {
+            Some(row) if row.user_id == Some(user) => self.delete_row(&row).await,
+            _ => Err(db::Error::NotFound),
+        }
+    }
+
+    async fn delete_for_user(&self, user: Uuid) -> std::result::Result<usize, db::Error> {
+        let rows = self.get_user_rows(user).await?
+            .into_iter()
+            .filter(|row| row.user_id == Some(user))
+            .collect::<Vec<UserSession>>();
+
+        for row in &rows {
+            self.delete_row(row).await?;
+        }
+
+        Ok(rows.len())
+    }
+
+    async fn delete_by_id(&self, sid: &str) -> std::result::Result<(), db::Error> {
+        match self.get_row(sid).await? {
+            Some(row) => self.delete_row(&row).await,
+            None => Ok(()),
+        }
+    }
+}
+
+fn session_key(sid: &str) -> String {
+    format!("{}session:{}", KEY_PREFIX, sid)
+}
+
+fn user_key(user: Uuid) -> String {
+    format!("{}user:{}:sessions", KEY_PREFIX, user)
+}
+
+/// Folds a Redis failure into the error type shared with the database, so callers handle an
+/// unreachable server like an unreachable database
+fn redis_error(e: RedisError) -> db::Error {
+    if e.is_io_error() || e.is_connection_refusal() || e.is_connection_dropped() || e.is_timeout() {
+        db::Error::Connection(e.to_string())
+    } else {
+        db::Error::Internal(e.to_string())
+    }
+}
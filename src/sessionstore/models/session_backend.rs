@@ -0,0 +1,296 @@
+use async_trait::async_trait;
+use async_session::{ Result, Session, SessionStore };
+use dotenvy::dotenv;
+use std::env;
+use uuid::Uuid;
+
+use crate::db::{ self, DbPool, UserSession };
+use crate::sessionstore::{ MemorySessionStore, PostgresSessionStore, RedisSessionStore, SessionPolicy, UserSessionIndex };
+
+/// The store sessions are kept in, chosen at boot
+///
+/// The session layer and the routes managing a user's sessions share the same backend, so
+/// whichever store is configured, sessions expire by the same `SessionPolicy` and can be listed and
+/// revoked per user. Cloning the backend is cheap, every clone shares the same store.
+///
+/// # Examples
+///
+/// ```
+/// let sessions = SessionBackend::from_env(pool.clone()).await;
+/// let devices = sessions.get_for_user(user_id).await?;
+/// ```
+#[derive(Clone, Debug)]
+pub enum SessionBackend {
+    Postgres(PostgresSessionStore),
+    Memory(MemorySessionStore),
+    Redis(RedisSessionStore),
+}
+
+impl SessionBackend {
+    /// Builds the backend named by the SESSION_BACKEND env variable
+    ///
+    /// `postgres` (the default) keeps sessions in the sessions table, `memory` in the memory of
+    /// the server, and `redis` in the Redis compatible server at REDIS_URL. The expiry policy is
+    /// read by `SessionPolicy::from_env`.
+    ///
+    /// # Panics
+    /// This function will panic if SESSION_BACKEND names an unknown backend, or the redis backend
+    /// is chosen and REDIS_URL is missing or the server can't be reached.
+    pub async fn from_env(pool: DbPool) -> Self {
+        dotenv().ok();
+        let policy = SessionPolicy::from_env();
+
+        match env::var("SESSION_BACKEND").unwrap_or_default().to_lowercase().as_str() {
+            "" | "postgres" => SessionBackend::Postgres(PostgresSessionStore::new(pool, policy)),
+            "memory" => SessionBackend::Memory(MemorySessionStore::new(policy)),
+            "redis" => {
+                let url = env::var("REDIS_URL").expect("REDIS_URL must be set");
+                let store = RedisSessionStore::connect(&url, policy).await
+                    .expect("Failed to connect to the redis session store");
+
+                SessionBackend::Redis(store)
+            },
+            other => panic!("Unknown SESSION_BACKEND {}", other),
+        }
+    }
+
+    pub fn policy(&self) -> SessionPolicy {
+        match self {
+            SessionBackend::Postgres(store) => store.policy,
+            SessionBackend::Memory(store) => store.policy,
+            SessionBackend::Redis(store) => store.policy,
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for SessionBackend {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        match self {
+            SessionBackend::Postgres(store) => store.load_session(cookie_value).await,
+            SessionBackend::Memory(store) => store.load_session(cookie_value).await,
+            SessionBackend::Redis(store) => store.load_session(cookie_value).await,
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        match self {
+            SessionBackend::Postgres(store) => store.store_session(session).await,
+            SessionBackend::Memory(store) => store.store_session(session).await,
+            SessionBackend::Redis(store) => store.store_session(session).await,
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        match self {
+            SessionBackend::Postgres(store) => store.destroy_session(session).await,
+            SessionBackend::Memory(store) => store.destroy_session(session).await,
+            SessionBackend::Redis(store) => store.destroy_session(session).await,
+        }
+    }
+
+    async fn clear_store(&self) -> Result {
+        match self {
+            SessionBackend::Postgres(store) => store.clear_store().await,
+            SessionBackend::Memory(store) => store.clear_store().await,
+            SessionBackend::Redis(store) => store.clear_store().await,
+        }
+    }
+}
+
+#[async_trait]
+impl UserSessionIndex for SessionBackend {
+    async fn get_for_user(&self, user: Uuid) -> std::result::Result<Vec<UserSession>, db::Error> {
+        match self {
+            SessionBackend::Postgres(store) => store.get_for_user(user).await,
+            SessionBackend::Memory(store) => store.get_for_user(user).await,
+            SessionBackend::Redis(store) => store.get_for_user(user).await,
+        }
+    }
+
+    async fn delete_for_user_by_id(&self, user: Uuid, sid: &str) -> std::result::Result<(), db::Error> {
+        match self {
+            SessionBackend::Postgres(store) => store.delete_for_user_by_id(user, sid).await,
+            SessionBackend::Memory(store) => store.delete_for_user_by_id(user, sid).await,
+            SessionBackend::Redis(store) => store.delete_for_user_by_id(user, sid).await,
+        }
+    }
+
+    async fn delete_for_user(&self, user: Uuid) -> std::result::Result<usize, db::Error> {
+        match self {
+            SessionBackend::Postgres(store) => store.delete_for_user(user).await,
+            SessionBackend::Memory(store) => store.delete_for_user(user).await,
+            SessionBackend::Redis(store) => store.delete_for_user(user).await,
+        }
+    }
+
+    async fn delete_by_id(&self, sid: &str) -> std::result::Result<(), db::Error> {
+        match self {
+            SessionBackend::Postgres(store) => store.delete_by_id(sid).await,
+            SessionBackend::Memory(store) => store.delete_by_id(sid).await,
+            SessionBackend::Redis(store) => store.delete_by_id(sid).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// A policy under which sessions expire as soon as they are stored
+    fn expiring() -> SessionPolicy {
+        SessionPolicy {
+            idle_timeout: Duration::zero(),
+            ..SessionPolicy::default()
+        }
+    }
+
+    /// Stores a new session signed in as the user, returning its id and cookie
+    async fn signed_in(store: &SessionBackend, user: Uuid) -> (String, String) {
+        let mut session = Session::new();
+        session.insert("user_id", user).unwrap();
+        session.insert("user_agent", "test-agent").unwrap();
+        let sid = session.id().to_string();
+
+        (sid, store.store_session(session).await.unwrap().unwrap())
+    }
+
+    async fn listed(store: &SessionBackend, user: Uuid) -> Vec<String> {
+        store.get_for_user(user).await.unwrap()
+            .into_iter()
+            .map(|session| session.id)
+            .collect()
+    }
+
+    /// Counts the rows of the postgres sessions table with the session id
+    async fn sessions_rows(pool: &DbPool, sid: &str) -> i64 {
+        use diesel::prelude::*;
+        use crate::db::schema::sessions;
+
+        let sid = sid.to_string();
+        db::with_connection(pool, move |conn| {
+            sessions::table
+                .filter(sessions::id.eq(&sid))
+                .count()
+                .get_result::<i64>(conn)
+        }).await.unwrap()
+    }
+
+    /// The behaviour every backend must share. `expiring` must share the store of `store`, with
+    /// the `expiring` policy. Given a database, backends other than postgres are also checked to
+    /// leave no rows behind in its sessions table.
+    async fn behaves_like_a_session_backend(store: SessionBackend, expiring: SessionBackend, alice: Uuid, bob: Uuid, pool: Option<DbPool>) {
+        let (first, first_cookie) = signed_in(&store, alice).await;
+        let (second, _) = signed_in(&store, alice).await;
+        let (_, bob_cookie) = signed_in(&store, bob).await;
+
+        let loaded = store.load_session(first_cookie.clone()).await.unwrap().unwrap();
+        assert_eq!(loaded.get::<Uuid>("user_id"), Some(alice));
+
+        let sessions = store.get_for_user(alice).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].id, second);
+        assert_eq!(sessions[0].user_agent.as_deref(), Some("test-agent"));
+
+        store.store_session(loaded).await.unwrap();
+        assert_eq!(listed(&store, alice).await, vec![first.clone(), second.clone()]);
+
+        assert!(matches!(store.delete_for_user_by_id(bob, &first).await, Err(db::Error::NotFound)));
+        store.delete_for_user_by_id(alice, &first).await.unwrap();
+        assert!(store.load_session(first_cookie).await.unwrap().is_none());
+        assert_eq!(listed(&store, alice).await, vec![second]);
+
+        assert_eq!(store.delete_for_user(alice).await.unwrap(), 1);
+        assert!(listed(&store, alice).await.is_empty());
+        assert!(store.load_session(bob_cookie).await.unwrap().is_some());
+
+        let (_, expired_cookie) = signed_in(&expiring, alice).await;
+        assert!(expiring.load_session(expired_cookie).await.unwrap().is_none());
+        assert!(listed(&expiring, alice).await.is_empty());
+
+        let unknown_cookie = Session::new().into_cookie_value().unwrap();
+        assert!(store.load_session(unknown_cookie).await.unwrap().is_none());
+        assert!(store.load_session("not base64!".to_string()).await.unwrap().is_none());
+
+        if let (Some(pool), false) = (pool, matches!(store, SessionBackend::Postgres(_))) {
+            let (sid, _) = signed_in(&store, bob).await;
+            db::Nonce::new(&sid).insert(&pool).await.unwrap();
+            assert_eq!(sessions_rows(&pool, &sid).await, 0);
+
+            assert!(db::Nonce::take(&pool, &sid).await.is_ok());
+            store.delete_by_id(&sid).await.unwrap();
+            assert_eq!(sessions_rows(&pool, &sid).await, 0);
+        }
+
+        store.delete_for_user(bob).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_backend() {
+        let store = MemorySessionStore::new(SessionPolicy::default());
+        let expiring = MemorySessionStore { policy: expiring(), ..store.clone() };
+
+        behaves_like_a_session_backend(
+            SessionBackend::Memory(store),
+            SessionBackend::Memory(expiring),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            None,
+        ).await;
+    }
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn memory_backend_beside_a_database() {
+        let store = MemorySessionStore::new(SessionPolicy::default());
+        let expiring = MemorySessionStore { policy: expiring(), ..store.clone() };
+
+        behaves_like_a_session_backend(
+            SessionBackend::Memory(store),
+            SessionBackend::Memory(expiring),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Some(crate::db::create_pool()),
+        ).await;
+    }
+
+    /// Needs a database with the migrations applied, run with
+    /// `DATABASE_URL=... cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "requires DATABASE_URL to point at a migrated database"]
+    async fn postgres_backend() {
+        use crate::db::insert_test_user;
+
+        let pool = crate::db::create_pool();
+        let user_ids = [insert_test_user(&pool, "session").await, insert_test_user(&pool, "session").await];
+
+        behaves_like_a_session_backend(
+            SessionBackend::Postgres(PostgresSessionStore::new(pool.clone(), SessionPolicy::default())),
+            SessionBackend::Postgres(PostgresSessionStore::new(pool, expiring())),
+            user_ids[0],
+            user_ids[1],
+            None,
+        ).await;
+    }
+
+    /// Needs a Redis compatible server, run with `REDIS_URL=redis://... cargo test -- --ignored`.
+    /// The sessions table is checked too when `DATABASE_URL` is set.
+    #[tokio::test]
+    #[ignore = "requires REDIS_URL to point at a Redis compatible server"]
+    async fn redis_backend() {
+        let url = env::var("REDIS_URL").expect("REDIS_URL must be set");
+        let store = RedisSessionStore::connect(&url, SessionPolicy::default()).await.unwrap();
+        let expiring = RedisSessionStore { policy: expiring(), ..store.clone() };
+
+        behaves_like_a_session_backend(
+            SessionBackend::Redis(store),
+            SessionBackend::Redis(expiring),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            env::var("DATABASE_URL").ok().map(|_| crate::db::create_pool()),
+        ).await;
+    }
+}
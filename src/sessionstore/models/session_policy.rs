@@ -0,0 +1,139 @@
+use chrono::{ Duration, NaiveDateTime };
+use dotenvy::dotenv;
+use std::env;
+
+use crate::db::UserSession;
+
+/// The default idle timeout, in seconds
+const DEFAULT_IDLE_TIMEOUT_SECS: i64 = 60 * 60 * 8;
+/// The default absolute lifetime, in seconds
+const DEFAULT_ABSOLUTE_TIMEOUT_SECS: i64 = 60 * 60 * 24 * 7;
+
+/// How long sessions live
+///
+/// A session expires once it has gone unused for the idle timeout, and every request made with it
+/// pushes its expiry back out (sliding expiry). However active it stays, it also expires once the
+/// absolute timeout has passed since it was created, after which the user has to sign in again.
+///
+/// # Examples
+///
+/// ```
+/// let policy = SessionPolicy::from_env();
+/// let expires_at = policy.expires_at(session.created_at, now);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct SessionPolicy {
+    pub idle_timeout: Duration,
+    pub absolute_timeout: Duration,
+}
+
+impl SessionPolicy {
+    /// Reads the policy from SESSION_IDLE_TIMEOUT (default 8 hours) and SESSION_ABSOLUTE_TIMEOUT
+    /// (default 7 days), both in seconds
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let idle_secs = str::parse::<i64>(
+            &env::var("SESSION_IDLE_TIMEOUT").unwrap_or_default()
+        ).unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS);
+
+        let absolute_secs = str::parse::<i64>(
+            &env::var("SESSION_ABSOLUTE_TIMEOUT").unwrap_or_default()
+        ).unwrap_or(DEFAULT_ABSOLUTE_TIMEOUT_SECS);
+
+        Self {
+            idle_timeout: Duration::seconds(idle_secs),
+            absolute_timeout: Duration::seconds(absolute_secs),
+        }
+    }
+
+    /// When a session created at `created_at` and used at `now` expires, whichever of its idle and
+    /// absolute timeouts comes first
+    pub fn expires_at(&self, created_at: NaiveDateTime, now: NaiveDateTime) -> NaiveDateTime {
+        (now + self.idle_timeout).min(created_at + self.absolute_timeout)
+    }
+
+    /// Whether the session can still be used at `now`
+    ///
+    /// Checked against the session's activity and age rather than only its stored expiry, so
+    /// shortening either timeout applies to existing sessions straight away.
+    pub fn is_live(&self, session: &UserSession, now: NaiveDateTime) -> bool {
+        session.expires_at > now
+            && session.last_activity + self.idle_timeout > now
+            && session.created_at + self.absolute_timeout > now
+    }
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::seconds(DEFAULT_IDLE_TIMEOUT_SECS),
+            absolute_timeout: Duration::seconds(DEFAULT_ABSOLUTE_TIMEOUT_SECS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> SessionPolicy {
+        SessionPolicy {
+            idle_timeout: Duration::minutes(30),
+            absolute_timeout: Duration::hours(8),
+        }
+    }
+
+    fn session(created_at: NaiveDateTime, last_activity: NaiveDateTime) -> UserSession {
+        let mut session = UserSession::new("sid".to_string(), None, None, None, None, None);
+        session.created_at = created_at;
+        session.last_activity = last_activity;
+        session.expires_at = policy().expires_at(created_at, last_activity);
+        session
+    }
+
+    #[test]
+    fn expiry_slides_with_activity_up_to_the_absolute_lifetime() {
+        let created_at = chrono::Utc::now().naive_utc();
+
+        assert_eq!(policy().expires_at(created_at, created_at), created_at + Duration::minutes(30));
+        assert_eq!(
+            policy().expires_at(created_at, created_at + Duration::hours(2)),
+            created_at + Duration::hours(2) + Duration::minutes(30),
+        );
+        assert_eq!(
+            policy().expires_at(created_at, created_at + Duration::minutes(7 * 60 + 45)),
+            created_at + Duration::hours(8),
+        );
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let created_at = chrono::Utc::now().naive_utc();
+        let session = session(created_at, created_at + Duration::hours(1));
+
+        assert!(policy().is_live(&session, created_at + Duration::minutes(89)));
+        assert!(!policy().is_live(&session, created_at + Duration::minutes(91)));
+    }
+
+    #[test]
+    fn active_sessions_expire_at_the_absolute_lifetime() {
+        let created_at = chrono::Utc::now().naive_utc();
+        let session = session(created_at, created_at + Duration::minutes(7 * 60 + 50));
+
+        assert!(policy().is_live(&session, created_at + Duration::minutes(7 * 60 + 55)));
+        assert!(!policy().is_live(&session, created_at + Duration::hours(8)));
+    }
+
+    #[test]
+    fn shortened_timeouts_apply_to_existing_sessions() {
+        let created_at = chrono::Utc::now().naive_utc();
+        let session = session(created_at, created_at);
+        let shorter = SessionPolicy {
+            idle_timeout: Duration::minutes(5),
+            ..policy()
+        };
+
+        assert!(policy().is_live(&session, created_at + Duration::minutes(10)));
+        assert!(!shorter.is_live(&session, created_at + Duration::minutes(10)));
+    }
+}